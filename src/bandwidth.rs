@@ -0,0 +1,98 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+
+use crate::config::parse_size;
+
+/// A token bucket that limits the combined rate of everything consuming from it. Clones share
+/// the same bucket, so a single limiter can be handed to every download task.
+#[derive(Clone)]
+pub struct BandwidthLimiter {
+    bytes_per_sec: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Debug for BandwidthLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("BandwidthLimiter({} B/s)", self.bytes_per_sec))
+    }
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, sleeping until the rate allows for it. The bucket is
+    /// allowed to go into debt, so chunks larger than the bucket itself still pass through,
+    /// and the ones after them wait until the debt is paid off.
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("bandwidth bucket lock poisoned");
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+
+            bucket.tokens = (bucket.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            sleep(wait).await
+        }
+    }
+}
+
+/// Parses a rate in bytes per second, with an optional K, M, G or T suffix (powers of 1024).
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    parse_size(value, "bandwidth limit")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bandwidth::*;
+
+    #[test]
+    fn parse_rate_with_suffixes() {
+        assert_eq!(parse_rate("512"), Ok(512));
+        assert_eq!(parse_rate("100K"), Ok(100 * 1024));
+        assert_eq!(parse_rate("10m"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_rate("1G"), Ok(1024 * 1024 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[tokio::test]
+    async fn consume_waits_for_debt() {
+        let limiter = BandwidthLimiter::new(1000);
+
+        let start = Instant::now();
+
+        limiter.consume(1000).await;
+        limiter.consume(200).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
@@ -1,16 +1,30 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use async_trait::async_trait;
 use clap::Parser;
+use compact_str::format_compact;
+use indicatif::{HumanBytes, MultiProgress};
+use serde::Serialize;
+use tokio::task::{JoinSet, spawn_blocking};
 
 use crate::context::Context;
-use crate::error::Result;
+use crate::error::{MirsError, Result};
 use crate::log;
+use crate::metrics;
+use crate::progress::ProgressSection;
 use crate::prune::PruneState;
+use crate::publish::rollback_all;
+use crate::report::{Report, RepositoryReport, StepReport};
+use crate::snapshot::{SnapshotCmd, new_snapshot_id, remove_empty_snapshot, reserve_snapshot};
 use crate::verify::VerifyState;
-use crate::{CliOpts, config::MirrorOpts, pgp::PgpKeyStore};
+use crate::{CliOpts, config::MirrorOpts, metadata::FilePath, pgp::PgpKeyStore};
 use crate::{
-    mirror::MirrorState,
+    mirror::{MirrorState, publish_staged, remove_orphaned_files},
     step::{Step, StepResult},
 };
 
@@ -18,7 +32,7 @@ pub type DynStep<T, R> = Box<dyn Step<T, Result = R>>;
 pub type ArcContext<T> = Arc<Context<T>>;
 pub type ContextWithSteps<T, R> = (ArcContext<T>, Vec<DynStep<T, R>>);
 
-#[derive(Parser, Clone, Copy)]
+#[derive(Parser, Clone)]
 #[command()]
 pub enum Cmd {
     /// Mirrors the configured repositories. If no command is specified, this is the default behavior.
@@ -29,6 +43,18 @@ pub enum Cmd {
             help = "Set the mtime of all downloaded files to the Date field in the Release"
         )]
         mtime: bool,
+        #[clap(
+            short,
+            long,
+            help = "Take a snapshot of every suite that was mirrored without errors, see the snapshot command"
+        )]
+        snapshot: bool,
+        #[clap(
+            short,
+            long,
+            help = "Downloads the release and indices, and prints how many files and bytes the mirror operation would download, without downloading them"
+        )]
+        dry_run: bool,
     },
     /// Verifies the downloaded mirror(s) against the mirror configuration and outputs a report
     Verify,
@@ -41,11 +67,27 @@ pub enum Cmd {
         )]
         dry_run: bool,
     },
+    /// Manages point-in-time snapshots of the mirrored suites, kept in the snapshots folder of
+    /// each mirror
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotCmd,
+    },
+    /// Switches the dists of the mirror(s) back to the previous generation, when publishing with
+    /// --atomic-publish
+    Rollback {
+        #[clap(help = "The generation to switch to, instead of the previous one")]
+        generation: Option<u32>,
+    },
 }
 
 impl Default for Cmd {
     fn default() -> Self {
-        Cmd::Mirror { mtime: false }
+        Cmd::Mirror {
+            mtime: false,
+            snapshot: false,
+            dry_run: false,
+        }
     }
 }
 
@@ -55,6 +97,8 @@ impl Display for Cmd {
             Cmd::Mirror { .. } => f.write_str("Mirroring"),
             Cmd::Verify => f.write_str("Verifying"),
             Cmd::Prune { .. } => f.write_str("Pruning"),
+            Cmd::Snapshot { .. } => f.write_str("Snapshots"),
+            Cmd::Rollback { .. } => f.write_str("Rolling back"),
         }
     }
 }
@@ -65,65 +109,464 @@ impl Cmd {
         opts: Vec<MirrorOpts>,
         cli_opts: Arc<CliOpts>,
         pgp_key_store: Arc<PgpKeyStore>,
-    ) -> Result<()> {
-        match self {
-            Cmd::Mirror { mtime } => {
-                let ctxs = Context::<MirrorState>::create(opts, cli_opts, pgp_key_store, mtime)?;
-                self.run_all(ctxs).await;
+    ) -> Result<i32> {
+        if !cli_opts.storage().is_local()
+            && let Some(option) = self.local_only_option(&cli_opts)
+        {
+            return Err(MirsError::Config {
+                msg: format_compact!("{option} needs the output on the local file system"),
+            });
+        }
+
+        let started = chrono::Local::now();
+        let report_file = cli_opts.report_file.clone();
+        let metrics_file = cli_opts.metrics_file.clone();
+
+        let (exit_code, repositories) = match &self {
+            Cmd::Mirror {
+                mtime,
+                snapshot,
+                dry_run,
+            } => {
+                let parallel_repos = cli_opts.parallel_repos;
+
+                // every suite of the run goes into the same snapshot
+                let snapshot = (*snapshot && !*dry_run).then(new_snapshot_id);
+
+                let ctxs = Context::<MirrorState>::create(
+                    opts,
+                    cli_opts,
+                    pgp_key_store,
+                    *mtime,
+                    snapshot.clone(),
+                    *dry_run,
+                )
+                .await?;
+
+                let root_dirs = ctxs
+                    .iter()
+                    .map(|(ctx, _)| ctx.state.repo.root_dir.clone())
+                    .collect::<BTreeSet<_>>();
+
+                if let Some(id) = &snapshot {
+                    reserve_snapshot(&root_dirs, id)?;
+                }
+
+                // a dry run leaves the mirror as it is, including what the next run resumes
+                if !*dry_run {
+                    remove_orphaned_files(&ctxs).await;
+                }
+
+                let publisher = ctxs
+                    .first()
+                    .and_then(|(ctx, _)| ctx.state.publisher.clone());
+
+                let mut runs = if parallel_repos > 1 {
+                    self.run_parallel(ctxs, parallel_repos as usize).await
+                } else {
+                    self.run_all(ctxs).await
+                };
+
+                // a mirror gets one new generation with all of its suites
+                if let Some(publisher) = &publisher {
+                    publish_staged(publisher, &mut runs).await;
+                }
+
+                let (mut exit_code, repositories) = summarize(&self, runs);
+
+                if let Some(id) = &snapshot {
+                    remove_empty_snapshot(&root_dirs, id);
+                }
+
+                if let Some(metrics_file) = metrics_file.filter(|_| !*dry_run)
+                    && let Err(e) = metrics::write_textfile(&metrics_file, &repositories)
+                {
+                    log(format!("Failed writing the metrics to {metrics_file}: {e}"));
+                    exit_code = with_failure(exit_code);
+                }
+
+                (exit_code, repositories)
             }
             Cmd::Prune { dry_run } => {
+                let dry_run = *dry_run;
+                let store = cli_opts.content_store();
+
                 let ctxs = Context::<PruneState>::create(opts, cli_opts, dry_run)?;
-                self.run_all(ctxs).await;
+                let runs = self.run_all(ctxs).await;
+
+                // stored files are only swept once every mirror has been pruned, so a file is
+                // kept as long as any mirror still links to it
+                let swept = match store {
+                    Some(store) => spawn_blocking(move || store.sweep(dry_run))
+                        .await
+                        .map_err(MirsError::from)
+                        .and_then(|v| v)
+                        .map(Some),
+                    None => Ok(None),
+                };
+
+                match &swept {
+                    Ok(Some(result)) => log(format!(
+                        "{self} dedup store: {} unreferenced files ({})",
+                        result.files,
+                        HumanBytes(result.bytes)
+                    )),
+                    Ok(None) => (),
+                    Err(e) => log(format!("{self} dedup store: Fail: {e}")),
+                }
+
+                let (exit_code, repositories) = summarize(&self, runs);
+
+                match swept {
+                    Ok(_) => (exit_code, repositories),
+                    Err(_) => (with_failure(exit_code), repositories),
+                }
             }
             Cmd::Verify => {
                 let ctxs = Context::<VerifyState>::create(opts, cli_opts)?;
-                self.run_all(ctxs).await;
+                let runs = self.run_all(ctxs).await;
+
+                summarize(&self, runs)
             }
+            Cmd::Snapshot { action } => return action.execute(opts, &cli_opts).await,
+            Cmd::Rollback { generation } => {
+                return rollback_all(opts, &cli_opts, *generation).await;
+            }
+        };
+
+        if let Some(report_file) = report_file
+            && let Err(e) = Report::new(&self, started, exit_code, repositories).write(&report_file)
+        {
+            log(format!("Failed writing the report to {report_file}: {e}"));
+            return Ok(with_failure(exit_code));
         }
 
-        Ok(())
+        Ok(exit_code)
+    }
+
+    /// The option in use that links files within the output, which only works when the output
+    /// is on the local file system.
+    fn local_only_option(&self, cli_opts: &CliOpts) -> Option<&'static str> {
+        match self {
+            Cmd::Mirror { snapshot: true, .. } => Some("--snapshot"),
+            Cmd::Snapshot { .. } => Some("the snapshot command"),
+            Cmd::Rollback { .. } => Some("the rollback command"),
+            Cmd::Mirror { .. } if cli_opts.atomic_publish => Some("--atomic-publish"),
+            Cmd::Mirror { .. } | Cmd::Prune { .. } if cli_opts.dedup => Some("--dedup"),
+            _ => None,
+        }
     }
 
     async fn run<T: CmdState<Result = R>, R: CmdResult>(
-        self,
+        &self,
         ctx: ArcContext<T>,
         steps: Vec<DynStep<T, R>>,
-    ) -> R {
+    ) -> RepositoryRun<R> {
         ctx.progress.reset();
 
         ctx.progress.set_total_steps(steps.len() as u8);
 
+        let mut step_reports = Vec::with_capacity(steps.len());
+        let mut result = None;
+
         for step in steps {
             ctx.next_step(step.step_name()).await;
 
-            match step.execute(ctx.clone()).await {
+            let step_started = Instant::now();
+            let step_result = step.execute(ctx.clone()).await;
+
+            step_reports.push(StepReport::new(
+                step.step_name(),
+                step_started.elapsed(),
+                &ctx.progress,
+            ));
+
+            match step_result {
                 Ok(StepResult::Continue) => (),
-                Ok(StepResult::End(result)) => return ctx.state.finalize_with_result(result).await,
-                Err(e) => return ctx.state.finalize_with_result(step.error(e)).await,
+                Ok(StepResult::End(end_result)) => {
+                    result = Some(ctx.state.finalize_with_result(end_result).await);
+                    break;
+                }
+                Err(e) => {
+                    result = Some(ctx.state.finalize_with_result(step.error(e)).await);
+                    break;
+                }
             }
         }
 
-        ctx.state.finalize().await
+        let result = match result {
+            Some(result) => result,
+            None => ctx.state.finalize().await,
+        };
+
+        RepositoryRun {
+            name: ctx.state.to_string(),
+            result,
+            steps: step_reports,
+            release_time: ctx.state.release_time().await,
+            root_dir: ctx.state.root_dir().cloned(),
+        }
     }
 
     async fn run_all<T: CmdState<Result = R>, R: CmdResult>(
-        self,
+        &self,
         ctxs: Vec<ContextWithSteps<T, R>>,
-    ) {
+    ) -> Vec<RepositoryRun<R>> {
+        let mut runs = Vec::with_capacity(ctxs.len());
+
         for (ctx, steps) in ctxs {
             log(format!("{self} {}", ctx.state));
-            let result = self.run(ctx, steps).await;
-            log(result.to_string());
+            let run = self.run(ctx, steps).await;
+            log(run.result.to_string());
+            runs.push(run);
+        }
+
+        runs
+    }
+
+    /// Runs up to `parallel` contexts at the same time. Every context draws its progress in a
+    /// section of its own, and the results are returned in the order of the contexts.
+    async fn run_parallel<T, R>(
+        &self,
+        ctxs: Vec<ContextWithSteps<T, R>>,
+        parallel: usize,
+    ) -> Vec<RepositoryRun<R>>
+    where
+        T: CmdState<Result = R> + 'static,
+        R: CmdResult + Send + 'static,
+    {
+        let multi = MultiProgress::new();
+
+        let num_tasks = parallel.min(ctxs.len());
+        let queue = Arc::new(Mutex::new(
+            ctxs.into_iter().enumerate().collect::<VecDeque<_>>(),
+        ));
+
+        let mut tasks = JoinSet::new();
+
+        for _ in 0..num_tasks {
+            let task_cmd = self.clone();
+            let task_queue = queue.clone();
+            let task_multi = multi.clone();
+
+            tasks.spawn(async move {
+                let mut runs = Vec::new();
+
+                loop {
+                    let next = task_queue
+                        .lock()
+                        .expect("context queue lock poisoned")
+                        .pop_front();
+
+                    let Some((index, (ctx, steps))) = next else {
+                        break;
+                    };
+
+                    let name = ctx.state.to_string();
+
+                    task_multi.suspend(|| log(format!("{task_cmd} {name}")));
+
+                    let section = ProgressSection::new(&task_multi, format!("{task_cmd} {name}"));
+                    ctx.progress.set_section(section.clone());
+
+                    let run = task_cmd.run(ctx, steps).await;
+
+                    section.clear();
+                    task_multi.suspend(|| log(format!("{name}: {}", run.result)));
+
+                    runs.push((index, run));
+                }
+
+                runs
+            });
+        }
+
+        let mut runs = tasks
+            .join_all()
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        runs.sort_by_key(|(index, _)| *index);
+
+        runs.into_iter().map(|(_, run)| run).collect()
+    }
+}
+
+/// The result of running the steps of one context, along with what the report needs from it.
+pub struct RepositoryRun<R> {
+    pub name: String,
+    pub result: R,
+    pub steps: Vec<StepReport>,
+    pub release_time: Option<u64>,
+    pub root_dir: Option<FilePath>,
+}
+
+pub trait CmdResult: Display {
+    fn summary(&self) -> ResultSummary;
+}
+
+/// How a repository came out of a command, from best to worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Ok,
+    Incomplete,
+    Failed,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Ok => f.write_str("ok"),
+            Outcome::Incomplete => f.write_str("incomplete"),
+            Outcome::Failed => f.write_str("failed"),
+        }
+    }
+}
+
+pub struct ResultSummary {
+    pub outcome: Outcome,
+    pub bytes: Option<u64>,
+    pub packages: Option<u64>,
+    pub failed: u64,
+    pub skipped: u64,
+}
+
+impl ResultSummary {
+    pub fn ok() -> Self {
+        Self {
+            outcome: Outcome::Ok,
+            bytes: None,
+            packages: None,
+            failed: 0,
+            skipped: 0,
+        }
+    }
+
+    pub fn failed() -> Self {
+        Self {
+            outcome: Outcome::Failed,
+            ..Self::ok()
         }
     }
 }
 
-pub trait CmdResult: Display {}
+pub const EXIT_PARTIAL_FAILURE: i32 = 1;
+pub const EXIT_TOTAL_FAILURE: i32 = 2;
+
+/// The exit code of the process: 0 when every repository is ok, 2 when all of them failed and
+/// 1 when some of them failed or are incomplete.
+pub fn exit_code(outcomes: &[Outcome]) -> i32 {
+    if outcomes.iter().all(|v| *v == Outcome::Ok) {
+        0
+    } else if outcomes.iter().all(|v| *v == Outcome::Failed) {
+        EXIT_TOTAL_FAILURE
+    } else {
+        EXIT_PARTIAL_FAILURE
+    }
+}
+
+/// The exit code of a run after something that concerns the run as a whole failed, like
+/// writing its report, which makes it at least a partial failure.
+fn with_failure(exit_code: i32) -> i32 {
+    exit_code.max(EXIT_PARTIAL_FAILURE)
+}
+
+/// Prints a table of how each repository came out, and returns the exit code for them along
+/// with the reports of the runs.
+fn summarize<R: CmdResult>(cmd: &Cmd, runs: Vec<RepositoryRun<R>>) -> (i32, Vec<RepositoryReport>) {
+    let summaries = runs
+        .iter()
+        .map(|run| (run.name.as_str(), run.result.summary()))
+        .collect::<Vec<_>>();
+
+    let name_width = summaries
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("Repository".len());
+
+    log(format!("{cmd} summary:"));
+
+    println!(
+        "{:name_width$}  {:10}  {:>12}  {:>8}  {:>8}",
+        "Repository", "Outcome", "Bytes", "Failed", "Skipped"
+    );
+
+    for (name, summary) in &summaries {
+        let bytes = summary
+            .bytes
+            .map(|v| HumanBytes(v).to_string())
+            .unwrap_or_else(|| String::from("-"));
+
+        println!(
+            "{name:name_width$}  {:10}  {bytes:>12}  {:>8}  {:>8}",
+            summary.outcome.to_string(),
+            summary.failed,
+            summary.skipped
+        );
+    }
+
+    let exit_code = exit_code(
+        &summaries
+            .iter()
+            .map(|(_, summary)| summary.outcome)
+            .collect::<Vec<_>>(),
+    );
+
+    (
+        exit_code,
+        runs.into_iter().map(RepositoryReport::from).collect(),
+    )
+}
 
 #[async_trait]
-pub trait CmdState: Display + Sized {
+pub trait CmdState: Display + Sized + Send + Sync {
     type Result;
 
     async fn finalize(&self) -> Self::Result;
     async fn finalize_with_result(&self, result: Self::Result) -> Self::Result;
+
+    /// The date of the published release, for commands that mirror one.
+    async fn release_time(&self) -> Option<u64> {
+        None
+    }
+
+    /// The folder in the output that the command works on, for commands of one repository.
+    fn root_dir(&self) -> Option<&FilePath> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cmd::*;
+
+    #[test]
+    fn exit_code_reflects_the_worst_outcome() {
+        assert_eq!(exit_code(&[]), 0);
+        assert_eq!(exit_code(&[Outcome::Ok, Outcome::Ok]), 0);
+        assert_eq!(
+            exit_code(&[Outcome::Ok, Outcome::Incomplete]),
+            EXIT_PARTIAL_FAILURE
+        );
+        assert_eq!(
+            exit_code(&[Outcome::Ok, Outcome::Failed]),
+            EXIT_PARTIAL_FAILURE
+        );
+        assert_eq!(
+            exit_code(&[Outcome::Failed, Outcome::Failed]),
+            EXIT_TOTAL_FAILURE
+        );
+    }
+
+    #[test]
+    fn failures_of_the_run_keep_the_worst_outcome() {
+        assert_eq!(with_failure(0), EXIT_PARTIAL_FAILURE);
+        assert_eq!(with_failure(EXIT_PARTIAL_FAILURE), EXIT_PARTIAL_FAILURE);
+        assert_eq!(with_failure(EXIT_TOTAL_FAILURE), EXIT_TOTAL_FAILURE);
+    }
 }
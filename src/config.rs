@@ -1,37 +1,157 @@
 use compact_str::{CompactString, ToCompactString, format_compact};
-use std::{cmp::Ordering, fmt::Display};
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::{cmp::Ordering, collections::BTreeSet, fmt::Display};
 
 use crate::{
+    bandwidth::parse_rate,
     error::{MirsError, Result},
-    metadata::FilePath,
+    metadata::{FilePath, package_filter::compile_pattern},
+    quota::parse_quota,
 };
 
 pub async fn read_config(path: &FilePath) -> Result<Vec<MirrorOpts>> {
-    let file = tokio::fs::File::open(path)
-        .await
+    let mut mirrors = Vec::new();
+
+    let mut visited = BTreeSet::new();
+
+    let mut pending = config_files(path)?;
+    pending.reverse();
+
+    while let Some(file) = pending.pop() {
+        if !visited.insert(file.clone()) {
+            continue;
+        }
+
+        let content = tokio::fs::read_to_string(&file)
+            .await
+            .map_err(|e| MirsError::Config {
+                msg: format_compact!("could not read {file}: {e}"),
+            })?;
+
+        let (opts, includes) = parse_config(&file, &content);
+
+        mirrors.extend(opts);
+
+        let mut included_files = Vec::new();
+
+        for (origin, pattern) in includes {
+            let matches = expand_include(&pattern).map_err(|e| MirsError::Config {
+                msg: format_compact!("invalid include in {origin}: {e}"),
+            })?;
+
+            if matches.is_empty() {
+                println!(
+                    "{} WARNING: include {pattern} in {origin} did not match any files",
+                    crate::now()
+                );
+            }
+
+            included_files.extend(matches);
+        }
+
+        pending.extend(included_files.into_iter().rev());
+    }
+
+    let mut mirrors = merge_similar(mirrors);
+
+    link_sibling_suites(&mut mirrors);
+
+    if mirrors.is_empty() {
+        return Err(MirsError::Config {
+            msg: format_compact!("no valid repositories in config"),
+        });
+    }
+
+    Ok(mirrors)
+}
+
+/// Lists the config files for a config path. A directory yields its `.list` and `.sources` files
+/// in alphabetical order.
+fn config_files(path: &FilePath) -> Result<Vec<FilePath>> {
+    let is_dir = std::fs::metadata(path).is_ok_and(|v| v.is_dir());
+
+    if !is_dir {
+        return Ok(vec![path.clone()]);
+    }
+
+    let mut files = std::fs::read_dir(path)
         .map_err(|e| MirsError::Config {
             msg: format_compact!("could not read {path}: {e}"),
+        })?
+        .filter_map(|entry| entry.ok().map(|v| FilePath::from(v.path())))
+        .filter(|v| matches!(v.extension(), Some("list") | Some("sources")))
+        .filter(|v| !v.metadata().is_ok_and(|m| m.is_dir()))
+        .collect::<Vec<_>>();
+
+    files.sort();
+
+    Ok(files)
+}
+
+fn expand_include(pattern: &str) -> Result<Vec<FilePath>> {
+    let paths = glob::glob(pattern).map_err(|e| MirsError::Config {
+        msg: e.to_compact_string(),
+    })?;
+
+    let mut files = Vec::new();
+
+    for path in paths {
+        let path = path.map_err(|e| MirsError::Config {
+            msg: e.to_compact_string(),
         })?;
 
-    let mut reader = BufReader::with_capacity(8192, file);
+        files.extend(config_files(&FilePath::from(path))?);
+    }
+
+    Ok(files)
+}
 
-    let mut buf = String::with_capacity(8192);
+/// Parses the content of a config file, returning the mirrors and the glob patterns of any
+/// `include` directives in it. Relative include patterns are resolved from the directory of
+/// the config file.
+fn parse_config(path: &FilePath, content: &str) -> (Vec<MirrorOpts>, Vec<(ConfigOrigin, String)>) {
+    let mut includes = Vec::new();
+
+    // include directives are blanked out rather than removed to keep the line numbers intact
+    let content = content
+        .lines()
+        .enumerate()
+        .map(|(line_num, line)| {
+            let Some(("include", pattern)) = line.trim_start().split_once(char::is_whitespace)
+            else {
+                return line;
+            };
 
-    let mut mirrors = Vec::new();
+            let pattern = pattern.split('#').next().unwrap_or("").trim();
 
-    let mut line_num = 0_usize;
+            let pattern = if pattern.starts_with('/') {
+                pattern.to_string()
+            } else {
+                FilePath::from(path.parent().unwrap_or("."))
+                    .join(pattern)
+                    .to_string()
+            };
 
-    loop {
-        buf.clear();
+            includes.push((ConfigOrigin::new(path, line_num + 1), pattern));
 
-        line_num += 1;
+            "#"
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
 
-        let mut line = match reader.read_line(&mut buf).await {
-            Ok(0) => break,
-            Ok(len) => (buf[..len]).trim(),
-            Err(e) => return Err(e.into()),
-        };
+    let mirrors = if is_deb822(&content) {
+        parse_deb822(path, &content)
+    } else {
+        parse_one_line(path, &content)
+    };
+
+    (mirrors, includes)
+}
+
+fn parse_one_line(path: &FilePath, content: &str) -> Vec<MirrorOpts> {
+    let mut mirrors = Vec::new();
+
+    for (line_num, line) in content.lines().enumerate() {
+        let mut line = line.trim();
 
         if let Some(pos) = line.find('#') {
             line = &line[..pos];
@@ -41,27 +161,110 @@ pub async fn read_config(path: &FilePath) -> Result<Vec<MirrorOpts>> {
             continue;
         }
 
+        let origin = ConfigOrigin::new(path, line_num + 1);
+
         match MirrorOpts::try_from(line) {
-            Ok(opts) => mirrors.push(opts),
+            Ok(opts) => mirrors.push(MirrorOpts { origin, ..opts }),
             Err(e) => {
-                println!(
-                    "{} failed parsing config on line {line_num}: {e}",
-                    crate::now()
-                );
+                println!("{} failed parsing config at {origin}: {e}", crate::now());
                 continue;
             }
         }
     }
 
-    let mirrors = merge_similar(mirrors);
+    mirrors
+}
 
-    if mirrors.is_empty() {
-        return Err(MirsError::Config {
-            msg: format_compact!("no valid repositories in config"),
-        });
+fn parse_deb822(path: &FilePath, content: &str) -> Vec<MirrorOpts> {
+    let mut mirrors = Vec::new();
+
+    for (line_num, stanza) in deb822_stanzas(content) {
+        let origin = ConfigOrigin::new(path, line_num);
+
+        match MirrorOpts::try_from_deb822(&stanza) {
+            Ok(opts) => mirrors.extend(opts.into_iter().map(|v| MirrorOpts {
+                origin: origin.clone(),
+                ..v
+            })),
+            Err(e) => {
+                println!("{} failed parsing config at {origin}: {e}", crate::now());
+                continue;
+            }
+        }
     }
 
-    Ok(mirrors)
+    mirrors
+}
+
+// the deb822 format is detected by the first meaningful line being a field, i.e. "Types: deb",
+// rather than a one-line entry such as "deb [arch=amd64] http://..."
+fn is_deb822(content: &str) -> bool {
+    content
+        .lines()
+        .map(str::trim)
+        .find(|v| !v.is_empty() && !v.starts_with('#'))
+        .and_then(|v| v.split_once(':'))
+        .is_some_and(|(key, _)| !key.is_empty() && !key.contains(char::is_whitespace))
+}
+
+type Deb822Stanza = Vec<(CompactString, Vec<CompactString>)>;
+
+fn deb822_stanzas(content: &str) -> Vec<(usize, Deb822Stanza)> {
+    let mut stanzas = Vec::new();
+
+    let mut current: Deb822Stanza = Vec::new();
+    let mut start_line = 0;
+
+    for (line_num, line) in content.lines().enumerate() {
+        let line = line.trim_end();
+
+        if line.starts_with('#') {
+            continue;
+        }
+
+        if line.is_empty() {
+            if !current.is_empty() {
+                stanzas.push((start_line, std::mem::take(&mut current)));
+            }
+
+            continue;
+        }
+
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, values)) = current.last_mut() {
+                // a lone "." on a continuation line represents an empty line, e.g. in an inlined key
+                match line.trim() {
+                    "." => values.push(CompactString::const_new("")),
+                    v => values.push(v.to_compact_string()),
+                }
+            }
+
+            continue;
+        }
+
+        if current.is_empty() {
+            start_line = line_num + 1;
+        }
+
+        match line.split_once(':') {
+            Some((key, value)) => {
+                let mut values = Vec::new();
+
+                if !value.trim().is_empty() {
+                    values.push(value.trim().to_compact_string());
+                }
+
+                current.push((key.trim().to_lowercase().to_compact_string(), values));
+            }
+            None => current.push((line.to_lowercase().to_compact_string(), Vec::new())),
+        }
+    }
+
+    if !current.is_empty() {
+        stanzas.push((start_line, current));
+    }
+
+    stanzas
 }
 
 fn merge_similar(mut mirrors: Vec<MirrorOpts>) -> Vec<MirrorOpts> {
@@ -90,6 +293,38 @@ fn merge_similar(mut mirrors: Vec<MirrorOpts>) -> Vec<MirrorOpts> {
                         }
                     }
 
+                    // the merged mirror should select everything that either of them did, so an
+                    // unfiltered mirror wins over selections and only common excludes are kept
+                    merge_selection(&mut last.include, new.include);
+                    merge_selection(&mut last.sections, new.sections);
+                    merge_selection(&mut last.priorities, new.priorities);
+                    merge_selection(&mut last.seeds, new.seeds);
+
+                    last.keep_versions = match (last.keep_versions, new.keep_versions) {
+                        (Some(a), Some(b)) => Some(a.max(b)),
+                        _ => None,
+                    };
+
+                    last.bandwidth_limit = match (last.bandwidth_limit, new.bandwidth_limit) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+
+                    last.quota = match (last.quota, new.quota) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+
+                    for url in new.fallback_urls {
+                        if url != last.url && !last.fallback_urls.contains(&url) {
+                            last.fallback_urls.push(url);
+                        }
+                    }
+
+                    last.exclude.retain(|v| new.exclude.contains(v));
+                    last.exclude_sections
+                        .retain(|v| new.exclude_sections.contains(v));
+
                     last.udeb |= new.udeb;
                     last.packages |= new.packages;
                     last.source |= new.source;
@@ -97,6 +332,17 @@ fn merge_similar(mut mirrors: Vec<MirrorOpts>) -> Vec<MirrorOpts> {
                     last.pgp_verify |= new.pgp_verify;
 
                     if let Some(pgp_pub_key) = new.pgp_pub_key.take() {
+                        if last.pgp_pub_key.as_ref().is_some_and(|v| *v != pgp_pub_key) {
+                            println!(
+                                "{} WARNING: {} sets a different pgp_pub_key than {} for {}, using the one from {}",
+                                crate::now(),
+                                new.origin,
+                                last.origin,
+                                new.suite,
+                                new.origin
+                            );
+                        }
+
                         last.pgp_pub_key = Some(pgp_pub_key)
                     }
                 } else {
@@ -110,7 +356,39 @@ fn merge_similar(mut mirrors: Vec<MirrorOpts>) -> Vec<MirrorOpts> {
         })
 }
 
-#[derive(Eq, Default)]
+/// Lets every mirror know the other suites that are mirrored into the same folder, since
+/// packages of one suite often depend on packages of another, i.e. trixie-updates on trixie.
+fn link_sibling_suites(mirrors: &mut [MirrorOpts]) {
+    let siblings = mirrors
+        .iter()
+        .map(|v| {
+            mirrors
+                .iter()
+                .filter(|other| other.root_name() == v.root_name() && other.suite != v.suite)
+                .map(|other| other.suite.clone())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    for (mirror, sibling_suites) in mirrors.iter_mut().zip(siblings) {
+        mirror.sibling_suites = sibling_suites;
+    }
+}
+
+fn merge_selection(last: &mut Vec<CompactString>, new: Vec<CompactString>) {
+    if last.is_empty() || new.is_empty() {
+        last.clear();
+        return;
+    }
+
+    for value in new {
+        if !last.contains(&value) {
+            last.push(value);
+        }
+    }
+}
+
+#[derive(Eq, Default, Clone)]
 pub struct MirrorOpts {
     pub url: CompactString,
     pub suite: CompactString,
@@ -123,6 +401,70 @@ pub struct MirrorOpts {
     pub pgp_verify: bool,
     pub udeb: bool,
     pub short_name: Option<CompactString>,
+    pub include: Vec<CompactString>,
+    pub exclude: Vec<CompactString>,
+    pub sections: Vec<CompactString>,
+    pub exclude_sections: Vec<CompactString>,
+    pub priorities: Vec<CompactString>,
+    pub seeds: Vec<CompactString>,
+    pub keep_versions: Option<usize>,
+    pub bandwidth_limit: Option<u64>,
+    pub quota: Option<u64>,
+    pub fallback_urls: Vec<CompactString>,
+    /// The other configured suites that share the folder of this mirror.
+    pub sibling_suites: Vec<CompactString>,
+    pub origin: ConfigOrigin,
+}
+
+/// Parses a size in bytes, with an optional K, M, G or T suffix (powers of 1024). `name` is
+/// what the size is for, in the errors.
+pub fn parse_size(value: &str, name: &str) -> std::result::Result<u64, String> {
+    let value = value.trim();
+
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 1 << 30),
+        Some((i, 't' | 'T')) => (&value[..i], 1 << 40),
+        _ => (value, 1),
+    };
+
+    match number.trim().parse::<u64>() {
+        Ok(0) => Err(format!("{name} must be larger than 0: {value}")),
+        Ok(n) => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("{name} is too large: {value}")),
+        Err(_) => Err(format!("invalid {name}: {value}")),
+    }
+}
+
+/// Validates a package pattern in the anchored form that the package filter compiles it to.
+fn package_pattern(value: &str) -> Result<CompactString> {
+    compile_pattern(value)?;
+
+    Ok(value.to_compact_string())
+}
+
+/// The config file and line a `MirrorOpts` was parsed from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigOrigin {
+    pub path: FilePath,
+    pub line: usize,
+}
+
+impl ConfigOrigin {
+    pub fn new(path: &FilePath, line: usize) -> Self {
+        Self {
+            path: path.clone(),
+            line,
+        }
+    }
+}
+
+impl Display for ConfigOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.path, self.line))
+    }
 }
 
 impl Ord for MirrorOpts {
@@ -161,21 +503,13 @@ impl PartialOrd for MirrorOpts {
 
 impl MirrorOpts {
     pub fn try_from(mut line: &str) -> Result<MirrorOpts> {
-        let mut arch = Vec::new();
-        let mut debian_installer_arch = Vec::new();
-        let mut pgp_pub_key: Option<CompactString> = None;
-        let mut short_name: Option<CompactString> = None;
-        let mut pgp_verify = false;
-        let mut udeb = false;
-
-        let mut packages = false;
-        let mut source = false;
+        let mut opts = MirrorOpts::default();
 
         line = if let Some(line) = line.strip_prefix("deb-src") {
-            source = true;
+            opts.source = true;
             line
         } else if let Some(line) = line.strip_prefix("deb") {
-            packages = true;
+            opts.packages = true;
             line
         } else {
             return Err(MirsError::Config {
@@ -202,19 +536,7 @@ impl MirrorOpts {
                     });
                 };
 
-                match opt_key {
-                    "arch" => arch.extend(opt_val.split(',').map(|v| v.to_compact_string())),
-                    "di_arch" => debian_installer_arch
-                        .extend(opt_val.split(',').map(|v| v.to_compact_string())),
-                    "pgp_pub_key" => {
-                        pgp_pub_key = Some(opt_val.to_compact_string());
-                        pgp_verify = true;
-                    }
-                    "pgp_verify" => pgp_verify = opt_val.to_lowercase() == "true",
-                    "udeb" => udeb = opt_val.to_lowercase() == "true",
-                    "short_name" => short_name = Some(opt_val.to_compact_string()),
-                    _ => (),
-                }
+                opts.apply_option(opt_key, opt_val)?;
             }
         }
 
@@ -228,7 +550,11 @@ impl MirrorOpts {
             });
         };
 
-        let url = url.strip_suffix('/').unwrap_or(url);
+        // alternative upstream mirrors of the same repository are separated by |
+        let mut urls = url.split('|').map(|v| v.strip_suffix('/').unwrap_or(v));
+
+        opts.url = urls.next().unwrap_or_default().to_compact_string();
+        opts.fallback_urls = urls.map(|v| v.to_compact_string()).collect();
 
         let Some(suite) = line_parts.next() else {
             return Err(MirsError::Config {
@@ -236,10 +562,12 @@ impl MirrorOpts {
             });
         };
 
+        opts.suite = suite.to_compact_string();
+
         // we split off the path of the component name because they are not used in the release file,
         // and might be a holdover from older repository structures. debian-security uses this and the path
         // is just symlinked back to the repository root. should we support this? maybe, but probably not.
-        let mut components = line_parts
+        opts.components = line_parts
             .map(|v| {
                 v.split('/')
                     .next_back()
@@ -248,27 +576,178 @@ impl MirrorOpts {
             })
             .collect::<Vec<_>>();
 
-        if components.is_empty() {
-            components.push(CompactString::const_new("main"));
+        Ok(opts.with_defaults())
+    }
+
+    /// Parses a deb822 stanza. A stanza with several `Suites` expands into one `MirrorOpts` per
+    /// suite. Several `URIs` are treated as mirrors of the same repository, where the first one
+    /// is the primary and the rest are used as fallbacks.
+    fn try_from_deb822(stanza: &Deb822Stanza) -> Result<Vec<MirrorOpts>> {
+        let mut template = MirrorOpts::default();
+
+        let mut urls = Vec::new();
+        let mut suites = Vec::new();
+
+        for (key, values) in stanza {
+            let mut words = values.iter().flat_map(|v| v.split_whitespace());
+
+            match key.as_str() {
+                "enabled" => {
+                    if words.any(|v| v.eq_ignore_ascii_case("no")) {
+                        return Ok(Vec::new());
+                    }
+                }
+                "types" => {
+                    for t in words {
+                        match t {
+                            "deb" => template.packages = true,
+                            "deb-src" => template.source = true,
+                            _ => {
+                                return Err(MirsError::Config {
+                                    msg: format_compact!("unsupported type '{t}'"),
+                                });
+                            }
+                        }
+                    }
+                }
+                "uris" => urls.extend(
+                    words
+                        .flat_map(|v| v.split('|'))
+                        .map(|v| v.strip_suffix('/').unwrap_or(v)),
+                ),
+                "suites" => suites.extend(words),
+                "components" => template.components.extend(words.map(|v| {
+                    v.split('/')
+                        .next_back()
+                        .expect("last should always exist here")
+                        .to_compact_string()
+                })),
+                "architectures" => template.arch.extend(words.map(|v| v.to_compact_string())),
+                "signed-by" => {
+                    let pgp_pub_key = if values.iter().any(|v| v.starts_with("-----BEGIN")) {
+                        values.join("\n").to_compact_string()
+                    } else {
+                        let Some(path) = words.next() else {
+                            return Err(MirsError::Config {
+                                msg: CompactString::const_new("Signed-By has no value"),
+                            });
+                        };
+
+                        path.to_compact_string()
+                    };
+
+                    template.apply_option("pgp_pub_key", &pgp_pub_key)?;
+                }
+                // aptmirs specific options can be set as fields, i.e. "Short-Name: debian"
+                _ => {
+                    let opt_key = key.replace('-', "_");
+
+                    for opt_val in words {
+                        template.apply_option(&opt_key, opt_val)?;
+                    }
+                }
+            }
+        }
+
+        if !template.packages && !template.source {
+            return Err(MirsError::Config {
+                msg: CompactString::const_new("no types specified"),
+            });
         }
 
-        if arch.is_empty() {
-            arch.push(CompactString::const_new("amd64"))
+        if urls.is_empty() {
+            return Err(MirsError::Config {
+                msg: CompactString::const_new("no url specified"),
+            });
         }
 
-        Ok(Self {
-            url: url.to_compact_string(),
-            suite: suite.to_compact_string(),
-            components,
-            arch,
-            debian_installer_arch,
-            source,
-            packages,
-            pgp_pub_key,
-            pgp_verify,
-            udeb,
-            short_name,
-        })
+        if suites.is_empty() {
+            return Err(MirsError::Config {
+                msg: CompactString::const_new("no suite specified"),
+            });
+        }
+
+        let template = template.with_defaults();
+
+        let fallback_urls = urls[1..]
+            .iter()
+            .map(|v| v.to_compact_string())
+            .collect::<Vec<_>>();
+
+        Ok(suites
+            .into_iter()
+            .map(|suite| MirrorOpts {
+                url: urls[0].to_compact_string(),
+                fallback_urls: fallback_urls.clone(),
+                suite: suite.to_compact_string(),
+                ..template.clone()
+            })
+            .collect())
+    }
+
+    fn apply_option(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "arch" => self
+                .arch
+                .extend(value.split(',').map(|v| v.to_compact_string())),
+            "di_arch" => self
+                .debian_installer_arch
+                .extend(value.split(',').map(|v| v.to_compact_string())),
+            "pgp_pub_key" => {
+                self.pgp_pub_key = Some(value.to_compact_string());
+                self.pgp_verify = true;
+            }
+            "pgp_verify" => self.pgp_verify = value.to_lowercase() == "true",
+            "udeb" => self.udeb = value.to_lowercase() == "true",
+            "short_name" => self.short_name = Some(value.to_compact_string()),
+            "include" => self.include.push(package_pattern(value)?),
+            "exclude" => self.exclude.push(package_pattern(value)?),
+            "section" => self
+                .sections
+                .extend(value.split(',').map(|v| v.to_compact_string())),
+            "exclude_section" => self
+                .exclude_sections
+                .extend(value.split(',').map(|v| v.to_compact_string())),
+            "keep_versions" => match value.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(MirsError::Config {
+                        msg: format_compact!("keep_versions must be a positive number: {value}"),
+                    });
+                }
+                Ok(n) => self.keep_versions = Some(n),
+            },
+            "bandwidth_limit" => {
+                self.bandwidth_limit =
+                    Some(parse_rate(value).map_err(|msg| MirsError::Config { msg: msg.into() })?)
+            }
+            "quota" => {
+                self.quota =
+                    Some(parse_quota(value).map_err(|msg| MirsError::Config { msg: msg.into() })?)
+            }
+            "seed" => self
+                .seeds
+                .extend(value.split(',').map(|v| v.to_compact_string())),
+            "priority" => self.priorities.extend(
+                value
+                    .split(',')
+                    .map(|v| v.to_lowercase().to_compact_string()),
+            ),
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn with_defaults(mut self) -> Self {
+        if self.components.is_empty() {
+            self.components.push(CompactString::const_new("main"));
+        }
+
+        if self.arch.is_empty() {
+            self.arch.push(CompactString::const_new("amd64"))
+        }
+
+        self
     }
 
     pub fn debian_installer(&self) -> bool {
@@ -279,6 +758,11 @@ impl MirrorOpts {
         self.suite == "/"
     }
 
+    /// The name the folder of the mirror is derived from.
+    pub fn root_name(&self) -> &CompactString {
+        self.short_name.as_ref().unwrap_or(&self.url)
+    }
+
     pub fn dist_part(&self) -> CompactString {
         if self.flat() {
             CompactString::with_capacity(0)
@@ -311,3 +795,139 @@ impl Display for MirrorOpts {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::*;
+
+    #[test]
+    fn one_line_config_is_not_deb822() {
+        let content = "# comment\ndeb [arch=amd64] http://deb.debian.org/debian trixie main\n";
+
+        assert!(!is_deb822(content));
+
+        let (mirrors, _) = parse_config(&FilePath::from("/etc/apt/mirror.list"), content);
+
+        assert_eq!(mirrors.len(), 1);
+        assert_eq!(mirrors[0].url, "http://deb.debian.org/debian");
+        assert!(mirrors[0].fallback_urls.is_empty());
+        assert_eq!(mirrors[0].components, vec!["main"]);
+        assert_eq!(mirrors[0].origin.to_string(), "/etc/apt/mirror.list:2");
+    }
+
+    #[test]
+    fn include_directives_resolve_relative_to_file() {
+        let content = "include mirror.list.d/*.list
+  include\t/opt/mirrors/*.sources # absolute
+deb http://deb.debian.org/debian trixie main
+";
+
+        let (mirrors, includes) = parse_config(&FilePath::from("/etc/apt/mirror.list"), content);
+
+        assert_eq!(mirrors.len(), 1);
+        assert_eq!(mirrors[0].origin.line, 3);
+
+        let patterns = includes
+            .iter()
+            .map(|(origin, pattern)| (origin.line, pattern.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            patterns,
+            vec![
+                (1, "/etc/apt/mirror.list.d/*.list"),
+                (2, "/opt/mirrors/*.sources")
+            ]
+        );
+    }
+
+    #[test]
+    fn deb822_expands_suites_with_fallback_uris() {
+        let content = "Types: deb deb-src
+URIs: http://deb.debian.org/debian/ http://ftp.se.debian.org/debian
+Suites: trixie trixie-updates
+Components: main contrib
+Architectures: amd64 arm64
+Short-Name: debian
+
+Types: deb
+URIs: http://example.com/disabled
+Suites: stable
+Enabled: no
+";
+
+        assert!(is_deb822(content));
+
+        let (mirrors, _) = parse_config(&FilePath::from("/etc/apt/debian.sources"), content);
+
+        assert_eq!(mirrors.len(), 2);
+
+        for opts in &mirrors {
+            assert!(opts.packages && opts.source);
+            assert_eq!(opts.components, vec!["main", "contrib"]);
+            assert_eq!(opts.arch, vec!["amd64", "arm64"]);
+            assert_eq!(opts.short_name.as_deref(), Some("debian"));
+            assert_eq!(opts.url, "http://deb.debian.org/debian");
+            assert_eq!(opts.fallback_urls, vec!["http://ftp.se.debian.org/debian"]);
+        }
+
+        assert_eq!(mirrors[1].suite, "trixie-updates");
+    }
+
+    #[test]
+    fn deb822_inline_signed_by() {
+        let content = "Types: deb
+URIs: http://deb.debian.org/debian
+Suites: trixie
+Signed-By:
+ -----BEGIN PGP PUBLIC KEY BLOCK-----
+ .
+ mQINBGPL0BUBEADmW5NlOSX+eV3e+4ryFtGPwdBv6HLvemyx0ZVB9WzTgDYU9Aoq
+ -----END PGP PUBLIC KEY BLOCK-----
+";
+
+        let (mirrors, _) = parse_config(&FilePath::from("/etc/apt/debian.sources"), content);
+
+        assert_eq!(mirrors.len(), 1);
+        assert!(mirrors[0].pgp_verify);
+        assert_eq!(
+            mirrors[0].pgp_pub_key.as_deref(),
+            Some(
+                "-----BEGIN PGP PUBLIC KEY BLOCK-----\n\nmQINBGPL0BUBEADmW5NlOSX+eV3e+4ryFtGPwdBv6HLvemyx0ZVB9WzTgDYU9Aoq\n-----END PGP PUBLIC KEY BLOCK-----"
+            )
+        );
+    }
+
+    #[test]
+    fn package_patterns_are_validated_as_anchored() {
+        assert!(package_pattern("linux-image-.*").is_ok());
+        assert!(package_pattern("lib[").is_err());
+    }
+
+    #[test]
+    fn suites_in_the_same_folder_are_siblings() {
+        let content = "deb [seed=curl] http://deb.debian.org/debian trixie main
+deb [seed=curl] http://deb.debian.org/debian trixie-updates main
+deb [short_name=debian] http://deb.debian.org/debian bookworm main
+";
+
+        let (mirrors, _) = parse_config(&FilePath::from("/etc/apt/mirror.list"), content);
+
+        let mut mirrors = merge_similar(mirrors);
+        link_sibling_suites(&mut mirrors);
+
+        let siblings = mirrors
+            .iter()
+            .map(|v| (v.suite.as_str(), v.sibling_suites.clone()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            siblings,
+            vec![
+                ("bookworm", vec![]),
+                ("trixie", vec!["trixie-updates".into()]),
+                ("trixie-updates", vec!["trixie".into()]),
+            ]
+        );
+    }
+}
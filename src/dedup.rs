@@ -0,0 +1,195 @@
+use std::os::unix::fs::MetadataExt;
+
+use compact_str::format_compact;
+use walkdir::WalkDir;
+
+use crate::{
+    downloader::{Download, PartialDir, create_dirs},
+    error::Result,
+    metadata::{
+        FilePath,
+        checksum::{Checksum, ChecksumType},
+    },
+    storage::Storage,
+};
+
+/// A content addressed store of hardlinks to downloaded files, keyed by their checksum. Files
+/// with the same content in several mirrors are only downloaded once, and linked from the store
+/// into the other mirrors.
+///
+/// Every file in the store is a hardlink to at least one mirror file, so the link count of a
+/// stored file tells how many mirror paths still reference it. When prune has unlinked all of
+/// them, only the store link remains and the file can be swept.
+#[derive(Clone, Debug)]
+pub struct ContentStore {
+    root: FilePath,
+}
+
+#[derive(Debug, Default)]
+pub struct SweepResult {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl ContentStore {
+    pub fn new(output: &FilePath) -> Self {
+        Self {
+            root: output.join(".store"),
+        }
+    }
+
+    pub fn path_for(&self, checksum: &Checksum) -> FilePath {
+        let algo = match checksum.checksum_type() {
+            ChecksumType::Md5 => "md5",
+            ChecksumType::Sha1 => "sha1",
+            ChecksumType::Sha256 => "sha256",
+            ChecksumType::Sha512 => "sha512",
+        };
+
+        let hex = checksum.to_string();
+
+        self.root
+            .join(format_compact!("{algo}/{}/{hex}", &hex[..2]))
+    }
+
+    /// Links the target of the download from the store, if the store has a file with the same
+    /// checksum. Returns the number of bytes that did not have to be downloaded.
+    pub async fn link_into(
+        &self,
+        download: &Download,
+        partials: &PartialDir,
+        storage: &dyn Storage,
+    ) -> Option<u64> {
+        let checksum = download.checksum.as_ref()?;
+
+        let stored_path = self.path_for(checksum);
+
+        let stored_size = tokio::fs::metadata(&stored_path).await.ok()?.len();
+
+        if download.size.is_some_and(|size| size != stored_size) {
+            return None;
+        }
+
+        // the link is staged like a download, and keeps its inode when it is put into place
+        let partial = partials.for_target(&download.primary_target_path);
+        create_dirs(&partial.path).await.ok()?;
+
+        _ = tokio::fs::remove_file(&partial.path).await;
+
+        // the store can be on another file system than the staging dir, in which case the file
+        // is downloaded like any other
+        tokio::fs::hard_link(&stored_path, &partial.path)
+            .await
+            .ok()?;
+
+        storage
+            .put(&partial.path, &download.primary_target_path)
+            .await
+            .ok()?;
+
+        Some(stored_size)
+    }
+
+    /// Adds a downloaded and verified file to the store, unless the store already has it.
+    pub async fn insert(&self, download: &Download) -> Result<()> {
+        let Some(checksum) = &download.checksum else {
+            return Ok(());
+        };
+
+        let stored_path = self.path_for(checksum);
+
+        if stored_path.exists() {
+            return Ok(());
+        }
+
+        create_dirs(&stored_path).await?;
+
+        match tokio::fs::hard_link(&download.primary_target_path, &stored_path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes stored files that are no longer linked from any mirror.
+    pub fn sweep(&self, dry_run: bool) -> Result<SweepResult> {
+        let mut result = SweepResult::default();
+
+        if !self.root.exists() {
+            return Ok(result);
+        }
+
+        for entry in WalkDir::new(&self.root) {
+            let entry = entry?;
+
+            if entry.file_type().is_dir() {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+
+            if metadata.nlink() > 1 {
+                continue;
+            }
+
+            result.files += 1;
+            result.bytes += metadata.len();
+
+            if dry_run {
+                eprintln!("{}", entry.path().display());
+            } else {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{dedup::*, storage::LocalStorage, testing::TestDir};
+
+    #[tokio::test]
+    async fn linked_files_are_swept_when_unreferenced() {
+        let output = TestDir::new("dedup");
+
+        let store = ContentStore::new(&output);
+
+        let checksum = Checksum::Md5(md5::compute(b"package").0);
+
+        let first = Download {
+            url: "http://a.example/pool/p.deb".into(),
+            fallback_urls: Vec::new(),
+            size: Some(7),
+            checksum: Some(checksum),
+            primary_target_path: output.join("a/pool/p.deb"),
+            symlink_paths: Vec::new(),
+            always_download: false,
+            optional: false,
+            bandwidth_limiter: None,
+        };
+
+        create_dirs(&first.primary_target_path).await.unwrap();
+        std::fs::write(&first.primary_target_path, b"package").unwrap();
+
+        store.insert(&first).await.unwrap();
+
+        let second = Download {
+            primary_target_path: output.join("b/pool/p.deb"),
+            ..first
+        };
+
+        let partials = PartialDir::new(&output.join(".tmp"), &output);
+
+        assert_eq!(
+            store.link_into(&second, &partials, &LocalStorage).await,
+            Some(7)
+        );
+
+        std::fs::remove_file(&second.primary_target_path).unwrap();
+        assert_eq!(store.sweep(false).unwrap().files, 0);
+
+        std::fs::remove_file(output.join("a/pool/p.deb")).unwrap();
+        assert_eq!(store.sweep(false).unwrap().files, 1);
+    }
+}
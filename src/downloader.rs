@@ -1,21 +1,41 @@
 use std::{
+    collections::VecDeque,
     fs::FileTimes,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
-        Arc,
+        Arc, Mutex,
         atomic::{AtomicU64, Ordering},
     },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use async_channel::{Receiver, Sender, bounded};
-use compact_str::{CompactString, ToCompactString};
-use reqwest::{Client, StatusCode};
-use tokio::{fs::symlink, io::AsyncWriteExt, task::JoinHandle};
+use ahash::HashMap;
+use async_channel::{Receiver, Sender, TryRecvError, bounded};
+use compact_str::{CompactString, ToCompactString, format_compact};
+use reqwest::{
+    Client, Response, StatusCode, Url,
+    header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{Notify, OwnedMutexGuard},
+    task::{JoinHandle, spawn_blocking},
+    time::sleep,
+};
+use walkdir::WalkDir;
 
 use crate::{
+    CliOpts,
+    bandwidth::BandwidthLimiter,
+    dedup::ContentStore,
     error::{MirsError, Result},
-    metadata::{FilePath, checksum::Checksum},
+    metadata::{
+        FilePath,
+        checksum::{Checksum, Hasher},
+    },
+    seed::SeedIndex,
+    storage::{LocalStorage, SharedStorage, Storage},
 };
 
 use super::progress::Progress;
@@ -33,11 +53,10 @@ fn now() -> Arc<AtomicU64> {
 #[derive(Clone)]
 pub struct Downloader {
     sender: Sender<Box<Download>>,
+    queue: Arc<DownloadQueue>,
     _tasks: Arc<Vec<JoinHandle<()>>>,
-    progress: Progress,
-    http_client: Client,
+    worker: DownloadWorker,
     pub time_to_set: Arc<AtomicU64>,
-    mtime: bool,
 }
 
 impl Default for Downloader {
@@ -45,218 +64,677 @@ impl Default for Downloader {
         let (sender, _) = bounded(1);
         Self {
             sender,
+            queue: Default::default(),
             _tasks: Default::default(),
-            progress: Default::default(),
-            http_client: Default::default(),
+            worker: Default::default(),
             time_to_set: now(),
-            mtime: false,
         }
     }
 }
 
-impl Downloader {
-    pub fn build(num_threads: u8, mtime: bool) -> Self {
-        let (sender, receiver) = bounded(1024);
+/// Everything a download task needs to fetch a file and account for it. Every task gets a
+/// clone, and the shared parts (progress, rate limits, stores) are shared between the clones.
+#[derive(Clone, Default)]
+struct DownloadWorker {
+    http_client: Client,
+    progress: Progress,
+    time: Option<Arc<AtomicU64>>,
+    retry_policy: RetryPolicy,
+    bandwidth_limiter: Option<BandwidthLimiter>,
+    store: Option<ContentStore>,
+    seeds: Option<Arc<SeedIndex>>,
+    storage: SharedStorage,
+    staging_dir: FilePath,
+    partials: PartialDir,
+    in_flight: InFlight,
+}
 
-        let mut tasks = Vec::with_capacity(num_threads as usize);
-        let progress = Progress::new();
-        let http_client = reqwest::Client::new();
+/// The downloads that are being processed, by target path. Suites that share an output folder
+/// often queue the same pool files, and a request for a file that is already in flight waits
+/// for that download to finish instead of writing the same file at the same time. This holds
+/// for any two downloads of the same target, also when they expect different checksums, since
+/// they would share the partial file.
+#[derive(Clone, Default)]
+struct InFlight(Arc<Mutex<HashMap<FilePath, Arc<tokio::sync::Mutex<()>>>>>);
 
-        let time_to_set = now();
+struct InFlightGuard {
+    in_flight: InFlight,
+    key: FilePath,
+    _guard: OwnedMutexGuard<()>,
+}
 
-        for _ in 0..num_threads {
-            let task_receiver: Receiver<Box<Download>> = receiver.clone();
-            let task_progress = progress.clone();
-            let task_http_client = http_client.clone();
-            let task_time = if mtime {
-                Some(time_to_set.clone())
-            } else {
-                None
-            };
+impl InFlight {
+    async fn claim(&self, dl: &Download) -> InFlightGuard {
+        let key = dl.primary_target_path.clone();
+
+        let lock = self
+            .0
+            .lock()
+            .expect("in flight lock poisoned")
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        InFlightGuard {
+            in_flight: self.clone(),
+            key,
+            _guard: lock.lock_owned().await,
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.0.lock().expect("in flight lock poisoned");
+
+        // the map and this guard hold the only references when nobody else is waiting
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) <= 2)
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// The downloads queued by every repository, each in a lane of its own. The download tasks
+/// take from the lanes in turn, so that a repository with a few files is not stuck behind one
+/// with a lot of them.
+#[derive(Default)]
+struct DownloadQueue {
+    lanes: Mutex<VecDeque<Lane>>,
+    notify: Notify,
+}
+
+struct Lane {
+    receiver: Receiver<Box<Download>>,
+    worker: Arc<DownloadWorker>,
+}
+
+impl DownloadQueue {
+    fn add_lane(&self, receiver: Receiver<Box<Download>>, worker: DownloadWorker) {
+        self.lanes
+            .lock()
+            .expect("download queue lock poisoned")
+            .push_back(Lane {
+                receiver,
+                worker: Arc::new(worker),
+            });
+    }
+
+    fn try_next(&self) -> Option<(Box<Download>, Arc<DownloadWorker>)> {
+        let mut lanes = self.lanes.lock().expect("download queue lock poisoned");
+
+        for _ in 0..lanes.len() {
+            let lane = lanes.pop_front()?;
+
+            match lane.receiver.try_recv() {
+                Ok(dl) => {
+                    let worker = lane.worker.clone();
+                    lanes.push_back(lane);
+                    return Some((dl, worker));
+                }
+                Err(TryRecvError::Empty) => lanes.push_back(lane),
+                // every handle of the lane is gone and it has nothing more to give
+                Err(TryRecvError::Closed) => (),
+            }
+        }
+
+        None
+    }
+
+    async fn next(&self) -> (Box<Download>, Arc<DownloadWorker>) {
+        loop {
+            if let Some(next) = self.try_next() {
+                // wakes another task, in case more was queued than there were tasks woken
+                self.notify.notify_one();
+                return next;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+}
+
+impl Downloader {
+    pub async fn build(cli_opts: &CliOpts, mtime: bool) -> Self {
+        let queue = Arc::new(DownloadQueue::default());
+
+        let mut tasks = Vec::with_capacity(cli_opts.dl_threads as usize);
+
+        for _ in 0..cli_opts.dl_threads {
+            let task_queue = queue.clone();
 
             let handle = tokio::spawn(async move {
-                while let Ok(dl) = task_receiver.recv().await {
-                    _ = Downloader::download_and_track(
-                        &task_http_client,
-                        task_time.clone(),
-                        task_progress.clone(),
-                        dl,
-                    )
-                    .await;
+                loop {
+                    let (dl, worker) = task_queue.next().await;
+                    _ = worker.download_and_track(dl).await;
                 }
             });
 
             tasks.push(handle);
         }
 
+        let time_to_set = now();
+
+        let worker = DownloadWorker {
+            http_client: reqwest::Client::new(),
+            progress: Progress::new(),
+            time: if mtime {
+                Some(time_to_set.clone())
+            } else {
+                None
+            },
+            retry_policy: cli_opts.retry_policy(),
+            bandwidth_limiter: cli_opts.bandwidth_limit.map(BandwidthLimiter::new),
+            // linked files share their inode, so setting the mtime of one would change it in
+            // every mirror that links the same file
+            store: cli_opts.content_store().filter(|_| !mtime),
+            seeds: SeedIndex::build(cli_opts.seed_dir.clone(), cli_opts.seed_hardlink)
+                .await
+                .map(Arc::new),
+            storage: cli_opts.storage(),
+            staging_dir: cli_opts.staging_dir(),
+            partials: PartialDir::new(&cli_opts.staging_dir(), &cli_opts.output),
+            in_flight: InFlight::default(),
+        };
+
+        Self::with_lane(queue, Arc::new(tasks), worker, time_to_set)
+    }
+
+    /// Creates a handle with its own progress and mtime, that shares the download tasks with
+    /// this one. Downloads queued through different handles are taken in turn.
+    pub fn for_repository(&self) -> Self {
+        let time_to_set = now();
+
+        let worker = DownloadWorker {
+            progress: Progress::new(),
+            time: self.worker.time.as_ref().map(|_| time_to_set.clone()),
+            ..self.worker.clone()
+        };
+
+        Self::with_lane(self.queue.clone(), self._tasks.clone(), worker, time_to_set)
+    }
+
+    fn with_lane(
+        queue: Arc<DownloadQueue>,
+        tasks: Arc<Vec<JoinHandle<()>>>,
+        worker: DownloadWorker,
+        time_to_set: Arc<AtomicU64>,
+    ) -> Self {
+        let (sender, receiver) = bounded(1024);
+
+        queue.add_lane(receiver, worker.clone());
+
         Self {
             sender,
-            _tasks: Arc::new(tasks),
-            progress,
-            http_client,
+            queue,
+            _tasks: tasks,
+            worker,
             time_to_set,
-            mtime,
         }
     }
 
     pub async fn queue(&self, download_entry: Box<Download>) -> Result<()> {
         if let Some(size) = download_entry.size {
-            self.progress.bytes.inc_total(size);
+            self.worker.progress.bytes.inc_total(size);
         }
 
-        self.progress.files.inc_total(1);
+        self.worker.progress.files.inc_total(1);
 
         self.sender.send(download_entry).await?;
+        self.queue.notify.notify_one();
 
         Ok(())
     }
 
-    async fn download_and_track(
-        http_client: &Client,
-        time: Option<Arc<AtomicU64>>,
-        progress: Progress,
-        dl: Box<Download>,
-    ) {
+    /// Downloads right away instead of queueing, and returns the error of a failed download
+    /// along with accounting for it.
+    pub async fn download(&self, download: Box<Download>) -> Result<()> {
+        self.worker.download_and_track(download).await
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.worker.progress.clone()
+    }
+
+    /// Whether the target of the download is missing, or differs in size from it.
+    pub async fn needs_downloading(&self, download: &Download) -> bool {
+        self.worker.needs_downloading(download).await
+    }
+
+    pub fn storage(&self) -> SharedStorage {
+        self.worker.storage.clone()
+    }
+
+    pub fn partials(&self) -> &PartialDir {
+        &self.worker.partials
+    }
+
+    pub fn set_time(&self, new_time: u64) {
+        self.time_to_set.store(new_time, Ordering::Relaxed);
+    }
+}
+
+impl DownloadWorker {
+    async fn download_and_track(&self, dl: Box<Download>) -> Result<()> {
+        let progress = &self.progress;
+
         let file_size = dl.size;
 
-        match download_file(http_client, time, dl, |downloaded| {
-            progress.bytes.inc_success(downloaded)
-        })
-        .await
-        {
-            Ok(true) => progress.files.inc_success(1),
+        // whether the target still needs downloading is only checked once the claim is held,
+        // so a download of the same file that finished while waiting leaves nothing to do
+        let _in_flight = self.in_flight.claim(&dl).await;
+
+        if self.needs_downloading(&dl).await {
+            if let Some(store) = &self.store
+                && let Some(linked) = store
+                    .link_into(
+                        &dl,
+                        &self.partials,
+                        self.storage_for(&dl.primary_target_path),
+                    )
+                    .await
+            {
+                self.track_local(&dl, || progress.inc_deduplicated(linked))
+                    .await;
+                return Ok(());
+            }
+
+            if let Some(seeds) = &self.seeds
+                && let Ok(Some(seeded)) = seeds
+                    .seed_into(
+                        &dl,
+                        &self.partials,
+                        self.storage_for(&dl.primary_target_path),
+                    )
+                    .await
+            {
+                if let Some(store) = &self.store {
+                    _ = store.insert(&dl).await;
+                }
+
+                self.track_local(&dl, || progress.inc_seeded(seeded)).await;
+                return Ok(());
+            }
+        }
+
+        let mut attempt = 0;
+
+        let result = loop {
+            match self
+                .download_from_mirrors(&dl, |downloaded| progress.bytes.inc_success(downloaded))
+                .await
+            {
+                Err(e) if e.is_transient() && attempt < self.retry_policy.retries => {
+                    attempt += 1;
+                    progress.inc_retries(1);
+
+                    sleep(self.retry_policy.delay(attempt, e.retry_after())).await;
+                }
+                result => break result,
+            }
+        };
+
+        match &result {
+            Ok(true) => {
+                if let Some(store) = &self.store {
+                    _ = store.insert(&dl).await;
+                }
+
+                progress.files.inc_success(1)
+            }
             Ok(false) => progress.files.inc_skipped(1),
             Err(e) => match e {
-                MirsError::Checksum { .. } => progress.files.inc_failed(1),
+                e if dl.optional && e.is_not_found() => {
+                    if let Some(size) = file_size {
+                        progress.bytes.inc_skipped(size);
+                    }
+
+                    progress.files.inc_skipped(1);
+                }
+                MirsError::Checksum { .. } => {
+                    progress.files.inc_failed(1);
+                    progress.add_corrupt_url(&dl.url);
+                }
                 MirsError::Download { .. } => {
                     if let Some(size) = file_size {
                         progress.bytes.inc_skipped(size);
                     }
 
                     progress.files.inc_skipped(1);
+                    progress.inc_errors(1);
+                    progress.add_failed_url(&dl.url);
+                }
+                _ => {
+                    progress.files.inc_skipped(1);
+                    progress.inc_errors(1);
+                    progress.add_failed_url(&dl.url);
                 }
-                _ => progress.files.inc_skipped(1),
             },
         }
+
+        result.map(|_| ())
     }
 
-    pub async fn download(&self, download: Box<Download>) {
-        let time = if self.mtime {
-            Some(self.time_to_set.clone())
-        } else {
-            None
-        };
-        Downloader::download_and_track(&self.http_client, time, self.progress.clone(), download)
-            .await
+    /// Accounts for a file that was put in place from a local source instead of downloaded.
+    async fn track_local<F: FnOnce()>(&self, dl: &Download, count: F) {
+        match self.create_symlinks(dl).await {
+            Ok(()) => {
+                self.progress.files.inc_success(1);
+                count();
+            }
+            Err(_) => {
+                self.progress.files.inc_skipped(1);
+                self.progress.inc_errors(1);
+                self.progress.add_failed_url(&dl.url);
+            }
+        }
     }
 
-    pub fn progress(&self) -> Progress {
-        self.progress.clone()
+    /// Downloads from the first url, falling back to the mirrors in `fallback_urls` when the
+    /// file is missing or the host can not be reached. Any mirror is fine, since the checksum
+    /// comes from the release that was chosen.
+    async fn download_from_mirrors<F>(
+        &self,
+        download: &Download,
+        mut progress_cb: F,
+    ) -> Result<bool>
+    where
+        F: FnMut(u64),
+    {
+        let mut result = self
+            .download_file(download, &download.url, &mut progress_cb)
+            .await;
+
+        for url in &download.fallback_urls {
+            match &result {
+                Err(e) if e.is_transient() || e.is_not_found() => (),
+                _ => break,
+            }
+
+            result = self.download_file(download, url, &mut progress_cb).await;
+        }
+
+        result
     }
 
-    pub fn set_time(&self, new_time: u64) {
-        self.time_to_set.store(new_time, Ordering::Relaxed);
+    async fn download_file<F>(
+        &self,
+        download: &Download,
+        url: &str,
+        mut progress_cb: F,
+    ) -> Result<bool>
+    where
+        F: FnMut(u64),
+    {
+        let mut downloaded = false;
+
+        if self.needs_downloading(download).await {
+            if download.size.is_some_and(|v| v > 0) || download.size.is_none() {
+                if let Some(source) = local_source(url) {
+                    self.fetch_local(download, url, &source, &mut progress_cb)
+                        .await?;
+                } else {
+                    self.fetch_http(download, url, &mut progress_cb).await?;
+                }
+
+                downloaded = true;
+            } else {
+                self.storage_for(&download.primary_target_path)
+                    .create(&download.primary_target_path)
+                    .await?;
+            }
+        }
+
+        self.create_symlinks(download).await?;
+
+        Ok(downloaded)
     }
-}
 
-async fn download_file<F>(
-    http_client: &Client,
-    time: Option<Arc<AtomicU64>>,
-    download: Box<Download>,
-    mut progress_cb: F,
-) -> Result<bool>
-where
-    F: FnMut(u64),
-{
-    let mut downloaded = false;
-
-    if needs_downloading(&download) {
-        create_dirs(&download.primary_target_path).await?;
-
-        let mut output = tokio::fs::File::create(&download.primary_target_path).await?;
-
-        if download.size.is_some_and(|v| v > 0) || download.size.is_none() {
-            let mut response = match http_client.get(download.url.as_str()).send().await {
-                Ok(r) => r,
-                Err(..) => {
-                    drop(output);
-                    tokio::fs::remove_file(&download.primary_target_path).await?;
-                    return Err(MirsError::Download {
-                        url: download.url.clone(),
-                        status_code: None,
-                    });
-                }  
-            };
+    async fn fetch_http<F>(&self, download: &Download, url: &str, mut progress_cb: F) -> Result<()>
+    where
+        F: FnMut(u64),
+    {
+        let partial = self.partials.for_target(&download.primary_target_path);
+        create_dirs(&partial.path).await?;
 
-            if response.status() != StatusCode::OK {
-                drop(output);
-                tokio::fs::remove_file(&download.primary_target_path).await?;
+        let resume = partial.resume_state().await;
+
+        let mut request = self.http_client.get(url);
+
+        if let Some((offset, validator)) = &resume {
+            request = request
+                .header(RANGE, format!("bytes={offset}-"))
+                .header(IF_RANGE, validator.as_str());
+        }
+
+        let mut response = match request.send().await {
+            Ok(r) => r,
+            Err(..) => {
                 return Err(MirsError::Download {
-                    url: download.url.clone(),
-                    status_code: Some(response.status()),
+                    url: url.to_compact_string(),
+                    status_code: None,
+                    retry_after: None,
                 });
             }
+        };
 
-            if let Some(expected_checksum) = download.checksum {
-                let mut hasher = expected_checksum.create_hasher();
+        // a server that does not support ranges, or has a newer version of the file than
+        // the one we have a part of, responds with the whole file
+        let resumed_from = match (response.status(), &resume) {
+            (StatusCode::PARTIAL_CONTENT, Some((offset, _)))
+                if content_range_start(&response) == Some(*offset) =>
+            {
+                Some(*offset)
+            }
+            (StatusCode::OK, _) => None,
+            (status, _) => {
+                partial.remove().await?;
+                return Err(MirsError::Download {
+                    url: url.to_compact_string(),
+                    status_code: Some(status),
+                    retry_after: retry_after(&response),
+                });
+            }
+        };
 
-                while let Some(chunk) = response.chunk().await? {
-                    output.write_all(&chunk).await?;
-                    hasher.consume(&chunk);
+        let mut hasher = download.checksum.as_ref().map(Checksum::create_hasher);
 
-                    progress_cb(chunk.len() as u64);
-                }
+        let mut output = if resumed_from.is_some() {
+            if let Some(hasher) = &mut hasher {
+                consume_file(&partial.path, hasher.as_mut()).await?;
+            }
 
-                let checksum = hasher.compute();
+            OpenOptions::new().append(true).open(&partial.path).await?
+        } else {
+            partial.store_validator(&response).await?;
+            tokio::fs::File::create(&partial.path).await?
+        };
 
-                if expected_checksum != checksum {
-                    drop(output);
-                    tokio::fs::remove_file(&download.primary_target_path).await?;
-                    return Err(MirsError::Checksum {
-                        url: download.url,
-                        expected: expected_checksum.to_compact_string(),
-                        hash: checksum.to_string(),
-                    });
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    // keep what we have so far, so that the next attempt can resume from it
+                    output.flush().await?;
+                    return Err(e.into());
                 }
-            } else {
-                while let Some(chunk) = response.chunk().await? {
-                    output.write_all(&chunk).await?;
+            };
 
-                    progress_cb(chunk.len() as u64);
-                }
+            for limiter in [
+                self.bandwidth_limiter.as_ref(),
+                download.bandwidth_limiter.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                limiter.consume(chunk.len() as u64).await;
             }
 
-            output.flush().await?;
-            downloaded = true;
+            output.write_all(&chunk).await?;
 
-            if let Some(time_to_set) = time {
-                let time = time_from_atomic(time_to_set);
-                let std_file = output.into_std().await;
-                tokio::task::spawn_blocking(move || {
-                    std_file.set_times(FileTimes::new().set_modified(time))
-                })
-                .await??;
+            if let Some(hasher) = &mut hasher {
+                hasher.consume(&chunk);
+            }
+
+            progress_cb(chunk.len() as u64);
+        }
+
+        output.flush().await?;
+
+        if let (Some(expected_checksum), Some(hasher)) = (&download.checksum, hasher) {
+            let checksum = hasher.compute();
+
+            if *expected_checksum != checksum {
+                drop(output);
+                partial.remove().await?;
+                return Err(MirsError::Checksum {
+                    url: url.to_compact_string(),
+                    expected: expected_checksum.to_compact_string(),
+                    hash: checksum.to_string(),
+                });
             }
         }
+
+        self.finish_partial(output, &partial, &download.primary_target_path)
+            .await
     }
 
-    for symlink_path in download.symlink_paths {
-        if symlink_path.exists() {
-            continue;
+    /// Copies a file from a local source. The copy lets the file system reflink or share blocks
+    /// where it can, and the checksum is verified like for any other download.
+    async fn fetch_local<F>(
+        &self,
+        download: &Download,
+        url: &str,
+        source: &Path,
+        mut progress_cb: F,
+    ) -> Result<()>
+    where
+        F: FnMut(u64),
+    {
+        if !source.is_file() {
+            return Err(MirsError::Download {
+                url: url.to_compact_string(),
+                status_code: Some(StatusCode::NOT_FOUND),
+                retry_after: None,
+            });
+        }
+
+        let partial = self.partials.for_target(&download.primary_target_path);
+        create_dirs(&partial.path).await?;
+
+        let copied = tokio::fs::copy(source, &partial.path).await?;
+
+        if let Some(expected_checksum) = &download.checksum {
+            let checksum = Checksum::checksum_file_with_hasher(
+                &partial.path,
+                expected_checksum.create_hasher(),
+            )
+            .await?;
+
+            if *expected_checksum != checksum {
+                partial.remove().await?;
+                return Err(MirsError::Checksum {
+                    url: url.to_compact_string(),
+                    expected: expected_checksum.to_compact_string(),
+                    hash: checksum.to_string(),
+                });
+            }
         }
 
-        let rel_primary_path = pathdiff::diff_paths(
-            &download.primary_target_path,
-            symlink_path.parent().expect("base dir needs to exist"),
-        )
-        .expect("all files will be in some relative path");
+        progress_cb(copied);
 
-        create_dirs(&symlink_path).await?;
+        let output = tokio::fs::File::open(&partial.path).await?;
 
-        symlink(&rel_primary_path, &symlink_path).await?;
+        self.finish_partial(output, &partial, &download.primary_target_path)
+            .await
     }
 
-    Ok(downloaded)
+    /// Puts a complete and verified partial file into place in the storage.
+    async fn finish_partial(
+        &self,
+        output: tokio::fs::File,
+        partial: &PartialFile,
+        target: &FilePath,
+    ) -> Result<()> {
+        let time = self.time.clone().map(time_from_atomic);
+        let std_file = output.into_std().await;
+
+        // the data has to be on disk before it is put into place, or a crash could publish a
+        // file with a complete name but incomplete content
+        tokio::task::spawn_blocking(move || {
+            if let Some(time) = time {
+                std_file.set_times(FileTimes::new().set_modified(time))?;
+            }
+
+            std_file.sync_all()
+        })
+        .await??;
+
+        self.storage_for(target).put(&partial.path, target).await?;
+        partial.remove_validator().await
+    }
+
+    async fn create_symlinks(&self, download: &Download) -> Result<()> {
+        for symlink_path in &download.symlink_paths {
+            let storage = self.storage_for(symlink_path);
+
+            if storage.exists(symlink_path).await? {
+                continue;
+            }
+
+            let rel_primary_path = pathdiff::diff_paths(
+                &download.primary_target_path,
+                symlink_path.parent().expect("base dir needs to exist"),
+            )
+            .expect("all files will be in some relative path");
+
+            storage.symlink(&rel_primary_path, symlink_path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The storage that `path` goes into. Metadata is downloaded into the staging dir, where it
+    /// stays on the local disk until the run publishes it.
+    fn storage_for(&self, path: &FilePath) -> &dyn Storage {
+        if Path::new(path.as_str()).starts_with(&self.staging_dir) {
+            &LocalStorage
+        } else {
+            &*self.storage
+        }
+    }
+
+    async fn needs_downloading(&self, dl: &Download) -> bool {
+        if dl.always_download {
+            return true;
+        }
+
+        if let Ok(Some(meta)) = self
+            .storage_for(&dl.primary_target_path)
+            .stat(&dl.primary_target_path)
+            .await
+        {
+            if let Some(size) = dl.size {
+                return size != meta.size;
+            }
+
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Returns the local path of `file://` urls.
+fn local_source(url: &str) -> Option<PathBuf> {
+    if !url.starts_with("file://") {
+        return None;
+    }
+
+    Url::parse(url).ok()?.to_file_path().ok()
 }
 
 pub fn time_from_atomic(time: Arc<AtomicU64>) -> SystemTime {
@@ -275,28 +753,561 @@ pub async fn create_dirs<P: AsRef<Path>>(path: P) -> Result<()> {
     Ok(())
 }
 
-fn needs_downloading(dl: &Download) -> bool {
-    if dl.always_download {
-        return true;
+/// How many times, and how far apart, downloads that fail with a transient error are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    const MAX_DELAY: Duration = Duration::from_secs(300);
+
+    /// The delay before the given attempt. A delay requested by the server is used as is,
+    /// otherwise the delay doubles for every attempt, with jitter so that workers that failed
+    /// at the same time do not retry at the same time.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(Self::MAX_DELAY);
+        }
+
+        let backoff = self
+            .backoff
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(Self::MAX_DELAY);
+
+        backoff / 2 + backoff.mul_f64(rand::random_range(0.0..0.5))
+    }
+}
+
+/// Reads the Retry-After header, which is either a number of seconds or an HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+
+    (date.to_utc() - chrono::Utc::now()).to_std().ok()
+}
+
+fn content_range_start(response: &Response) -> Option<u64> {
+    let content_range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+
+    content_range
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .parse()
+        .ok()
+}
+
+async fn consume_file(path: &FilePath, hasher: &mut dyn Hasher) -> Result<()> {
+    let mut file = tokio::fs::File::open(path).await?;
+
+    let mut buf = vec![0_u8; 1024 * 64];
+
+    loop {
+        match file.read(&mut buf).await? {
+            0 => break,
+            n => hasher.consume(&buf[..n]),
+        }
+    }
+
+    Ok(())
+}
+
+/// The folder in the staging dir that partial files are kept in, under the relative paths of
+/// their targets. Keeping them out of the mirror means that only this folder has to be searched
+/// for the ones that interrupted runs left behind.
+pub const PARTIAL_DIR: &str = "partial";
+
+/// Where the partial files of the downloads into an output folder go.
+#[derive(Clone, Default)]
+pub struct PartialDir {
+    dir: FilePath,
+    output: FilePath,
+}
+
+impl PartialDir {
+    pub fn new(staging_dir: &FilePath, output: &FilePath) -> Self {
+        Self {
+            dir: staging_dir.join(PARTIAL_DIR),
+            output: output.clone(),
+        }
+    }
+
+    pub fn for_target(&self, target: &FilePath) -> PartialFile {
+        // a target outside of the output keeps its partial file next to it
+        let path = match Path::new(target.as_str()).strip_prefix(&self.output) {
+            Ok(rel_path) => self.dir.join(rel_path.to_string_lossy()),
+            Err(_) => target.clone(),
+        };
+
+        PartialFile {
+            path: FilePath(format_compact!("{path}.partial")),
+            validator_path: FilePath(format_compact!("{path}.partial.validator")),
+        }
+    }
+
+    /// The target of a partial file in the folder.
+    fn target_of(&self, partial_path: &Path) -> Option<FilePath> {
+        let rel_path = partial_path.strip_prefix(&self.dir).ok()?.to_str()?;
+
+        Some(self.output.join(rel_path.strip_suffix(".partial")?))
+    }
+}
+
+/// The in-progress data of a download is kept in a `.partial` file, along with the validator
+/// (ETag or Last-Modified) of the response it came from. The data is only moved into the
+/// target when it is complete and its checksum is verified.
+pub struct PartialFile {
+    pub path: FilePath,
+    validator_path: FilePath,
+}
+
+impl PartialFile {
+    async fn resume_state(&self) -> Option<(u64, String)> {
+        let len = tokio::fs::metadata(&self.path).await.ok()?.len();
+
+        if len == 0 {
+            return None;
+        }
+
+        let validator = tokio::fs::read_to_string(&self.validator_path).await.ok()?;
+
+        Some((len, validator))
     }
 
-    if let Ok(metadata) = dl.primary_target_path.metadata() {
-        if let Some(size) = dl.size {
-            return size != metadata.len();
+    async fn store_validator(&self, response: &Response) -> Result<()> {
+        // weak etags can not be used with If-Range
+        let validator = response
+            .headers()
+            .get(ETAG)
+            .filter(|v| !v.as_bytes().starts_with(b"W/"))
+            .or_else(|| response.headers().get(LAST_MODIFIED))
+            .and_then(|v| v.to_str().ok());
+
+        match validator {
+            Some(validator) => tokio::fs::write(&self.validator_path, validator).await?,
+            None => self.remove_validator().await?,
         }
 
-        return false;
+        Ok(())
+    }
+
+    async fn remove(&self) -> Result<()> {
+        remove_if_exists(&self.path).await?;
+        self.remove_validator().await
+    }
+
+    async fn remove_validator(&self) -> Result<()> {
+        remove_if_exists(&self.validator_path).await
+    }
+}
+
+/// Removes `.partial` files left behind by earlier runs that can not be resumed, either because
+/// the validator is missing or because the target has since been completed, along with
+/// validators that have lost their `.partial` file. Returns the number of removed files.
+pub async fn remove_orphaned_partials(partials: &PartialDir, storage: &dyn Storage) -> Result<u64> {
+    let dir = partials.dir.clone();
+
+    // the partial dir is in the staging dir, which is always on the local disk
+    let paths = spawn_blocking(move || {
+        WalkDir::new(&dir)
+            .into_iter()
+            .filter_map(|v| v.ok())
+            .filter(|v| !v.file_type().is_dir())
+            .map(|v| v.into_path())
+            .collect::<Vec<_>>()
+    })
+    .await?;
+
+    let mut removed = 0;
+
+    for path in paths {
+        let Some(path_str) = path.to_str() else {
+            continue;
+        };
+
+        let orphans = if let Some(target) = partials.target_of(&path) {
+            let validator = format!("{path_str}.validator");
+
+            if storage.exists(&target).await? || !Path::new(&validator).exists() {
+                vec![path_str.to_string(), validator]
+            } else {
+                Vec::new()
+            }
+        } else if let Some(partial) = path_str.strip_suffix(".validator")
+            && partial.ends_with(".partial")
+            && !Path::new(partial).exists()
+        {
+            vec![path_str.to_string()]
+        } else {
+            Vec::new()
+        };
+
+        for orphan in orphans {
+            match tokio::fs::remove_file(orphan).await {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                Err(_) => (),
+            }
+        }
     }
 
-    true
+    Ok(removed)
+}
+
+async fn remove_if_exists(path: &FilePath) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
 }
 
 #[derive(Debug)]
 pub struct Download {
     pub url: CompactString,
+    pub fallback_urls: Vec<CompactString>,
     pub size: Option<u64>,
     pub checksum: Option<Checksum>,
     pub primary_target_path: FilePath,
     pub symlink_paths: Vec<FilePath>,
     pub always_download: bool,
+    /// Whether the file may be missing from the mirror, like a variant of an index that the
+    /// release lists. Not finding it is then not an error.
+    pub optional: bool,
+    pub bandwidth_limiter: Option<BandwidthLimiter>,
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use sha2::{Digest, Sha256};
+
+    use crate::{downloader::*, testing::TestDir};
+
+    #[tokio::test]
+    async fn orphaned_partials_are_removed() {
+        let output = TestDir::new("partials");
+        let partials = PartialDir::new(&output.join(".tmp"), &output);
+
+        let files = [
+            // resumable, kept
+            ".tmp/partial/pool/resumable.deb.partial",
+            ".tmp/partial/pool/resumable.deb.partial.validator",
+            // no validator to resume with
+            ".tmp/partial/pool/unvalidated.deb.partial",
+            // target already completed
+            "pool/done.deb",
+            ".tmp/partial/pool/done.deb.partial",
+            ".tmp/partial/pool/done.deb.partial.validator",
+            // validator without data
+            ".tmp/partial/pool/lost.deb.partial.validator",
+            // only the partial dir is searched
+            "pool/elsewhere.deb.partial",
+        ];
+
+        for file in files {
+            output.write(file, b"data");
+        }
+
+        assert_eq!(
+            partials.for_target(&output.join("pool/resumable.deb")).path,
+            output.join(".tmp/partial/pool/resumable.deb.partial")
+        );
+
+        let removed = remove_orphaned_partials(&partials, &LocalStorage)
+            .await
+            .unwrap();
+
+        let remaining = |dir: &str| {
+            let mut files = std::fs::read_dir(output.join(dir))
+                .unwrap()
+                .map(|v| v.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        assert_eq!(removed, 4);
+        assert_eq!(
+            remaining(".tmp/partial/pool"),
+            vec!["resumable.deb.partial", "resumable.deb.partial.validator"]
+        );
+        assert_eq!(remaining("pool"), vec!["done.deb", "elsewhere.deb.partial"]);
+    }
+
+    #[tokio::test]
+    async fn queued_downloads_are_taken_from_lanes_in_turn() {
+        let queue = DownloadQueue::default();
+
+        let download = |url: &str| {
+            Box::new(Download {
+                url: url.into(),
+                fallback_urls: Vec::new(),
+                size: None,
+                checksum: None,
+                primary_target_path: FilePath::from("/dev/null"),
+                symlink_paths: Vec::new(),
+                always_download: false,
+                optional: false,
+                bandwidth_limiter: None,
+            })
+        };
+
+        let (large, large_receiver) = bounded(8);
+        let (small, small_receiver) = bounded(8);
+
+        queue.add_lane(large_receiver, DownloadWorker::default());
+        queue.add_lane(small_receiver, DownloadWorker::default());
+
+        for url in ["large-1", "large-2", "large-3"] {
+            large.send(download(url)).await.unwrap();
+        }
+
+        small.send(download("small-1")).await.unwrap();
+        drop(small);
+
+        let order = std::iter::from_fn(|| queue.try_next())
+            .map(|(dl, _)| dl.url)
+            .collect::<Vec<_>>();
+
+        assert_eq!(order, ["large-1", "small-1", "large-2", "large-3"]);
+        assert_eq!(queue.lanes.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn same_download_waits_for_the_one_in_flight() {
+        let in_flight = InFlight::default();
+
+        let download = |target: &str, content: &[u8]| Download {
+            url: "http://a.example/pool/p.deb".into(),
+            fallback_urls: Vec::new(),
+            size: Some(7),
+            checksum: Some(Checksum::Md5(md5::compute(content).0)),
+            primary_target_path: FilePath::from(target),
+            symlink_paths: Vec::new(),
+            always_download: false,
+            optional: false,
+            bandwidth_limiter: None,
+        };
+
+        let first = in_flight
+            .claim(&download("/mirror/pool/p.deb", b"package"))
+            .await;
+
+        let same = download("/mirror/pool/p.deb", b"package");
+        let waiting = tokio::time::timeout(Duration::from_millis(50), in_flight.claim(&same));
+        assert!(waiting.await.is_err());
+
+        // another checksum for the same target would still write the same partial file
+        let changed = download("/mirror/pool/p.deb", b"changed");
+        let waiting = tokio::time::timeout(Duration::from_millis(50), in_flight.claim(&changed));
+        assert!(waiting.await.is_err());
+
+        let _other = in_flight
+            .claim(&download("/mirror/pool/q.deb", b"package"))
+            .await;
+
+        drop(first);
+        assert_eq!(in_flight.0.lock().unwrap().len(), 1);
+    }
+
+    /// Answers each connection with the next of `responses`. Returns the url to request, and a
+    /// handle to the requests that were received.
+    async fn serve(responses: Vec<&'static str>) -> (CompactString, JoinHandle<Vec<String>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format_compact!("http://{}/pool/p.deb", listener.local_addr().unwrap());
+
+        let handle = tokio::spawn(async move {
+            let mut requests = Vec::new();
+
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        n => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+
+                requests.push(String::from_utf8(request).unwrap().to_lowercase());
+
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+
+            requests
+        });
+
+        (url, handle)
+    }
+
+    /// A worker downloading into `output`, with a partial download of `content` from a
+    /// response with `validator`.
+    async fn resumable(output: &TestDir, content: &str, validator: &str) -> (Downloader, Download) {
+        let cli_opts = CliOpts::parse_from(["aptmirs", "--output", output.as_str()]);
+        let downloader = Downloader::build(&cli_opts, false).await;
+
+        let download = Download {
+            url: "http://a.example/pool/p.deb".into(),
+            fallback_urls: Vec::new(),
+            size: Some(11),
+            checksum: Some(Checksum::Sha256(Sha256::digest(b"hello world").into())),
+            primary_target_path: output.join("pool/p.deb"),
+            symlink_paths: Vec::new(),
+            always_download: false,
+            optional: false,
+            bandwidth_limiter: None,
+        };
+
+        output.write(".tmp/partial/pool/p.deb.partial", content);
+        output.write(".tmp/partial/pool/p.deb.partial.validator", validator);
+
+        (downloader, download)
+    }
+
+    #[tokio::test]
+    async fn partial_download_resumes_with_a_matching_validator() {
+        let output = TestDir::new("resume");
+        let (downloader, download) = resumable(&output, "hello ", "\"v1\"").await;
+
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 6-10/11\r\nContent-Length: 5\r\nConnection: close\r\n\r\nworld",
+        ])
+        .await;
+
+        downloader
+            .worker
+            .fetch_http(&download, &url, |_| ())
+            .await
+            .unwrap();
+
+        let requests = requests.await.unwrap();
+        assert!(requests[0].contains("range: bytes=6-\r\n"));
+        assert!(requests[0].contains("if-range: \"v1\"\r\n"));
+
+        assert_eq!(
+            std::fs::read_to_string(&download.primary_target_path).unwrap(),
+            "hello world"
+        );
+        assert!(!output.join(".tmp/partial/pool/p.deb.partial").exists());
+        assert!(
+            !output
+                .join(".tmp/partial/pool/p.deb.partial.validator")
+                .exists()
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_download_restarts_when_the_validator_changed() {
+        let output = TestDir::new("resume");
+        let (downloader, download) = resumable(&output, "HELLO ", "\"v1\"").await;
+
+        // the file changed upstream, so the server ignores the range and sends all of it
+        let (url, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world",
+        ])
+        .await;
+
+        downloader
+            .worker
+            .fetch_http(&download, &url, |_| ())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&download.primary_target_path).unwrap(),
+            "hello world"
+        );
+    }
+
+    #[tokio::test]
+    async fn full_response_to_a_range_request_replaces_the_partial_file() {
+        let output = TestDir::new("resume");
+        let (downloader, download) =
+            resumable(&output, "longer than the whole file", "\"v1\"").await;
+
+        // a server without range support
+        let (url, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world",
+        ])
+        .await;
+
+        downloader
+            .worker
+            .fetch_http(&download, &url, |_| ())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&download.primary_target_path).unwrap(),
+            "hello world"
+        );
+        assert!(
+            !output
+                .join(".tmp/partial/pool/p.deb.partial.validator")
+                .exists()
+        );
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_range_discards_the_partial_file() {
+        let output = TestDir::new("resume");
+        let (downloader, download) = resumable(&output, "hello world and more", "\"v1\"").await;
+
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */11\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world",
+        ])
+        .await;
+
+        let result = downloader.worker.fetch_http(&download, &url, |_| ()).await;
+
+        assert!(matches!(
+            result,
+            Err(MirsError::Download {
+                status_code: Some(StatusCode::RANGE_NOT_SATISFIABLE),
+                ..
+            })
+        ));
+        assert!(!output.join(".tmp/partial/pool/p.deb.partial").exists());
+        assert!(
+            !output
+                .join(".tmp/partial/pool/p.deb.partial.validator")
+                .exists()
+        );
+
+        // the next attempt starts over
+        downloader
+            .worker
+            .fetch_http(&download, &url, |_| ())
+            .await
+            .unwrap();
+
+        let requests = requests.await.unwrap();
+        assert!(!requests[1].contains("range:"));
+
+        assert_eq!(
+            std::fs::read_to_string(&download.primary_target_path).unwrap(),
+            "hello world"
+        );
+    }
 }
@@ -1,8 +1,9 @@
-use std::{num::ParseIntError, sync::Arc};
+use std::{num::ParseIntError, sync::Arc, time::Duration};
 
 use async_channel::SendError;
 use compact_str::CompactString;
 use hex::FromHexError;
+use indicatif::HumanBytes;
 use reqwest::StatusCode;
 use thiserror::Error;
 use tokio::task::JoinError;
@@ -28,6 +29,7 @@ pub enum MirsError {
     Download {
         url: CompactString,
         status_code: Option<StatusCode>,
+        retry_after: Option<Duration>,
     },
 
     #[error("failed to parse line {line}")]
@@ -118,6 +120,43 @@ pub enum MirsError {
     #[error("error occurred while finalizing mirror operation: {inner}")]
     Finalize { inner: Box<MirsError> },
 
+    #[error("error occurred while taking a snapshot: {inner}")]
+    Snapshot { inner: Box<MirsError> },
+
+    #[error("no snapshot {id} in the configured mirrors")]
+    SnapshotNotFound { id: CompactString },
+
+    #[error("snapshot {id} already exists")]
+    SnapshotExists { id: CompactString },
+
+    #[error(
+        "not enough free space in {path}: {} is needed, but {} is available",
+        HumanBytes(*.required),
+        HumanBytes(*.available)
+    )]
+    InsufficientSpace {
+        path: FilePath,
+        required: u64,
+        available: u64,
+    },
+
+    #[error(
+        "{suite} needs {}, but its quota is {}",
+        HumanBytes(*.required),
+        HumanBytes(*.quota)
+    )]
+    QuotaExceeded {
+        suite: CompactString,
+        required: u64,
+        quota: u64,
+    },
+
+    #[error("unable to apply pdiffs: {msg}")]
+    Pdiff { msg: CompactString },
+
+    #[error("unable to publish {path}: {msg}")]
+    Publish { path: FilePath, msg: CompactString },
+
     #[error("error reading {path}: {inner}")]
     ReadingPackage {
         path: FilePath,
@@ -145,6 +184,9 @@ pub enum MirsError {
         inner: Box<MirsError>,
     },
 
+    #[error("unable to read inline PGP pub key: {inner}")]
+    PgpInlinePubKey { inner: Box<MirsError> },
+
     #[error("could not verify PGP signature")]
     PgpNotVerified,
 
@@ -154,3 +196,42 @@ pub enum MirsError {
     #[error("repository is in an inconsistent state, file stats: {progress}")]
     InconsistentRepository { progress: ProgressPart },
 }
+
+impl MirsError {
+    /// Whether the error is likely to go away if the operation is attempted again, such as
+    /// timeouts, dropped connections and overloaded servers.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MirsError::Download {
+                status_code: Some(status),
+                ..
+            } => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || status.is_server_error()
+            }
+            MirsError::Download {
+                status_code: None, ..
+            } => true,
+            MirsError::Reqwest(e) => e.is_timeout() || e.is_connect() || e.is_body(),
+            _ => false,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            MirsError::Download {
+                status_code: Some(StatusCode::NOT_FOUND | StatusCode::GONE),
+                ..
+            }
+        )
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            MirsError::Download { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
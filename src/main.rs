@@ -1,24 +1,38 @@
-use std::{fmt::Display, process::exit, sync::Arc};
+use std::{fmt::Display, process::exit, sync::Arc, time::Duration};
 
 use clap::Parser;
 use cmd::Cmd;
 use config::read_config;
+use dedup::ContentStore;
+use downloader::RetryPolicy;
 use metadata::FilePath;
 use pgp::PgpKeyStore;
+use storage::SharedStorage;
 
 use crate::error::Result;
 
+mod bandwidth;
 mod cmd;
 mod config;
 mod context;
+mod dedup;
 mod downloader;
 mod error;
 mod metadata;
+mod metrics;
 mod mirror;
 mod pgp;
 mod progress;
 mod prune;
+mod publish;
+mod quota;
+mod report;
+mod seed;
+mod snapshot;
 mod step;
+mod storage;
+#[cfg(test)]
+mod testing;
 mod verifier;
 mod verify;
 
@@ -36,12 +50,14 @@ async fn main() -> Result<()> {
             .await
     };
 
-    if let Err(e) = result {
-        println!("FATAL: {e}");
-        exit(-1)
+    match result {
+        Ok(0) => Ok(()),
+        Ok(exit_code) => exit(exit_code),
+        Err(e) => {
+            println!("FATAL: {e}");
+            exit(-1)
+        }
     }
-
-    Ok(())
 }
 
 #[derive(Parser)]
@@ -53,7 +69,7 @@ struct CliOpts {
         env,
         value_name = "CONFIG_FILE",
         default_value = "/etc/apt/mirror.list",
-        help = "The path to the config file containing the mirror options"
+        help = "The path to the config file containing the mirror options, or a directory of .list and .sources config files"
     )]
     config: FilePath,
 
@@ -66,6 +82,14 @@ struct CliOpts {
     )]
     output: FilePath,
 
+    #[arg(
+        long,
+        env,
+        value_name = "STAGING_DIR",
+        help = "The local directory that downloads are staged in until they are complete. Defaults to .tmp in the output directory, where complete files are moved into place with a rename"
+    )]
+    staging_dir: Option<FilePath>,
+
     #[arg(
         short,
         long,
@@ -76,6 +100,43 @@ struct CliOpts {
     )]
     dl_threads: u8,
 
+    #[arg(
+        long,
+        env,
+        value_name = "PARALLEL_REPOS",
+        default_value_t = 1_u8,
+        value_parser = clap::value_parser!(u8).range(1..),
+        help = "The maximum number of repositories that are mirrored at the same time, sharing the download tasks"
+    )]
+    parallel_repos: u8,
+
+    #[arg(
+        long,
+        env,
+        value_name = "RETRIES",
+        default_value_t = 3_u32,
+        help = "The number of times a download is retried after a transient error, such as a timeout or a 5xx response"
+    )]
+    retries: u32,
+
+    #[arg(
+        long,
+        env,
+        value_name = "MILLISECONDS",
+        default_value_t = 500_u64,
+        help = "The base delay before retrying a failed download, doubled for every following attempt"
+    )]
+    retry_backoff: u64,
+
+    #[arg(
+        long,
+        env,
+        value_name = "BYTES_PER_SEC",
+        value_parser = bandwidth::parse_rate,
+        help = "Limit the combined download rate of all download tasks, with an optional K, M, G or T suffix, e.g. 10M"
+    )]
+    bandwidth_limit: Option<u64>,
+
     #[arg(
         short,
         long,
@@ -85,6 +146,56 @@ struct CliOpts {
     )]
     pgp_key_path: Option<FilePath>,
 
+    #[arg(
+        long,
+        env,
+        value_name = "DEDUP",
+        help = "Keep a content addressed store of hardlinks in the output directory, and link files with the same checksum from it instead of downloading them again"
+    )]
+    dedup: bool,
+
+    #[arg(
+        long,
+        env,
+        value_name = "SEED_DIR",
+        value_delimiter = ',',
+        help = "A directory of existing files, like an old mirror or /var/cache/apt/archives, to copy files with matching checksums from instead of downloading them. Can be given several times"
+    )]
+    seed_dir: Vec<FilePath>,
+
+    #[arg(
+        long,
+        env,
+        value_name = "SEED_HARDLINK",
+        help = "Hardlink the files from --seed-dir instead of copying them. The mirror and the seed directory then share the files, so a change to one changes the other"
+    )]
+    seed_hardlink: bool,
+
+    #[arg(
+        long,
+        env,
+        value_name = "APPLY_PDIFFS",
+        help = "Patch the previously mirrored Packages and Sources indices with their pdiffs, instead of downloading them in full"
+    )]
+    apply_pdiffs: bool,
+
+    #[arg(
+        long,
+        env,
+        value_name = "ATOMIC_PUBLISH",
+        help = "Publish the metadata of every run as a new generation of dists, and switch a dists symlink to it in one step"
+    )]
+    atomic_publish: bool,
+
+    #[arg(
+        long,
+        env,
+        value_name = "KEEP_GENERATIONS",
+        default_value_t = 2_usize,
+        help = "The number of previous generations of dists that atomic publishing keeps to roll back to"
+    )]
+    keep_generations: usize,
+
     #[arg(
         short,
         long,
@@ -94,13 +205,59 @@ struct CliOpts {
     )]
     force: bool,
 
+    #[arg(
+        long,
+        env,
+        value_name = "REPORT_FILE",
+        help = "Write a JSON report of the run, with the outcome, step timings and counters of every repository, to this file"
+    )]
+    report_file: Option<FilePath>,
+
+    #[arg(
+        long,
+        env,
+        value_name = "METRICS_FILE",
+        help = "Write Prometheus metrics of mirror runs to this file, for the textfile collector of node_exporter (*.prom)"
+    )]
+    metrics_file: Option<FilePath>,
+
     #[command(subcommand)]
     command: Option<Cmd>,
+
+    #[arg(skip)]
+    storage: SharedStorage,
 }
 
 impl CliOpts {
     pub fn command(&self) -> Cmd {
-        self.command.unwrap_or_default()
+        self.command.clone().unwrap_or_default()
+    }
+
+    pub fn content_store(&self) -> Option<ContentStore> {
+        if self.dedup {
+            Some(ContentStore::new(&self.output))
+        } else {
+            None
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            backoff: Duration::from_millis(self.retry_backoff),
+        }
+    }
+
+    /// The storage that the mirror output is published to, which is the local file system unless
+    /// another one was set.
+    pub fn storage(&self) -> SharedStorage {
+        self.storage.clone()
+    }
+
+    pub fn staging_dir(&self) -> FilePath {
+        self.staging_dir
+            .clone()
+            .unwrap_or_else(|| self.output.join(".tmp"))
     }
 }
 
@@ -19,13 +19,17 @@ use crate::error::{MirsError, Result};
 use self::checksum::Checksum;
 
 pub mod checksum;
+pub mod dependency_resolver;
 pub mod diff_index_file;
 pub mod metadata_file;
+pub mod package_filter;
 pub mod packages_file;
+pub mod pdiff;
 pub mod release;
 pub mod repository;
 pub mod sources_file;
 pub mod sum_file;
+pub mod version;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Ord, Default, Hash)]
 pub struct FilePath(pub CompactString);
@@ -190,7 +194,19 @@ pub struct IndexFileEntry {
     pub path: CompactString,
     pub size: Option<u64>,
     pub checksum: Option<Checksum>,
+    pub meta: Option<PackageMeta>,
 }
+
+/// Control fields of the package that an entry in a Packages or Sources file belongs to.
+#[derive(Debug, Clone, Default)]
+pub struct PackageMeta {
+    pub name: CompactString,
+    pub section: Option<CompactString>,
+    pub priority: Option<CompactString>,
+    pub version: Option<CompactString>,
+    pub architecture: Option<CompactString>,
+}
+
 pub struct TrackingReader<R: Read> {
     inner: R,
     read: Arc<AtomicU64>,
@@ -12,7 +12,7 @@ use crate::error::{MirsError, Result};
 
 use super::FilePath;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Checksum {
     Md5([u8; 16]),
     Sha1([u8; 20]),
@@ -0,0 +1,328 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use ahash::{HashMap, HashSet, HashSetExt};
+use compact_str::CompactString;
+
+use super::packages_file::{Dependency, PackageRecord};
+
+/// Resolves the transitive closure of `Depends` and `Pre-Depends` for a set of seed packages.
+///
+/// Alternatives are satisfied by the first one that can be resolved, unless another alternative
+/// has already been selected. Virtual packages are satisfied by the first of their providers by
+/// name. Version constraints are ignored, so every version of a selected package is resolved,
+/// and `keep_versions` prunes them afterwards in `PackageFilter::select_versions`.
+#[derive(Default)]
+pub struct DependencyResolver {
+    records: Vec<PackageRecord>,
+    by_name: HashMap<CompactString, Vec<usize>>,
+    by_provides: HashMap<CompactString, Vec<usize>>,
+}
+
+#[derive(Default, Debug)]
+pub struct Resolution {
+    pub filenames: HashSet<CompactString>,
+    pub sources: HashSet<CompactString>,
+    pub unresolved: BTreeSet<CompactString>,
+}
+
+struct ResolveState {
+    selected: HashSet<usize>,
+    queue: VecDeque<usize>,
+}
+
+impl ResolveState {
+    fn select(&mut self, idx: usize) {
+        if self.selected.insert(idx) {
+            self.queue.push_back(idx);
+        }
+    }
+}
+
+impl DependencyResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, record: PackageRecord) {
+        let idx = self.records.len();
+
+        self.by_name
+            .entry(record.package.clone())
+            .or_default()
+            .push(idx);
+
+        for provided in &record.provides {
+            self.by_provides
+                .entry(provided.clone())
+                .or_default()
+                .push(idx);
+        }
+
+        self.records.push(record);
+    }
+
+    pub fn resolve(&self, seeds: &[CompactString], archs: &[CompactString]) -> Resolution {
+        let mut resolution = Resolution::default();
+
+        for arch in archs {
+            let mut state = ResolveState {
+                selected: HashSet::new(),
+                queue: VecDeque::new(),
+            };
+
+            for seed in seeds {
+                let Some(dep) = Dependency::parse(seed) else {
+                    continue;
+                };
+
+                if !self.select_group(&[dep], arch, &mut state) {
+                    resolution.unresolved.insert(seed.clone());
+                }
+            }
+
+            while let Some(idx) = state.queue.pop_front() {
+                let record = &self.records[idx];
+
+                for group in record.pre_depends.iter().chain(&record.depends) {
+                    if !self.select_group(group, arch, &mut state) {
+                        resolution.unresolved.insert(
+                            group
+                                .iter()
+                                .map(|v| v.name.as_str())
+                                .collect::<Vec<_>>()
+                                .join(" | ")
+                                .into(),
+                        );
+                    }
+                }
+            }
+
+            for idx in state.selected {
+                let record = &self.records[idx];
+
+                resolution.filenames.insert(record.filename.clone());
+                resolution.sources.insert(record.source_name().into());
+            }
+        }
+
+        resolution
+    }
+
+    fn select_group(&self, group: &[Dependency], arch: &str, state: &mut ResolveState) -> bool {
+        let already_satisfied = group.iter().any(|dep| {
+            self.candidates(dep, arch)
+                .chain(self.providers(dep, arch))
+                .any(|idx| state.selected.contains(&idx))
+        });
+
+        if already_satisfied {
+            return true;
+        }
+
+        for dep in group {
+            let candidates = self.candidates(dep, arch).collect::<Vec<_>>();
+
+            if !candidates.is_empty() {
+                for idx in candidates {
+                    state.select(idx);
+                }
+
+                return true;
+            }
+
+            let provider = self
+                .providers(dep, arch)
+                .map(|idx| &self.records[idx].package)
+                .min();
+
+            if let Some(provider) = provider {
+                let dep = Dependency {
+                    name: provider.clone(),
+                    arch: dep.arch.clone(),
+                };
+
+                for idx in self.candidates(&dep, arch).collect::<Vec<_>>() {
+                    state.select(idx);
+                }
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn candidates<'a>(
+        &'a self,
+        dep: &'a Dependency,
+        arch: &'a str,
+    ) -> impl Iterator<Item = usize> + 'a {
+        self.by_name
+            .get(&dep.name)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(move |idx| arch_matches(&self.records[*idx], dep, arch))
+    }
+
+    fn providers<'a>(
+        &'a self,
+        dep: &'a Dependency,
+        arch: &'a str,
+    ) -> impl Iterator<Item = usize> + 'a {
+        self.by_provides
+            .get(&dep.name)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(move |idx| arch_matches(&self.records[*idx], dep, arch))
+    }
+}
+
+/// Whether `record` satisfies `dep` for a package of `arch`, following the Multi-Arch rules of
+/// dpkg. A `Multi-Arch: foreign` package satisfies dependencies of any architecture that are
+/// not qualified with an explicit one, and `:any` reaches other architectures only for packages
+/// that are `Multi-Arch: allowed`.
+fn arch_matches(record: &PackageRecord, dep: &Dependency, arch: &str) -> bool {
+    let multi_arch = record.multi_arch.as_deref();
+
+    if multi_arch == Some("foreign") && matches!(dep.arch.as_deref(), None | Some("native" | "any"))
+    {
+        return true;
+    }
+
+    if dep.arch.as_deref() == Some("any") && multi_arch == Some("allowed") {
+        return true;
+    }
+
+    let record_arch = match record.architecture.as_str() {
+        "all" => arch,
+        v => v,
+    };
+
+    match dep.arch.as_deref() {
+        None | Some("native") | Some("any") => record_arch == arch,
+        Some(qualifier) => record_arch == qualifier,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metadata::{dependency_resolver::*, packages_file::PackageRecord};
+
+    fn record(paragraph: &str) -> PackageRecord {
+        PackageRecord::parse(paragraph).unwrap()
+    }
+
+    fn resolver() -> DependencyResolver {
+        let mut resolver = DependencyResolver::new();
+
+        for paragraph in [
+            "Package: app\nArchitecture: amd64\nVersion: 1.0\nPre-Depends: libc6 (>= 2.36)\nDepends: mail-transport-agent, default-jre | java-runtime, python3:any\nFilename: pool/a/app.deb",
+            "Package: libc6\nArchitecture: amd64\nVersion: 2.36\nSource: glibc (2.36-9)\nFilename: pool/g/libc6_amd64.deb",
+            "Package: libc6\nArchitecture: i386\nVersion: 2.36\nSource: glibc (2.36-9)\nFilename: pool/g/libc6_i386.deb",
+            "Package: postfix\nArchitecture: amd64\nVersion: 3.7\nProvides: mail-transport-agent\nFilename: pool/p/postfix.deb",
+            "Package: exim4\nArchitecture: amd64\nVersion: 4.96\nProvides: mail-transport-agent\nFilename: pool/e/exim4.deb",
+            "Package: openjdk-17-jre\nArchitecture: amd64\nVersion: 17\nProvides: java-runtime\nFilename: pool/o/openjdk.deb",
+            "Package: python3\nArchitecture: i386\nVersion: 3.11\nMulti-Arch: allowed\nFilename: pool/p/python3_i386.deb",
+            "Package: unrelated\nArchitecture: all\nVersion: 1\nFilename: pool/u/unrelated.deb",
+        ] {
+            resolver.add(record(paragraph));
+        }
+
+        resolver
+    }
+
+    #[test]
+    fn resolves_alternatives_virtuals_and_arch_qualifiers() {
+        let resolution = resolver().resolve(&["app".into()], &["amd64".into()]);
+
+        let mut filenames = resolution
+            .filenames
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>();
+        filenames.sort();
+
+        assert_eq!(
+            filenames,
+            vec![
+                "pool/a/app.deb",
+                "pool/e/exim4.deb",
+                "pool/g/libc6_amd64.deb",
+                "pool/o/openjdk.deb",
+                "pool/p/python3_i386.deb",
+            ]
+        );
+
+        assert!(resolution.sources.contains("glibc"));
+        assert!(resolution.unresolved.is_empty());
+    }
+
+    #[test]
+    fn unresolved_seeds_are_reported() {
+        let resolution = resolver().resolve(&["missing".into()], &["amd64".into()]);
+
+        assert!(resolution.filenames.is_empty());
+        assert!(resolution.unresolved.contains("missing"));
+    }
+
+    #[test]
+    fn other_architectures_need_multi_arch() {
+        let mut resolver = DependencyResolver::new();
+
+        for paragraph in [
+            "Package: app\nArchitecture: amd64\nVersion: 1.0\nDepends: perl:any, make, zlib1g:any\nFilename: pool/a/app.deb",
+            "Package: perl\nArchitecture: i386\nVersion: 5.36\nFilename: pool/p/perl_i386.deb",
+            "Package: make\nArchitecture: i386\nVersion: 4.3\nMulti-Arch: foreign\nFilename: pool/m/make_i386.deb",
+            "Package: zlib1g\nArchitecture: amd64\nVersion: 1.2\nMulti-Arch: same\nFilename: pool/z/zlib1g_amd64.deb",
+        ] {
+            resolver.add(record(paragraph));
+        }
+
+        let resolution = resolver.resolve(&["app".into()], &["amd64".into()]);
+
+        let mut filenames = resolution
+            .filenames
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>();
+        filenames.sort();
+
+        // perl is not Multi-Arch: allowed, so the i386 one does not satisfy perl:any
+        assert_eq!(
+            filenames,
+            vec![
+                "pool/a/app.deb",
+                "pool/m/make_i386.deb",
+                "pool/z/zlib1g_amd64.deb",
+            ]
+        );
+        assert!(resolution.unresolved.contains("perl"));
+    }
+
+    #[test]
+    fn explicit_arch_qualifiers_are_not_satisfied_by_foreign_packages() {
+        let mut resolver = DependencyResolver::new();
+
+        for paragraph in [
+            "Package: app\nArchitecture: amd64\nVersion: 1.0\nDepends: foo:i386, bar:amd64\nFilename: pool/a/app.deb",
+            "Package: foo\nArchitecture: amd64\nVersion: 1.0\nMulti-Arch: foreign\nFilename: pool/f/foo_amd64.deb",
+            "Package: bar\nArchitecture: amd64\nVersion: 1.0\nMulti-Arch: foreign\nFilename: pool/b/bar_amd64.deb",
+        ] {
+            resolver.add(record(paragraph));
+        }
+
+        let resolution = resolver.resolve(&["app".into()], &["amd64".into()]);
+
+        let mut filenames = resolution
+            .filenames
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>();
+        filenames.sort();
+
+        assert_eq!(filenames, vec!["pool/a/app.deb", "pool/b/bar_amd64.deb"]);
+        assert!(resolution.unresolved.contains("foo"));
+    }
+}
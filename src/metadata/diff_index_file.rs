@@ -16,6 +16,13 @@ use super::{
 
 pub struct DiffIndexFile {
     pub files: BTreeMap<CompactString, FileEntry>,
+    /// The checksum of the index file that the patches lead up to.
+    pub current: Option<Checksum>,
+    /// The checksums of earlier versions of the index file, oldest first, each along with the
+    /// name of the patch that applies to it.
+    pub history: Vec<(CompactString, Checksum)>,
+    /// The checksums of the uncompressed patches.
+    pub patches: BTreeMap<CompactString, Checksum>,
     file: MetadataFile,
     size: u64,
     read: Arc<AtomicU64>,
@@ -44,23 +51,43 @@ impl Iterator for DiffIndexFile {
                 path,
                 size: Some(value.size),
                 checksum: value.strongest_hash(),
+                meta: None,
             })
         })
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    Download,
+    History,
+    Patches,
+    Other,
+}
+
 impl DiffIndexFile {
     pub fn build(meta_file: MetadataFile) -> Result<Box<dyn IndexFileEntryIterator>> {
+        Ok(Box::new(Self::read(meta_file)?))
+    }
+
+    pub fn read(meta_file: MetadataFile) -> Result<Self> {
         let file = File::open(meta_file.path())?;
         let size = file.metadata()?.len();
 
         let (mut reader, counter) = create_reader(file, meta_file.path())?;
 
         let mut files = BTreeMap::new();
+        let mut current = None;
+        let mut history = Vec::new();
+        let mut patches = BTreeMap::new();
 
         let mut buf = String::with_capacity(1024 * 8);
 
-        let mut in_download_scope = false;
+        let mut scope = Scope::Other;
+
+        let parsing_error = || MirsError::ParsingDiffIndex {
+            path: meta_file.path().to_owned(),
+        };
 
         loop {
             buf.clear();
@@ -75,64 +102,93 @@ impl DiffIndexFile {
 
             match line {
                 _ if line.ends_with("Download:") => {
-                    in_download_scope = true;
+                    scope = Scope::Download;
+                }
+                "SHA256-History:" => {
+                    scope = Scope::History;
+                }
+                "SHA256-Patches:" => {
+                    scope = Scope::Patches;
+                }
+                _ if line.starts_with("SHA256-Current:") => {
+                    scope = Scope::Other;
+
+                    let hash = line["SHA256-Current:".len()..]
+                        .split_ascii_whitespace()
+                        .next()
+                        .ok_or_else(parsing_error)?;
+
+                    current = Some(Checksum::try_from(hash).map_err(|_| parsing_error())?);
                 }
-                _ if line.starts_with(' ') && in_download_scope => {
+                _ if line.starts_with(' ') && scope != Scope::Other => {
                     let mut split = line.split_ascii_whitespace();
 
                     let (Some(hash), Some(size), Some(path)) =
                         (split.next(), split.next(), split.next())
                     else {
-                        return Err(MirsError::ParsingDiffIndex {
-                            path: meta_file.path().to_owned(),
-                        });
+                        return Err(parsing_error());
                     };
 
                     let Ok(size) = size.parse() else {
-                        return Err(MirsError::ParsingDiffIndex {
-                            path: meta_file.path().to_owned(),
-                        });
+                        return Err(parsing_error());
                     };
 
-                    if !files.contains_key(path) {
-                        files.insert(
-                            path.to_compact_string(),
-                            FileEntry {
-                                size,
-                                md5: None,
-                                sha1: None,
-                                sha256: None,
-                                sha512: None,
-                            },
-                        );
-                    }
-
-                    let entry = files.get_mut(path).unwrap();
-
                     let Ok(checksum) = Checksum::try_from(hash) else {
-                        return Err(MirsError::ParsingDiffIndex {
-                            path: meta_file.path().to_owned(),
-                        });
+                        return Err(parsing_error());
                     };
 
-                    match checksum {
-                        Checksum::Md5(v) => entry.md5 = Some(v),
-                        Checksum::Sha1(v) => entry.sha1 = Some(v),
-                        Checksum::Sha256(v) => entry.sha256 = Some(v),
-                        Checksum::Sha512(v) => entry.sha512 = Some(v),
+                    match scope {
+                        Scope::Download => add_download(&mut files, path, size, checksum),
+                        Scope::History => history.push((path.to_compact_string(), checksum)),
+                        Scope::Patches => {
+                            patches.insert(path.to_compact_string(), checksum);
+                        }
+                        Scope::Other => unreachable!(),
                     }
                 }
                 _ => {
-                    in_download_scope = false;
+                    scope = Scope::Other;
                 }
             }
         }
 
-        Ok(Box::new(Self {
+        Ok(Self {
             files,
+            current,
+            history,
+            patches,
             file: meta_file,
             size,
             read: counter,
-        }))
+        })
+    }
+
+    /// The compressed patch file that is downloaded for the patch `name`.
+    pub fn download_for(&self, name: &str) -> Option<(&CompactString, &FileEntry)> {
+        self.files
+            .iter()
+            .find(|(path, _)| path.strip_prefix(name).is_some_and(|v| v.starts_with('.')))
+    }
+}
+
+fn add_download(
+    files: &mut BTreeMap<CompactString, FileEntry>,
+    path: &str,
+    size: u64,
+    checksum: Checksum,
+) {
+    let entry = files.entry(path.to_compact_string()).or_insert(FileEntry {
+        size,
+        md5: None,
+        sha1: None,
+        sha256: None,
+        sha512: None,
+    });
+
+    match checksum {
+        Checksum::Md5(v) => entry.md5 = Some(v),
+        Checksum::Sha1(v) => entry.sha1 = Some(v),
+        Checksum::Sha256(v) => entry.sha256 = Some(v),
+        Checksum::Sha512(v) => entry.sha512 = Some(v),
     }
 }
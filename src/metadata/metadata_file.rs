@@ -8,7 +8,7 @@ use super::{
     sources_file::SourcesFile, sum_file::SumFile,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MetadataFile {
     Packages(FilePath),
     Sources(FilePath),
@@ -75,6 +75,22 @@ impl MetadataFile {
         self.path().exists()
     }
 
+    /// Whether this is a compressed variant of a Packages or Sources index that is missing,
+    /// while the index and its diff index are there. Variants that compressing a patched index
+    /// does not reproduce are left out of the mirror like that.
+    pub fn is_left_out_variant(&self) -> bool {
+        if !matches!(self, MetadataFile::Packages(..) | MetadataFile::Sources(..)) {
+            return false;
+        }
+
+        let canonical_path = self.canonical_path();
+
+        *self.path() != canonical_path
+            && !self.exists()
+            && canonical_path.exists()
+            && FilePath(format_compact!("{canonical_path}.diff/Index")).exists()
+    }
+
     pub fn into_reader(self) -> Result<Box<dyn IndexFileEntryIterator>> {
         match &self {
             MetadataFile::Packages(..) => PackagesFile::build(self),
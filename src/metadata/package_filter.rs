@@ -0,0 +1,394 @@
+use ahash::{HashMap, HashSet};
+use compact_str::{CompactString, format_compact};
+use regex::Regex;
+
+use crate::{
+    config::MirrorOpts,
+    error::{MirsError, Result},
+};
+
+use super::{
+    FilePath, IndexFileEntry,
+    dependency_resolver::{DependencyResolver, Resolution},
+    metadata_file::MetadataFile,
+    packages_file::PackageRecords,
+    version::DebianVersion,
+};
+
+/// Decides which entries of the Packages and Sources indices a mirror selects, based on the
+/// package filters in its `MirrorOpts`. Entries that do not belong to a package, such as diffs
+/// and debian installer files, are always accepted.
+#[derive(Default)]
+pub struct PackageFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    sections: Vec<CompactString>,
+    exclude_sections: Vec<CompactString>,
+    priorities: Vec<CompactString>,
+    resolution: Option<Resolution>,
+    kept_versions: Option<HashSet<CompactString>>,
+}
+
+impl PackageFilter {
+    pub fn new(opts: &MirrorOpts) -> Result<Self> {
+        let include = opts
+            .include
+            .iter()
+            .map(|v| compile_pattern(v))
+            .collect::<Result<Vec<_>>>()?;
+
+        let exclude = opts
+            .exclude
+            .iter()
+            .map(|v| compile_pattern(v))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            include,
+            exclude,
+            sections: opts.sections.clone(),
+            exclude_sections: opts.exclude_sections.clone(),
+            priorities: opts.priorities.clone(),
+            resolution: None,
+            kept_versions: None,
+        })
+    }
+
+    /// Selects the newest `keep_versions` versions of each package and architecture in the
+    /// given Packages and Sources indices. Only entries belonging to those versions are
+    /// accepted afterwards.
+    pub fn select_versions(&mut self, opts: &MirrorOpts, files: &[MetadataFile]) -> Result<()> {
+        let Some(keep_versions) = opts.keep_versions else {
+            return Ok(());
+        };
+
+        type Versions = HashMap<CompactString, Vec<CompactString>>;
+
+        let mut groups: HashMap<(CompactString, CompactString), Versions> = HashMap::default();
+
+        for file in files {
+            if !matches!(file, MetadataFile::Packages(..) | MetadataFile::Sources(..)) {
+                continue;
+            }
+
+            for entry in file.clone().into_reader()? {
+                let entry = entry?;
+
+                let Some(meta) = entry.meta else {
+                    continue;
+                };
+
+                let Some(version) = meta.version else {
+                    continue;
+                };
+
+                let arch = meta
+                    .architecture
+                    .unwrap_or(CompactString::const_new("source"));
+
+                groups
+                    .entry((meta.name, arch))
+                    .or_default()
+                    .entry(version)
+                    .or_default()
+                    .push(entry.path);
+            }
+        }
+
+        let mut kept = HashSet::default();
+
+        for versions in groups.into_values() {
+            let mut versions = versions
+                .into_iter()
+                .map(|(version, paths)| (DebianVersion::from(version.as_str()), paths))
+                .collect::<Vec<_>>();
+
+            versions.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+            for (_, paths) in versions.into_iter().take(keep_versions) {
+                kept.extend(paths);
+            }
+        }
+
+        self.kept_versions = Some(kept);
+
+        Ok(())
+    }
+
+    /// Resolves the dependency closure of the seed packages of a mirror, if any, from the
+    /// Packages indices of its suite and its sibling suites, under the first of the `bases` that
+    /// has them. Only packages in the closure, and the sources they are built from, are accepted
+    /// afterwards.
+    pub fn resolve_dependencies(&mut self, opts: &MirrorOpts, bases: &[&FilePath]) -> Result<()> {
+        if opts.seeds.is_empty() {
+            return Ok(());
+        }
+
+        let mut resolver = DependencyResolver::new();
+
+        for path in find_packages_indices(opts, bases) {
+            for record in PackageRecords::build(&path)? {
+                resolver.add(record?);
+            }
+        }
+
+        let resolution = resolver.resolve(&opts.seeds, &opts.arch);
+
+        if !resolution.unresolved.is_empty() {
+            println!(
+                "{} WARNING: unable to resolve {}",
+                crate::now(),
+                resolution
+                    .unresolved
+                    .iter()
+                    .map(|v| v.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        self.resolution = Some(resolution);
+
+        Ok(())
+    }
+
+    pub fn accept(&self, entry: &IndexFileEntry) -> bool {
+        let Some(meta) = &entry.meta else {
+            return true;
+        };
+
+        if let Some(kept_versions) = &self.kept_versions
+            && meta.version.is_some()
+            && !kept_versions.contains(entry.path.as_str())
+        {
+            return false;
+        }
+
+        if let Some(resolution) = &self.resolution {
+            let selected = if is_binary_package(&entry.path) {
+                resolution.filenames.contains(entry.path.as_str())
+            } else {
+                resolution.sources.contains(meta.name.as_str())
+            };
+
+            if !selected {
+                return false;
+            }
+        }
+
+        if !self.include.is_empty() && !self.include.iter().any(|v| v.is_match(&meta.name)) {
+            return false;
+        }
+
+        if self.exclude.iter().any(|v| v.is_match(&meta.name)) {
+            return false;
+        }
+
+        // sections in non-main components are prefixed with the component, i.e. "contrib/games"
+        let section = meta
+            .section
+            .as_deref()
+            .map(|v| v.rsplit('/').next().unwrap_or(v));
+
+        if !self.sections.is_empty()
+            && !section.is_some_and(|v| self.sections.iter().any(|s| s == v))
+        {
+            return false;
+        }
+
+        if section.is_some_and(|v| self.exclude_sections.iter().any(|s| s == v)) {
+            return false;
+        }
+
+        // priorities select binary packages, and sources are often listed without one
+        if !self.priorities.is_empty() && is_binary_package(&entry.path) {
+            return meta
+                .priority
+                .as_deref()
+                .is_some_and(|v| self.priorities.iter().any(|p| p.eq_ignore_ascii_case(v)));
+        }
+
+        true
+    }
+}
+
+fn is_binary_package(path: &str) -> bool {
+    path.ends_with(".deb") || path.ends_with(".udeb") || path.ends_with(".ddeb")
+}
+
+/// Lists the Packages indices of the suite of `opts` and of its sibling suites. The siblings are
+/// searched for the components and architectures of `opts`.
+fn find_packages_indices(opts: &MirrorOpts, bases: &[&FilePath]) -> Vec<FilePath> {
+    let mut dirs = Vec::new();
+
+    for suite in std::iter::once(&opts.suite).chain(&opts.sibling_suites) {
+        if suite == "/" {
+            dirs.push(CompactString::const_new(""));
+            continue;
+        }
+
+        for component in &opts.components {
+            for arch in opts.arch.iter().map(|v| v.as_str()).chain(["all"]) {
+                dirs.push(format_compact!("dists/{suite}/{component}/binary-{arch}"));
+
+                if opts.udeb {
+                    dirs.push(format_compact!(
+                        "dists/{suite}/{component}/debian-installer/binary-{arch}"
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut indices = Vec::new();
+
+    for dir in dirs {
+        let index = bases.iter().find_map(|base| {
+            ["Packages.xz", "Packages.gz", "Packages.bz2", "Packages"]
+                .into_iter()
+                .map(|name| base.join(&dir).join(name))
+                .find(FilePath::exists)
+        });
+
+        indices.extend(index);
+    }
+
+    indices
+}
+
+// patterns have to match the whole package name, otherwise "linux-image-.*" would also select
+// packages such as "xen-linux-image-foo"
+pub fn compile_pattern(pattern: &str) -> Result<Regex> {
+    Regex::new(&format!("^(?:{pattern})$")).map_err(|e| MirsError::Config {
+        msg: format_compact!("invalid package pattern {pattern}: {e}"),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use compact_str::CompactString;
+
+    use crate::{
+        metadata::{IndexFileEntry, PackageMeta, package_filter::*},
+        testing::TestDir,
+    };
+
+    fn entry(name: &str) -> IndexFileEntry {
+        IndexFileEntry {
+            path: CompactString::const_new("pool/main/f/foo/foo_1.0_amd64.deb"),
+            size: None,
+            checksum: None,
+            meta: Some(PackageMeta {
+                name: name.into(),
+                section: Some("contrib/games".into()),
+                priority: Some("optional".into()),
+                version: Some("1.0".into()),
+                architecture: Some("amd64".into()),
+            }),
+        }
+    }
+
+    #[test]
+    fn include_and_exclude_match_whole_name() {
+        let opts = MirrorOpts {
+            include: vec!["linux-image-.*".into()],
+            exclude: vec![".*-dbg(sym)?".into()],
+            ..Default::default()
+        };
+
+        let filter = PackageFilter::new(&opts).unwrap();
+
+        assert!(filter.accept(&entry("linux-image-amd64")));
+        assert!(!filter.accept(&entry("xen-linux-image-amd64")));
+        assert!(!filter.accept(&entry("linux-image-amd64-dbg")));
+        assert!(!filter.accept(&entry("bash")));
+    }
+
+    #[test]
+    fn sections_and_priorities() {
+        let exclude_games = MirrorOpts {
+            exclude_sections: vec!["games".into()],
+            ..Default::default()
+        };
+
+        let required_only = MirrorOpts {
+            priorities: vec!["required".into(), "important".into()],
+            ..Default::default()
+        };
+
+        assert!(
+            !PackageFilter::new(&exclude_games)
+                .unwrap()
+                .accept(&entry("foo"))
+        );
+        assert!(
+            !PackageFilter::new(&required_only)
+                .unwrap()
+                .accept(&entry("foo"))
+        );
+
+        // the section of the entry is "contrib/games", which is matched without its component
+        let games_only = MirrorOpts {
+            sections: vec!["games".into()],
+            ..Default::default()
+        };
+
+        let optional = MirrorOpts {
+            priorities: vec!["important".into(), "optional".into()],
+            ..Default::default()
+        };
+
+        assert!(
+            PackageFilter::new(&games_only)
+                .unwrap()
+                .accept(&entry("foo"))
+        );
+        assert!(PackageFilter::new(&optional).unwrap().accept(&entry("foo")));
+
+        let source = IndexFileEntry {
+            path: CompactString::const_new("pool/main/f/foo/foo_1.0.dsc"),
+            meta: Some(PackageMeta {
+                name: "foo".into(),
+                section: None,
+                priority: None,
+                version: Some("1.0".into()),
+                architecture: None,
+            }),
+            ..entry("foo")
+        };
+
+        assert!(PackageFilter::new(&required_only).unwrap().accept(&source));
+    }
+
+    #[test]
+    fn dependencies_are_resolved_from_sibling_suites() {
+        let dir = TestDir::new("siblings");
+
+        dir.write(
+            "dists/trixie/main/binary-amd64/Packages",
+            "Package: libc6\nArchitecture: amd64\nVersion: 2.41\nFilename: pool/main/libc6.deb\n\n",
+        );
+        dir.write(
+            "dists/trixie-updates/main/binary-amd64/Packages",
+            "Package: curl\nArchitecture: amd64\nVersion: 8.14\nDepends: libc6\nFilename: pool/main/curl.deb\n\n",
+        );
+
+        let opts = MirrorOpts {
+            suite: "trixie-updates".into(),
+            components: vec!["main".into()],
+            arch: vec!["amd64".into()],
+            seeds: vec!["curl".into()],
+            sibling_suites: vec!["trixie".into()],
+            ..Default::default()
+        };
+
+        let mut filter = PackageFilter::new(&opts).unwrap();
+        filter.resolve_dependencies(&opts, &[&dir]).unwrap();
+
+        let resolution = filter.resolution.unwrap();
+
+        assert!(resolution.filenames.contains("pool/main/curl.deb"));
+        assert!(resolution.filenames.contains("pool/main/libc6.deb"));
+        assert!(resolution.unresolved.is_empty());
+    }
+}
@@ -4,12 +4,12 @@ use std::{
     sync::{Arc, atomic::AtomicU64},
 };
 
-use compact_str::ToCompactString;
+use compact_str::{CompactString, ToCompactString};
 
 use crate::error::{MirsError, Result};
 
 use super::{
-    IndexFileEntry, IndexFileEntryIterator,
+    FilePath, IndexFileEntry, IndexFileEntryIterator, PackageMeta,
     checksum::{Checksum, ChecksumType},
     create_reader,
     metadata_file::MetadataFile,
@@ -81,12 +81,27 @@ impl Iterator for PackagesFile {
             }
         }
 
+        let mut name = None;
+        let mut section = None;
+        let mut priority = None;
+        let mut version = None;
+        let mut architecture = None;
         let mut path = None;
         let mut size = None;
         let mut hash = None;
 
         for line in self.buf.lines() {
-            if let Some(filename) = line.strip_prefix("Filename: ") {
+            if let Some(package) = line.strip_prefix("Package: ") {
+                name = Some(package.to_compact_string())
+            } else if let Some(line_section) = line.strip_prefix("Section: ") {
+                section = Some(line_section.to_compact_string())
+            } else if let Some(line_priority) = line.strip_prefix("Priority: ") {
+                priority = Some(line_priority.to_compact_string())
+            } else if let Some(line_version) = line.strip_prefix("Version: ") {
+                version = Some(line_version.to_compact_string())
+            } else if let Some(line_arch) = line.strip_prefix("Architecture: ") {
+                architecture = Some(line_arch.to_compact_string())
+            } else if let Some(filename) = line.strip_prefix("Filename: ") {
                 path = Some(filename.to_compact_string())
             } else if let Some(line_size) = line.strip_prefix("Size: ") {
                 size = Some(
@@ -136,9 +151,200 @@ impl Iterator for PackagesFile {
                 path,
                 size: Some(size),
                 checksum,
+                meta: name.map(|name| PackageMeta {
+                    name,
+                    section,
+                    priority,
+                    version,
+                    architecture,
+                }),
             }))
         } else {
             None
         }
     }
 }
+
+/// A structured view of a paragraph in a Packages file, with the fields needed to resolve
+/// relationships between packages.
+#[derive(Debug, Clone, Default)]
+pub struct PackageRecord {
+    pub package: CompactString,
+    pub version: CompactString,
+    pub architecture: CompactString,
+    pub source: Option<CompactString>,
+    pub filename: CompactString,
+    pub depends: Vec<Vec<Dependency>>,
+    pub pre_depends: Vec<Vec<Dependency>>,
+    pub provides: Vec<CompactString>,
+    pub multi_arch: Option<CompactString>,
+}
+
+impl PackageRecord {
+    pub fn parse(paragraph: &str) -> Option<Self> {
+        let mut record = PackageRecord::default();
+
+        for line in paragraph.lines() {
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+
+            match key {
+                "Package" => record.package = value.to_compact_string(),
+                "Version" => record.version = value.to_compact_string(),
+                "Architecture" => record.architecture = value.to_compact_string(),
+                "Filename" => record.filename = value.to_compact_string(),
+                "Source" => {
+                    // the source version is appended in parentheses if it differs from the binary
+                    let name = value.split_whitespace().next().unwrap_or(value);
+                    record.source = Some(name.to_compact_string())
+                }
+                "Multi-Arch" => record.multi_arch = Some(value.to_compact_string()),
+                "Depends" => record.depends = Dependency::parse_list(value),
+                "Pre-Depends" => record.pre_depends = Dependency::parse_list(value),
+                "Provides" => {
+                    record.provides = Dependency::parse_list(value)
+                        .into_iter()
+                        .flatten()
+                        .map(|v| v.name)
+                        .collect()
+                }
+                _ => (),
+            }
+        }
+
+        if record.package.is_empty() || record.filename.is_empty() {
+            return None;
+        }
+
+        Some(record)
+    }
+
+    pub fn source_name(&self) -> &str {
+        self.source.as_deref().unwrap_or(&self.package)
+    }
+}
+
+/// A single alternative of a package relationship, i.e. `python3:any (>= 3.11)`. Version
+/// constraints are not kept, since resolution ignores them. Which versions are mirrored is up to
+/// `PackageFilter::select_versions` afterwards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: CompactString,
+    pub arch: Option<CompactString>,
+}
+
+impl Dependency {
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+
+        let end = value.find([' ', '(', '[', '<']).unwrap_or(value.len());
+        let name = &value[..end];
+
+        if name.is_empty() {
+            return None;
+        }
+
+        Some(match name.split_once(':') {
+            Some((name, arch)) => Self {
+                name: name.to_compact_string(),
+                arch: Some(arch.to_compact_string()),
+            },
+            None => Self {
+                name: name.to_compact_string(),
+                arch: None,
+            },
+        })
+    }
+
+    /// Parses a comma separated list of relationships, where each relationship can have
+    /// alternatives separated by `|`.
+    pub fn parse_list(value: &str) -> Vec<Vec<Dependency>> {
+        value
+            .split(',')
+            .map(|group| {
+                group
+                    .split('|')
+                    .filter_map(Dependency::parse)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|group| !group.is_empty())
+            .collect()
+    }
+}
+
+pub struct PackageRecords {
+    reader: Box<dyn BufRead + Send>,
+    path: FilePath,
+    buf: String,
+}
+
+impl PackageRecords {
+    pub fn build(path: &FilePath) -> Result<Self> {
+        let file = File::open(path)?;
+
+        let (reader, _) = create_reader(file, path)?;
+
+        Ok(Self {
+            reader,
+            path: path.clone(),
+            buf: String::with_capacity(1024 * 8),
+        })
+    }
+}
+
+impl Iterator for PackageRecords {
+    type Item = Result<PackageRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buf.clear();
+
+            loop {
+                let line_start = self.buf.len();
+
+                match self.reader.read_line(&mut self.buf) {
+                    Ok(0) if self.buf.is_empty() => return None,
+                    Ok(0) => break,
+                    // paragraphs are separated by an empty line, which may end with \r\n
+                    Ok(_) if self.buf[line_start..].trim().is_empty() => break,
+                    Ok(_) => (),
+                    Err(e) => {
+                        return Some(Err(MirsError::ReadingPackage {
+                            path: self.path.clone(),
+                            inner: Box::new(e.into()),
+                        }));
+                    }
+                }
+            }
+
+            if let Some(record) = PackageRecord::parse(&self.buf) {
+                return Some(Ok(record));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{metadata::packages_file::*, testing::TestDir};
+
+    #[test]
+    fn records_are_split_on_crlf_lines() {
+        let dir = TestDir::new("crlf-records");
+
+        let path = dir.write(
+            "Packages",
+            "Package: a\r\nVersion: 1\r\nArchitecture: amd64\r\nFilename: pool/a.deb\r\n\r\nPackage: b\r\nVersion: 2\r\nArchitecture: amd64\r\nFilename: pool/b.deb\r\n",
+        );
+
+        let records = PackageRecords::build(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].package, "a");
+        assert_eq!(records[1].filename, "pool/b.deb");
+    }
+}
@@ -0,0 +1,351 @@
+use compact_str::{CompactString, format_compact};
+use sha2::{Digest, Sha256};
+
+use crate::error::{MirsError, Result};
+
+use super::{checksum::Checksum, diff_index_file::DiffIndexFile};
+
+/// A command of an ed script, with 1-based line numbers.
+#[derive(Debug, PartialEq)]
+enum EdCommand<'a> {
+    Append {
+        after: usize,
+        lines: Vec<&'a [u8]>,
+    },
+    Change {
+        from: usize,
+        to: usize,
+        lines: Vec<&'a [u8]>,
+    },
+    Delete {
+        from: usize,
+        to: usize,
+    },
+}
+
+impl EdCommand<'_> {
+    /// The 0-based index of the first line the command touches, and the index after the last.
+    fn range(&self) -> (usize, usize) {
+        match self {
+            EdCommand::Append { after, .. } => (*after, *after),
+            EdCommand::Change { from, to, .. } | EdCommand::Delete { from, to } => (from - 1, *to),
+        }
+    }
+}
+
+/// The patches of PDiffs, which are ed scripts as written by `diff --ed`. Their commands come
+/// last line first, so that the line numbers of each command refer to the original file.
+#[derive(Debug)]
+pub struct EdScript<'a> {
+    commands: Vec<EdCommand<'a>>,
+}
+
+fn invalid(msg: &str) -> MirsError {
+    MirsError::Pdiff {
+        msg: CompactString::from(msg),
+    }
+}
+
+impl<'a> EdScript<'a> {
+    pub fn parse(script: &'a [u8]) -> Result<Self> {
+        let mut commands = Vec::new();
+        let mut lines = script.split_inclusive(|v| *v == b'\n');
+
+        while let Some(line) = lines.next() {
+            let line = std::str::from_utf8(line.trim_ascii_end())
+                .map_err(|_| invalid("command is not utf8"))?;
+
+            let Some(command) = line.chars().last() else {
+                return Err(invalid("empty command"));
+            };
+
+            let address = &line[..line.len() - command.len_utf8()];
+
+            let (from, to) = match address.split_once(',') {
+                Some((from, to)) => (parse_line_number(from)?, parse_line_number(to)?),
+                None => {
+                    let line_number = parse_line_number(address)?;
+                    (line_number, line_number)
+                }
+            };
+
+            if to < from {
+                return Err(invalid("backwards line range"));
+            }
+
+            let command = match command {
+                'a' => EdCommand::Append {
+                    after: from,
+                    lines: read_text(&mut lines)?,
+                },
+                'c' if from > 0 => EdCommand::Change {
+                    from,
+                    to,
+                    lines: read_text(&mut lines)?,
+                },
+                'd' if from > 0 => EdCommand::Delete { from, to },
+                _ => return Err(invalid(&format!("unsupported command {line}"))),
+            };
+
+            commands.push(command);
+        }
+
+        Ok(Self { commands })
+    }
+
+    pub fn apply(&self, original: &[u8]) -> Result<Vec<u8>> {
+        let original = original
+            .split_inclusive(|v| *v == b'\n')
+            .collect::<Vec<_>>();
+
+        let mut output = Vec::with_capacity(original.iter().map(|v| v.len()).sum());
+        let mut pos = 0;
+
+        for command in self.commands.iter().rev() {
+            let (start, end) = command.range();
+
+            if start < pos || end > original.len() {
+                return Err(invalid("commands out of order or out of range"));
+            }
+
+            original[pos..start]
+                .iter()
+                .for_each(|line| output.extend_from_slice(line));
+
+            if let EdCommand::Append { lines, .. } | EdCommand::Change { lines, .. } = command {
+                lines.iter().for_each(|line| output.extend_from_slice(line));
+            }
+
+            pos = end;
+        }
+
+        original[pos..]
+            .iter()
+            .for_each(|line| output.extend_from_slice(line));
+
+        Ok(output)
+    }
+}
+
+fn parse_line_number(value: &str) -> Result<usize> {
+    value.parse().map_err(|_| invalid("invalid line number"))
+}
+
+fn read_text<'a>(lines: &mut impl Iterator<Item = &'a [u8]>) -> Result<Vec<&'a [u8]>> {
+    let mut text = Vec::new();
+
+    for line in lines {
+        if line.trim_ascii_end() == b"." {
+            return Ok(text);
+        }
+
+        text.push(line);
+    }
+
+    Err(invalid("unterminated text"))
+}
+
+/// Patches `content` up to the current version in the diff index, with the patches that
+/// `read_patch` returns by name. Each patch is looked up by the checksum of the content it
+/// applies to, which works both for patches that lead to the next version, and for merged
+/// patches that lead straight to the current one.
+pub fn patch_to_current<F>(
+    index: &DiffIndexFile,
+    mut content: Vec<u8>,
+    mut read_patch: F,
+) -> Result<Vec<u8>>
+where
+    F: FnMut(&str) -> Result<Vec<u8>>,
+{
+    let Some(current) = &index.current else {
+        return Err(invalid("the diff index has no current checksum"));
+    };
+
+    let mut last_applied = None;
+
+    loop {
+        let checksum = Checksum::Sha256(Sha256::digest(&content).into());
+
+        if checksum == *current {
+            return Ok(content);
+        }
+
+        let Some(next) = index
+            .history
+            .iter()
+            .enumerate()
+            .skip(last_applied.map_or(0, |v| v + 1))
+            .find(|(_, (_, v))| *v == checksum)
+            .map(|(i, _)| i)
+        else {
+            return Err(MirsError::Pdiff {
+                msg: format_compact!("no patch applies to a file with checksum {checksum}"),
+            });
+        };
+
+        let name = &index.history[next].0;
+        let patch = read_patch(name)?;
+
+        if let Some(expected) = index.patches.get(name)
+            && Checksum::Sha256(Sha256::digest(&patch).into()) != *expected
+        {
+            return Err(MirsError::Pdiff {
+                msg: format_compact!("patch {name} does not match its checksum"),
+            });
+        }
+
+        content = EdScript::parse(&patch)?.apply(&content)?;
+
+        last_applied = Some(next);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        metadata::{metadata_file::MetadataFile, pdiff::*},
+        testing::TestDir,
+    };
+
+    const V1: &[u8] = b"a\nb\nc\n";
+    const V2: &[u8] = b"a\nB\nc\n";
+    const V3: &[u8] = b"a\nB\nc\nd\n";
+
+    fn sha256(content: &[u8]) -> String {
+        hex::encode(Sha256::digest(content))
+    }
+
+    /// A diff index that leads up to `V3`, with `history` of the patch names and the versions
+    /// they apply to, and the content of each patch.
+    fn diff_index(
+        dir: &TestDir,
+        history: &[(&str, &[u8])],
+        patches: &[(&str, &[u8])],
+    ) -> DiffIndexFile {
+        let mut index = format!(
+            "SHA256-Current: {} {}\nSHA256-History:\n",
+            sha256(V3),
+            V3.len()
+        );
+
+        for (name, version) in history {
+            index.push_str(&format!(" {} {} {name}\n", sha256(version), version.len()));
+        }
+
+        index.push_str("SHA256-Patches:\n");
+
+        for (name, patch) in patches {
+            index.push_str(&format!(" {} {} {name}\n", sha256(patch), patch.len()));
+        }
+
+        let path = dir.write("Packages.diff/Index", index);
+
+        DiffIndexFile::read(MetadataFile::DiffIndex(path)).unwrap()
+    }
+
+    /// Patches `V1` with `patches`, and returns the result along with the names of the patches
+    /// that were read.
+    fn patch(index: &DiffIndexFile, patches: &[(&str, &[u8])]) -> (Result<Vec<u8>>, Vec<String>) {
+        let patches = patches.iter().copied().collect::<BTreeMap<_, _>>();
+        let mut read = Vec::new();
+
+        let result = patch_to_current(index, V1.to_vec(), |name| {
+            read.push(name.to_string());
+            Ok(patches[name].to_vec())
+        });
+
+        (result, read)
+    }
+
+    #[test]
+    fn applies_ed_scripts() {
+        let original = b"one\ntwo\nthree\nfour\nfive\n";
+
+        let script = b"5a\nsix\n.\n3,4c\nTHREE\n.\n1d\n";
+
+        let patched = EdScript::parse(script).unwrap().apply(original).unwrap();
+
+        assert_eq!(patched, b"two\nTHREE\nfive\nsix\n");
+    }
+
+    #[test]
+    fn rejects_out_of_order_commands() {
+        let script = b"1d\n3d\n";
+
+        assert!(
+            EdScript::parse(script)
+                .unwrap()
+                .apply(b"one\ntwo\nthree\n")
+                .is_err()
+        );
+        assert!(EdScript::parse(b"1s/.//\n").is_err());
+        assert!(EdScript::parse(b"1a\nunterminated\n").is_err());
+    }
+
+    #[test]
+    fn patches_through_the_history() {
+        let dir = TestDir::new("pdiff");
+
+        let patches: &[(&str, &[u8])] = &[("p1", b"2c\nB\n.\n"), ("p2", b"3a\nd\n.\n")];
+        let index = diff_index(&dir, &[("p1", V1), ("p2", V2)], patches);
+
+        let (result, read) = patch(&index, patches);
+
+        assert_eq!(result.unwrap(), V3);
+        assert_eq!(read, vec!["p1", "p2"]);
+    }
+
+    #[test]
+    fn merged_patches_lead_straight_to_current() {
+        let dir = TestDir::new("pdiff");
+
+        let patches: &[(&str, &[u8])] = &[("m1", b"3a\nd\n.\n2c\nB\n.\n"), ("m2", b"3a\nd\n.\n")];
+        let index = diff_index(&dir, &[("m1", V1), ("m2", V2)], patches);
+
+        let (result, read) = patch(&index, patches);
+
+        assert_eq!(result.unwrap(), V3);
+        assert_eq!(read, vec!["m1"]);
+    }
+
+    #[test]
+    fn patches_must_match_their_checksum() {
+        let dir = TestDir::new("pdiff");
+
+        let index = diff_index(
+            &dir,
+            &[("p1", V1), ("p2", V2)],
+            &[("p1", b"2c\nB\n.\n"), ("p2", b"3a\nd\n.\n")],
+        );
+
+        let (result, _) = patch(&index, &[("p1", b"2c\nX\n.\n"), ("p2", b"3a\nd\n.\n")]);
+
+        assert!(
+            matches!(result, Err(MirsError::Pdiff { msg }) if msg == "patch p1 does not match its checksum")
+        );
+    }
+
+    #[test]
+    fn broken_chain_is_an_error() {
+        let dir = TestDir::new("pdiff");
+
+        // the version p1 leads to is not in the history
+        let patches: &[(&str, &[u8])] = &[("p1", b"2c\nX\n.\n"), ("p2", b"3a\nd\n.\n")];
+        let index = diff_index(&dir, &[("p1", V1), ("p2", V2)], patches);
+
+        let (result, read) = patch(&index, patches);
+
+        assert!(result.is_err());
+        assert_eq!(read, vec!["p1"]);
+
+        // and nothing applies to a version that is not in the history at all
+        let index = diff_index(&dir, &[("p2", V2)], patches);
+
+        let (result, read) = patch(&index, patches);
+
+        assert!(result.is_err());
+        assert!(read.is_empty());
+    }
+}
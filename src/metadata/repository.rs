@@ -1,4 +1,4 @@
-use std::{str::FromStr, sync::Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use compact_str::{CompactString, ToCompactString, format_compact};
 use pgp::composed::{CleartextSignedMessage, DetachedSignature, SignedPublicKey};
@@ -6,13 +6,37 @@ use reqwest::Url;
 
 use crate::{
     CliOpts,
+    bandwidth::BandwidthLimiter,
     config::MirrorOpts,
     downloader::Download,
     error::{MirsError, Result},
     metadata::{FilePath, IndexFileEntry, checksum::Checksum, release::FileEntry},
-    pgp::{KeyStore, read_public_key},
+    pgp::{KeyStore, load_public_key},
 };
 
+/// Plain paths are treated as `file://` urls.
+fn to_root_url(url: &CompactString) -> CompactString {
+    if url.starts_with('/') {
+        format_compact!("file://{url}")
+    } else {
+        url.clone()
+    }
+}
+
+fn mirror_urls(mirror_opts: &MirrorOpts) -> Result<Vec<CompactString>> {
+    std::iter::once(&mirror_opts.url)
+        .chain(&mirror_opts.fallback_urls)
+        .map(|url| {
+            let root_url = to_root_url(url);
+
+            match Url::parse(&root_url) {
+                Ok(_) => Ok(root_url),
+                Err(_) => Err(MirsError::UrlParsing { url: root_url }),
+            }
+        })
+        .collect()
+}
+
 pub const INRELEASE_FILE_NAME: &str = "InRelease";
 pub const RELEASE_FILE_NAME: &str = "Release";
 pub const RELEASE_GPG_FILE_NAME: &str = "Release.gpg";
@@ -24,14 +48,17 @@ pub struct Repository {
     pub dist_url: CompactString,
     pub tmp_dir: FilePath,
     pub pgp_pub_key: Option<SignedPublicKey>,
+    /// The limiter of the repository, which all of its suites share. Set by the mirror command.
+    pub bandwidth_limiter: Option<BandwidthLimiter>,
+    /// The root urls of all upstream mirrors of this repository, the primary one first. Paths
+    /// are always derived from the primary url, regardless of which mirror a file is fetched from.
+    pub mirror_urls: Vec<CompactString>,
+    active_mirror: AtomicUsize,
 }
 
 impl Repository {
     pub fn build(mirror_opts: &MirrorOpts, cli_opts: &CliOpts) -> Result<Self> {
-        let root_url = match mirror_opts.url.as_str().strip_prefix('/') {
-            Some(url) => url.to_compact_string(),
-            None => mirror_opts.url.clone(),
-        };
+        let root_url = to_root_url(&mirror_opts.url);
 
         let dist_url = format_compact!("{root_url}/{}", mirror_opts.dist_part());
 
@@ -40,8 +67,7 @@ impl Repository {
         })?;
 
         let pgp_pub_key = if let Some(pgp_signing_key) = &mirror_opts.pgp_pub_key {
-            let file = FilePath::from_str(pgp_signing_key.as_ref())?;
-            Some(read_public_key(&file)?)
+            Some(load_public_key(pgp_signing_key)?)
         } else {
             None
         };
@@ -53,48 +79,27 @@ impl Repository {
         };
 
         Ok(Self {
+            mirror_urls: mirror_urls(mirror_opts)?,
             root_url,
             root_dir,
             dist_url,
             tmp_dir: FilePath::from(""),
             pgp_pub_key,
+            bandwidth_limiter: None,
+            active_mirror: AtomicUsize::new(0),
         })
     }
 
-    pub fn build_with_tmp(mirror_opts: &MirrorOpts, cli_opts: &CliOpts) -> Result<Arc<Self>> {
-        let root_url = match mirror_opts.url.as_str().strip_prefix('/') {
-            Some(url) => url.to_compact_string(),
-            None => mirror_opts.url.clone(),
-        };
+    pub fn build_with_tmp(mirror_opts: &MirrorOpts, cli_opts: &CliOpts) -> Result<Self> {
+        let mut repo = Self::build(mirror_opts, cli_opts)?;
 
-        let dist_url = format_compact!("{root_url}/{}", mirror_opts.dist_part());
-
-        let parsed_url = Url::parse(&root_url).map_err(|_| MirsError::UrlParsing {
-            url: root_url.clone(),
+        let parsed_url = Url::parse(&repo.root_url).map_err(|_| MirsError::UrlParsing {
+            url: repo.root_url.clone(),
         })?;
 
-        let pgp_pub_key = if let Some(pgp_signing_key) = &mirror_opts.pgp_pub_key {
-            let file = FilePath::from_str(pgp_signing_key.as_ref())?;
-            Some(read_public_key(&file)?)
-        } else {
-            None
-        };
-
-        let root_dir = if let Some(short_name) = &mirror_opts.short_name {
-            cli_opts.output.join(short_name)
-        } else {
-            local_dir_from_archive_url(&parsed_url, &cli_opts.output)?
-        };
-
-        let tmp_dir = create_tmp_dir(&parsed_url, &mirror_opts.suite, &cli_opts.output)?;
+        repo.tmp_dir = create_tmp_dir(&parsed_url, &mirror_opts.suite, &cli_opts.staging_dir())?;
 
-        Ok(Arc::new(Self {
-            root_url,
-            root_dir,
-            dist_url,
-            tmp_dir,
-            pgp_pub_key,
-        }))
+        Ok(repo)
     }
 
     pub fn release_urls(&self) -> [CompactString; 3] {
@@ -105,11 +110,41 @@ impl Repository {
         ]
     }
 
+    pub fn set_active_mirror(&self, index: usize) {
+        self.active_mirror.store(index, Ordering::SeqCst);
+    }
+
+    /// Rebases a url in the primary root onto the root of the mirror at `index`.
+    pub fn to_mirror_url(&self, url: &str, index: usize) -> CompactString {
+        match url.strip_prefix(self.root_url.as_str()) {
+            Some(rel) if index > 0 => format_compact!("{}{rel}", self.mirror_urls[index]),
+            _ => url.to_compact_string(),
+        }
+    }
+
+    /// Returns the url to fetch from the active mirror, and the urls of the remaining mirrors
+    /// to fall back to, in order.
+    pub fn to_mirror_urls(&self, url: CompactString) -> (CompactString, Vec<CompactString>) {
+        if self.mirror_urls.len() < 2 {
+            return (url, Vec::new());
+        }
+
+        let active = self.active_mirror.load(Ordering::SeqCst);
+
+        let mut urls = (0..self.mirror_urls.len())
+            .map(|i| (active + i) % self.mirror_urls.len())
+            .map(|i| self.to_mirror_url(&url, i));
+
+        let primary = urls.next().expect("there is always at least one mirror");
+
+        (primary, urls.collect())
+    }
+
     pub fn has_specified_pgp_key(&self) -> bool {
         self.pgp_pub_key.is_some()
     }
 
-    fn to_path_in_local_dir(&self, base: &FilePath, url: &str) -> FilePath {
+    pub fn to_path_in_local_dir(&self, base: &FilePath, url: &str) -> FilePath {
         let relative_path = url
             .strip_prefix(self.root_url.as_str())
             .expect("implementation error; download url should be in archive root");
@@ -188,13 +223,18 @@ impl Repository {
         let url = self.to_url_in_root(&package.path);
         let primary_target_path = self.to_path_in_root(&url);
 
+        let (url, fallback_urls) = self.to_mirror_urls(url);
+
         Box::new(Download {
             url,
+            fallback_urls,
             size: package.size,
             checksum: package.checksum,
             primary_target_path,
             symlink_paths: Vec::new(),
             always_download: false,
+            optional: false,
+            bandwidth_limiter: self.bandwidth_limiter.clone(),
         })
     }
 
@@ -204,13 +244,18 @@ impl Repository {
         url: CompactString,
         checksum: Option<Checksum>,
     ) -> Box<Download> {
+        let (url, fallback_urls) = self.to_mirror_urls(url);
+
         Box::new(Download {
             url,
+            fallback_urls,
             size: None,
             checksum,
             primary_target_path: target_path,
             symlink_paths: Vec::new(),
             always_download: true,
+            optional: false,
+            bandwidth_limiter: self.bandwidth_limiter.clone(),
         })
     }
 
@@ -226,13 +271,18 @@ impl Repository {
         let (checksum, primary_target_path, symlink_paths) =
             file_entry.into_paths(&file_path, by_hash)?;
 
+        let (url, fallback_urls) = self.to_mirror_urls(url);
+
         Ok(Box::new(Download {
             url,
+            fallback_urls,
             size: Some(size),
             checksum,
             primary_target_path,
             symlink_paths,
             always_download: false,
+            optional: true,
+            bandwidth_limiter: self.bandwidth_limiter.clone(),
         }))
     }
 }
@@ -303,12 +353,8 @@ fn sanitize_name(part: &str) -> CompactString {
     sanitized
 }
 
-fn create_tmp_dir(url: &Url, suite: &str, base_dir: &FilePath) -> Result<FilePath> {
-    let Some(host) = url.host() else {
-        return Err(MirsError::UrlParsing {
-            url: url.to_compact_string(),
-        });
-    };
+fn create_tmp_dir(url: &Url, suite: &str, staging_dir: &FilePath) -> Result<FilePath> {
+    let host = url_host(url)?;
 
     let path = url.path();
 
@@ -320,7 +366,7 @@ fn create_tmp_dir(url: &Url, suite: &str, base_dir: &FilePath) -> Result<FilePat
 
     let suite_part = sanitize_name(suite);
 
-    let tmp_dir = base_dir.join(format_compact!(".tmp/{host}{path_part}_{suite_part}"));
+    let tmp_dir = staging_dir.join(format_compact!("{host}{path_part}_{suite_part}"));
 
     match std::fs::metadata(&tmp_dir) {
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
@@ -339,14 +385,22 @@ fn create_tmp_dir(url: &Url, suite: &str, base_dir: &FilePath) -> Result<FilePat
     }
 }
 
-fn local_dir_from_archive_url(url: &Url, dir: &FilePath) -> Result<FilePath> {
-    let Some(host) = url.host() else {
-        return Err(MirsError::UrlParsing {
+/// The host part of the local folder for a url. Local sources do not have a host, and are put
+/// under `localhost` instead.
+fn url_host(url: &Url) -> Result<CompactString> {
+    match url.host() {
+        Some(host) => Ok(host.to_compact_string()),
+        None if url.scheme() == "file" => Ok(CompactString::const_new("localhost")),
+        None => Err(MirsError::UrlParsing {
             url: url.to_compact_string(),
-        });
-    };
+        }),
+    }
+}
+
+fn local_dir_from_archive_url(url: &Url, dir: &FilePath) -> Result<FilePath> {
+    let host = url_host(url)?;
 
-    let mut base_dir = dir.join(host.to_compact_string());
+    let mut base_dir = dir.join(host);
 
     if let Some(path) = url.path().strip_prefix('/') {
         base_dir = base_dir.join(path);
@@ -10,7 +10,7 @@ use compact_str::{CompactString, ToCompactString, format_compact};
 use crate::error::{MirsError, Result};
 
 use super::{
-    IndexFileEntry, IndexFileEntryIterator, checksum::Checksum, create_reader,
+    IndexFileEntry, IndexFileEntryIterator, PackageMeta, checksum::Checksum, create_reader,
     metadata_file::MetadataFile,
 };
 
@@ -24,6 +24,7 @@ pub struct SourcesFile {
     file: MetadataFile,
     buf: String,
     files_buf: BTreeMap<CompactString, SourceEntry>,
+    meta: Option<PackageMeta>,
     size: u64,
     read: Arc<AtomicU64>,
 }
@@ -40,6 +41,7 @@ impl SourcesFile {
             file: meta_file,
             buf: String::with_capacity(1024 * 8),
             files_buf: BTreeMap::new(),
+            meta: None,
             size,
             read: counter,
         }))
@@ -66,6 +68,10 @@ impl Iterator for SourcesFile {
     fn next(&mut self) -> Option<Self::Item> {
         if self.files_buf.is_empty() {
             let mut maybe_dir = None;
+            let mut maybe_name = None;
+            let mut section = None;
+            let mut priority = None;
+            let mut version = None;
 
             loop {
                 match self.reader.read_line(&mut self.buf) {
@@ -86,6 +92,14 @@ impl Iterator for SourcesFile {
             while let Some(line) = line_iter.next() {
                 if let Some(d) = line.strip_prefix("Directory: ") {
                     maybe_dir = Some(d)
+                } else if let Some(name) = line.strip_prefix("Package: ") {
+                    maybe_name = Some(name.to_compact_string())
+                } else if let Some(v) = line.strip_prefix("Section: ") {
+                    section = Some(v.to_compact_string())
+                } else if let Some(v) = line.strip_prefix("Priority: ") {
+                    priority = Some(v.to_compact_string())
+                } else if let Some(v) = line.strip_prefix("Version: ") {
+                    version = Some(v.to_compact_string())
                 } else if matches!(
                     line,
                     "Files:" | "Checksums-Sha1:" | "Checksums-Sha256:" | "Checksums-Sha512:"
@@ -152,6 +166,13 @@ impl Iterator for SourcesFile {
             }
 
             self.files_buf = new_map;
+            self.meta = maybe_name.map(|name| PackageMeta {
+                name,
+                section,
+                priority,
+                version,
+                architecture: None,
+            });
 
             self.buf.clear();
         }
@@ -161,6 +182,7 @@ impl Iterator for SourcesFile {
                 path,
                 size: Some(entry.size),
                 checksum: Some(entry.checksum),
+                meta: self.meta.clone(),
             }));
         }
 
@@ -89,6 +89,7 @@ impl Iterator for SumFile {
             path,
             size: None,
             checksum: Some(checksum),
+            meta: None,
         }))
     }
 }
@@ -0,0 +1,176 @@
+use std::{cmp::Ordering, fmt::Display};
+
+use compact_str::{CompactString, ToCompactString};
+
+/// A Debian package version, `[epoch:]upstream_version[-debian_revision]`, ordered the same way
+/// as dpkg orders them.
+#[derive(Debug, Clone, Eq)]
+pub struct DebianVersion {
+    epoch: u64,
+    upstream: CompactString,
+    revision: CompactString,
+}
+
+impl From<&str> for DebianVersion {
+    fn from(value: &str) -> Self {
+        let value = value.trim();
+
+        let (epoch, rest) = match value.split_once(':') {
+            Some((epoch, rest)) if epoch.bytes().all(|v| v.is_ascii_digit()) => {
+                (epoch.parse().unwrap_or(0), rest)
+            }
+            _ => (0, value),
+        };
+
+        let (upstream, revision) = match rest.rsplit_once('-') {
+            Some((upstream, revision)) => (upstream, revision),
+            None => (rest, ""),
+        };
+
+        Self {
+            epoch,
+            upstream: upstream.to_compact_string(),
+            revision: revision.to_compact_string(),
+        }
+    }
+}
+
+impl Display for DebianVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.epoch > 0 {
+            f.write_fmt(format_args!("{}:", self.epoch))?;
+        }
+
+        f.write_str(&self.upstream)?;
+
+        if !self.revision.is_empty() {
+            f.write_fmt(format_args!("-{}", self.revision))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PartialEq for DebianVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for DebianVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DebianVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| compare_part(&self.upstream, &other.upstream))
+            .then_with(|| compare_part(&self.revision, &other.revision))
+    }
+}
+
+// the weight of a non-digit character: a tilde sorts before anything, even the end of the
+// string, and letters sort before all other characters
+fn order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+
+    let mut i = 0;
+    let mut j = 0;
+
+    let is_digit = |v: &[u8], pos: usize| v.get(pos).is_some_and(u8::is_ascii_digit);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !is_digit(a, i)) || (j < b.len() && !is_digit(b, j)) {
+            let ac = order(a.get(i).copied());
+            let bc = order(b.get(j).copied());
+
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+
+        while is_digit(a, i) && is_digit(b, j) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+
+            i += 1;
+            j += 1;
+        }
+
+        if is_digit(a, i) {
+            return Ordering::Greater;
+        }
+
+        if is_digit(b, j) {
+            return Ordering::Less;
+        }
+
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+
+    use crate::metadata::version::DebianVersion;
+
+    fn cmp(a: &str, b: &str) -> Ordering {
+        DebianVersion::from(a).cmp(&DebianVersion::from(b))
+    }
+
+    #[test]
+    fn tilde_sorts_before_release() {
+        assert_eq!(cmp("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(cmp("1.0~rc1", "1.0~rc2"), Ordering::Less);
+        assert_eq!(cmp("1.0~~", "1.0~"), Ordering::Less);
+    }
+
+    #[test]
+    fn epoch_wins_over_upstream() {
+        assert_eq!(cmp("1:0.1", "2.0"), Ordering::Greater);
+        assert_eq!(cmp("0:2.0", "2.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn numeric_and_revision_ordering() {
+        assert_eq!(cmp("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(cmp("1.0", "1.00"), Ordering::Equal);
+        assert_eq!(cmp("1.0a", "1.0"), Ordering::Greater);
+        assert_eq!(cmp("1.0+b1", "1.0a"), Ordering::Greater);
+        assert_eq!(cmp("2.36-9+deb12u4", "2.36-9+deb12u10"), Ordering::Less);
+        assert_eq!(cmp("1.2-3-1", "1.2-3"), Ordering::Greater);
+    }
+}
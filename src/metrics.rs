@@ -0,0 +1,299 @@
+use std::{collections::HashMap, fmt::Write as _, io::Write as _, path::Path};
+
+use crate::{cmd::Outcome, error::Result, metadata::FilePath, report::RepositoryReport};
+
+const LAST_SUCCESS: &str = "aptmirs_repository_last_success_timestamp_seconds";
+
+/// Writes the metrics of a mirror run as a node_exporter textfile. The file is written next to
+/// its destination and renamed into place, so the collector never reads a partial file.
+///
+/// The last success of a repository that did not succeed in this run is carried over from the
+/// previous file.
+pub fn write_textfile(path: &FilePath, repositories: &[RepositoryReport]) -> Result<()> {
+    let previous = match std::fs::read_to_string(path) {
+        Ok(content) => parse_last_success(&content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let content = render(
+        repositories,
+        &previous,
+        chrono::Utc::now().timestamp() as u64,
+    );
+
+    let tmp_path = format!("{path}.tmp");
+
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, Path::new(path.as_str()))?;
+
+    Ok(())
+}
+
+fn render(repositories: &[RepositoryReport], previous: &HashMap<String, u64>, now: u64) -> String {
+    let mut output = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        if samples.is_empty() {
+            return;
+        }
+
+        _ = writeln!(output, "# HELP {name} {help}");
+        _ = writeln!(output, "# TYPE {name} {kind}");
+
+        for (labels, value) in samples {
+            if labels.is_empty() {
+                _ = writeln!(output, "{name} {value}");
+            } else {
+                _ = writeln!(output, "{name}{{{labels}}} {value}");
+            }
+        }
+    };
+
+    let per_repository = |value: &dyn Fn(&RepositoryReport) -> Option<String>| {
+        repositories
+            .iter()
+            .filter_map(|repo| Some((repository_labels(repo), value(repo)?)))
+            .collect::<Vec<_>>()
+    };
+
+    metric(
+        "aptmirs_repository_success",
+        "gauge",
+        "Whether the last run of the repository was ok (1), or incomplete or failed (0).",
+        per_repository(&|repo| Some(u8::from(repo.outcome == Outcome::Ok).to_string())),
+    );
+
+    metric(
+        LAST_SUCCESS,
+        "gauge",
+        "When the repository was last mirrored without failures.",
+        per_repository(&|repo| {
+            if repo.outcome == Outcome::Ok {
+                Some(now.to_string())
+            } else {
+                previous.get(&repository_labels(repo)).map(u64::to_string)
+            }
+        }),
+    );
+
+    metric(
+        "aptmirs_release_timestamp_seconds",
+        "gauge",
+        "The Date field of the published Release.",
+        per_repository(&|repo| repo.release_time.map(|v| v.to_string())),
+    );
+
+    metric(
+        "aptmirs_release_age_seconds",
+        "gauge",
+        "The age of the published Release at the end of the run.",
+        per_repository(&|repo| repo.release_time.map(|v| now.saturating_sub(v).to_string())),
+    );
+
+    metric(
+        "aptmirs_downloaded_bytes",
+        "gauge",
+        "The number of bytes downloaded in the last run.",
+        per_repository(&|repo| Some(repo.bytes.unwrap_or(0).to_string())),
+    );
+
+    metric(
+        "aptmirs_downloaded_packages",
+        "gauge",
+        "The number of packages and source files downloaded in the last run.",
+        per_repository(&|repo| Some(repo.packages.unwrap_or(0).to_string())),
+    );
+
+    metric(
+        "aptmirs_failed_files",
+        "gauge",
+        "The number of files that failed their checksum in the last run.",
+        per_repository(&|repo| Some(repo.failed.to_string())),
+    );
+
+    metric(
+        "aptmirs_skipped_files",
+        "gauge",
+        "The number of files that could not be downloaded in the last run.",
+        per_repository(&|repo| Some(repo.skipped.to_string())),
+    );
+
+    metric(
+        "aptmirs_step_duration_seconds",
+        "gauge",
+        "How long each step of the last run took.",
+        repositories
+            .iter()
+            .flat_map(|repo| {
+                repo.steps.iter().map(|step| {
+                    (
+                        format!("{},step=\"{}\"", repository_labels(repo), escape(step.name)),
+                        step.duration_secs.to_string(),
+                    )
+                })
+            })
+            .collect(),
+    );
+
+    metric(
+        "aptmirs_last_run_timestamp_seconds",
+        "gauge",
+        "When aptmirs last finished mirroring.",
+        vec![(String::new(), now.to_string())],
+    );
+
+    output
+}
+
+/// The labels that identify a repository. The same upstream can be mirrored into several
+/// folders of the output, so the folder is a label as well.
+fn repository_labels(repo: &RepositoryReport) -> String {
+    format!(
+        "repository=\"{}\",root_dir=\"{}\"",
+        escape(&repo.repository),
+        escape(repo.root_dir.as_deref().unwrap_or_default())
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Reads the last success of every repository from a previously written textfile, by the
+/// labels of the repository.
+fn parse_last_success(content: &str) -> HashMap<String, u64> {
+    let prefix = format!("{LAST_SUCCESS}{{");
+
+    content
+        .lines()
+        .filter_map(|line| {
+            let (labels, value) = line.strip_prefix(&prefix)?.rsplit_once("} ")?;
+            Some((labels.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{metrics::*, progress::Progress, report::StepReport};
+
+    fn repository(name: &str, root_dir: &str, outcome: Outcome) -> RepositoryReport {
+        RepositoryReport {
+            repository: String::from(name),
+            root_dir: Some(root_dir.into()),
+            outcome,
+            status: String::new(),
+            bytes: Some(2048),
+            packages: Some(3),
+            failed: 0,
+            skipped: 0,
+            release_date: None,
+            release_time: Some(1_000),
+            steps: vec![StepReport::new(
+                "Downloading packages",
+                Duration::from_millis(1500),
+                &Progress::new(),
+            )],
+        }
+    }
+
+    #[test]
+    fn last_success_is_carried_over_for_failed_repositories() {
+        let first = render(
+            &[
+                repository(
+                    "deb http://a.example/debian \"trixie\" main",
+                    "/mirror/a",
+                    Outcome::Ok,
+                ),
+                repository(
+                    "deb http://b.example/debian trixie main",
+                    "/mirror/b",
+                    Outcome::Ok,
+                ),
+            ],
+            &HashMap::new(),
+            5_000,
+        );
+
+        let previous = parse_last_success(&first);
+        assert_eq!(
+            previous.get(
+                "repository=\"deb http://a.example/debian \\\"trixie\\\" main\",root_dir=\"/mirror/a\""
+            ),
+            Some(&5_000)
+        );
+
+        let second = render(
+            &[
+                repository(
+                    "deb http://a.example/debian \"trixie\" main",
+                    "/mirror/a",
+                    Outcome::Ok,
+                ),
+                repository(
+                    "deb http://b.example/debian trixie main",
+                    "/mirror/b",
+                    Outcome::Failed,
+                ),
+            ],
+            &previous,
+            6_000,
+        );
+
+        assert!(second.contains(
+            "aptmirs_repository_last_success_timestamp_seconds{repository=\"deb http://a.example/debian \\\"trixie\\\" main\",root_dir=\"/mirror/a\"} 6000"
+        ));
+        assert!(second.contains(
+            "aptmirs_repository_last_success_timestamp_seconds{repository=\"deb http://b.example/debian trixie main\",root_dir=\"/mirror/b\"} 5000"
+        ));
+        assert!(second.contains(
+            "aptmirs_release_age_seconds{repository=\"deb http://b.example/debian trixie main\",root_dir=\"/mirror/b\"} 5000"
+        ));
+        assert!(second.contains(
+            "aptmirs_step_duration_seconds{repository=\"deb http://b.example/debian trixie main\",root_dir=\"/mirror/b\",step=\"Downloading packages\"} 1.5"
+        ));
+    }
+
+    #[test]
+    fn same_upstream_in_different_folders_are_separate_series() {
+        let output = render(
+            &[
+                repository(
+                    "deb http://a.example/debian trixie main",
+                    "/mirror/a",
+                    Outcome::Ok,
+                ),
+                repository(
+                    "deb http://a.example/debian trixie main",
+                    "/mirror/b",
+                    Outcome::Failed,
+                ),
+            ],
+            &HashMap::new(),
+            5_000,
+        );
+
+        let series = output
+            .lines()
+            .filter(|line| line.starts_with("aptmirs_repository_success{"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            series,
+            vec![
+                "aptmirs_repository_success{repository=\"deb http://a.example/debian trixie main\",root_dir=\"/mirror/a\"} 1",
+                "aptmirs_repository_success{repository=\"deb http://a.example/debian trixie main\",root_dir=\"/mirror/b\"} 0",
+            ]
+        );
+    }
+}
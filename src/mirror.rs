@@ -1,29 +1,44 @@
-use std::{fmt::Display, path::Path, sync::Arc};
+use std::{fmt::Display, sync::Arc};
 
+use ahash::HashMap;
 use async_trait::async_trait;
+use compact_str::{CompactString, ToCompactString, format_compact};
 use debian_installer::DownloadDebianInstaller;
 use diffs::DownloadFromDiffs;
+use estimate::{Estimate, EstimatePackageDownloads};
 use indicatif::HumanBytes;
 use metadata::DownloadMetadata;
 use packages::DownloadFromPackageIndices;
 use release::DownloadRelease;
-use tokio::{sync::Mutex, task::spawn_blocking};
+use tokio::sync::Mutex;
 
 use crate::error::Result;
 use crate::{
     CliOpts,
-    cmd::{CmdResult, CmdState},
+    bandwidth::BandwidthLimiter,
+    cmd::{CmdResult, CmdState, Outcome, RepositoryRun, ResultSummary},
     config::MirrorOpts,
     context::Context,
-    downloader::Downloader,
+    downloader::{Downloader, remove_orphaned_partials},
     error::MirsError,
-    metadata::{FilePath, metadata_file::MetadataFile, release::Release, repository::Repository},
+    metadata::{
+        FilePath,
+        metadata_file::MetadataFile,
+        release::Release,
+        repository::{Repository, get_rooted_release_files, pick_release},
+    },
     pgp::PgpKeyStore,
+    progress::Progress,
+    publish::{Publisher, StagedSuite, publish},
+    quota::SpaceReservations,
+    snapshot::create_snapshot,
     step::Step,
+    storage::{LocalStorage, Storage},
 };
 
 pub mod debian_installer;
 pub mod diffs;
+pub mod estimate;
 pub mod metadata;
 pub mod packages;
 pub mod release;
@@ -36,10 +51,21 @@ pub enum MirrorResult {
     NewRelease {
         total_download_size: u64,
         num_packages_downloaded: u64,
+        num_retries: u64,
+        deduplicated_size: u64,
+        deduplicated_files: u64,
+        seeded_size: u64,
+        num_patched: u64,
+        num_failed: u64,
+        num_skipped: u64,
     },
     ReleaseUnchanged,
     IrrelevantChanges,
-    ReleaseUnchangedButIncomplete,
+    ReleaseUnchangedButIncomplete {
+        /// The missing files that could still not be downloaded.
+        num_skipped: u64,
+    },
+    DryRun(Estimate),
     Error(MirsError),
 }
 
@@ -49,24 +75,107 @@ impl Display for MirrorResult {
             MirrorResult::NewRelease {
                 total_download_size,
                 num_packages_downloaded,
-            } => f.write_fmt(format_args!(
-                "Ok: {} downloaded, {} packages/source files",
-                HumanBytes(*total_download_size),
-                num_packages_downloaded
-            )),
+                num_retries,
+                deduplicated_size,
+                deduplicated_files,
+                seeded_size,
+                num_patched,
+                num_failed,
+                num_skipped,
+            } => {
+                f.write_fmt(format_args!(
+                    "Ok: {} downloaded, {} packages/source files",
+                    HumanBytes(*total_download_size),
+                    num_packages_downloaded
+                ))?;
+
+                if *deduplicated_size > 0 {
+                    f.write_fmt(format_args!(
+                        ", {deduplicated_files} files ({}) linked from the dedup store",
+                        HumanBytes(*deduplicated_size)
+                    ))?;
+                }
+
+                if *seeded_size > 0 {
+                    f.write_fmt(format_args!(
+                        ", {} seeded from local files",
+                        HumanBytes(*seeded_size)
+                    ))?;
+                }
+
+                if *num_patched > 0 {
+                    f.write_fmt(format_args!(", {num_patched} indices patched from pdiffs"))?;
+                }
+
+                if *num_retries > 0 {
+                    f.write_fmt(format_args!(", {num_retries} retries"))?;
+                }
+
+                if *num_failed > 0 {
+                    f.write_fmt(format_args!(", {num_failed} failed checksum"))?;
+                }
+
+                if *num_skipped > 0 {
+                    f.write_fmt(format_args!(", {num_skipped} could not be downloaded"))?;
+                }
+
+                Ok(())
+            }
             MirrorResult::ReleaseUnchanged => f.write_str("Ok: release unchanged"),
             MirrorResult::IrrelevantChanges => {
                 f.write_str("Ok: new release, but changes do not apply to configured selections")
             }
-            MirrorResult::ReleaseUnchangedButIncomplete => {
-                f.write_str("Ok: release unchanged, but attempted to download missing files")
+            MirrorResult::ReleaseUnchangedButIncomplete { num_skipped } => {
+                f.write_str("Ok: release unchanged, but attempted to download missing files")?;
+
+                if *num_skipped > 0 {
+                    f.write_fmt(format_args!(", {num_skipped} could not be downloaded"))?;
+                }
+
+                Ok(())
             }
+            MirrorResult::DryRun(estimate) => estimate.fmt(f),
             MirrorResult::Error(e) => f.write_fmt(format_args!("Fail: {e}")),
         }
     }
 }
 
-impl CmdResult for MirrorResult {}
+impl CmdResult for MirrorResult {
+    fn summary(&self) -> ResultSummary {
+        match self {
+            MirrorResult::NewRelease {
+                total_download_size,
+                num_packages_downloaded,
+                num_failed,
+                num_skipped,
+                ..
+            } => ResultSummary {
+                outcome: if num_failed + num_skipped > 0 {
+                    Outcome::Incomplete
+                } else {
+                    Outcome::Ok
+                },
+                bytes: Some(*total_download_size),
+                packages: Some(*num_packages_downloaded),
+                failed: *num_failed,
+                skipped: *num_skipped,
+            },
+            MirrorResult::ReleaseUnchangedButIncomplete { num_skipped } => ResultSummary {
+                outcome: if *num_skipped > 0 {
+                    Outcome::Incomplete
+                } else {
+                    Outcome::Ok
+                },
+                skipped: *num_skipped,
+                ..ResultSummary::ok()
+            },
+            MirrorResult::ReleaseUnchanged
+            | MirrorResult::IrrelevantChanges
+            | MirrorResult::DryRun(..) => ResultSummary::ok(),
+            MirrorResult::Error(..) => ResultSummary::failed(),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct MirrorState {
@@ -75,6 +184,13 @@ pub struct MirrorState {
     pub downloader: Downloader,
     pub pgp_key_store: Arc<PgpKeyStore>,
     pub mtime: bool,
+    pub snapshot: Option<CompactString>,
+    /// Whether to only estimate what the run would download, without writing to the mirror.
+    pub dry_run: bool,
+    /// Where the suite is staged when publishing atomically.
+    pub publisher: Option<Publisher>,
+    /// The free space claimed by the suites that are downloading packages.
+    pub reserved_space: SpaceReservations,
     pub output: Arc<Mutex<MirrorOutput>>,
 }
 
@@ -85,6 +201,13 @@ pub struct MirrorOutput {
     pub delete_paths: Vec<FilePath>,
     pub total_bytes_downloaded: u64,
     pub total_packages_downloaded: u64,
+    pub total_retries: u64,
+    pub total_bytes_deduplicated: u64,
+    pub total_files_deduplicated: u64,
+    pub total_bytes_seeded: u64,
+    pub total_indices_patched: u64,
+    pub total_files_failed: u64,
+    pub total_files_skipped: u64,
     pub new_release: bool,
 }
 
@@ -93,6 +216,37 @@ impl MirrorOutput {
         self.indices.is_empty()
     }
 
+    /// Adds the transfer counters of a finished step to the totals of the run.
+    pub fn add_transfers(&mut self, progress: &Progress) {
+        self.total_bytes_downloaded += progress.bytes.success();
+        self.total_retries += progress.retries();
+        self.total_bytes_deduplicated += progress.deduplicated();
+        self.total_files_deduplicated += progress.deduplicated_files();
+        self.total_bytes_seeded += progress.seeded();
+    }
+
+    /// Adds the files of a finished step that failed their checksum or could not be
+    /// downloaded. Only steps that download files the release promises to have should count
+    /// them, since the metadata step also tries variants of indices that may not exist.
+    pub fn add_failures(&mut self, progress: &Progress) {
+        self.total_files_failed += progress.files.failed();
+        self.total_files_skipped += progress.errors();
+    }
+
+    fn new_release_result(&self) -> MirrorResult {
+        MirrorResult::NewRelease {
+            total_download_size: self.total_bytes_downloaded,
+            num_packages_downloaded: self.total_packages_downloaded,
+            num_retries: self.total_retries,
+            deduplicated_size: self.total_bytes_deduplicated,
+            deduplicated_files: self.total_files_deduplicated,
+            seeded_size: self.total_bytes_seeded,
+            num_patched: self.total_indices_patched,
+            num_failed: self.total_files_failed,
+            num_skipped: self.total_files_skipped,
+        }
+    }
+
     pub fn take_metadata<F: Fn(&MetadataFile) -> bool>(
         &mut self,
         filter_func: F,
@@ -115,28 +269,30 @@ impl MirrorState {
     async fn move_metadata_into_root(&self) -> Result<MirrorResult> {
         let output = self.output.lock().await;
 
-        let tmp_dir = self.repo.tmp_dir.clone();
-        let root_dir = self.repo.root_dir.clone();
+        let storage = self.downloader.storage();
+
+        let tmp_dir = &self.repo.tmp_dir;
+        let root_dir = &self.repo.root_dir;
 
         for path in &output.delete_paths {
-            if path.exists() {
-                tokio::fs::remove_file(path).await?;
+            if storage.stat(path).await?.is_some() {
+                storage.remove(path).await?;
             }
         }
 
-        spawn_blocking(move || {
-            rebase_dir(tmp_dir.as_ref(), tmp_dir.as_ref(), root_dir.as_ref())?;
+        // the tmp dir is in the staging dir, which is always on the local disk
+        for path in LocalStorage.list(tmp_dir, &[]).await? {
+            let rel_path = path
+                .as_str()
+                .strip_prefix(tmp_dir.as_str())
+                .expect("implemention error; path should be in tmp");
 
-            std::fs::remove_dir_all(&tmp_dir)?;
+            storage.put(&path, &root_dir.join(rel_path)).await?;
+        }
 
-            Ok::<(), MirsError>(())
-        })
-        .await??;
+        self.repo.delete_tmp()?;
 
-        Ok(MirrorResult::NewRelease {
-            total_download_size: output.total_bytes_downloaded,
-            num_packages_downloaded: output.total_packages_downloaded,
-        })
+        Ok(output.new_release_result())
     }
 }
 
@@ -151,38 +307,79 @@ impl CmdState for MirrorState {
     type Result = MirrorResult;
 
     async fn finalize(&self) -> Self::Result {
-        let result = {
-            let output = self.output.lock().await;
-
-            MirrorResult::NewRelease {
-                total_download_size: output.total_bytes_downloaded,
-                num_packages_downloaded: output.total_packages_downloaded,
-            }
-        };
+        let result = self.output.lock().await.new_release_result();
 
         self.finalize_with_result(result).await
     }
 
+    /// The date of the release that is published in the mirror, which is still the previous
+    /// one when the run failed.
+    async fn release_time(&self) -> Option<u64> {
+        published_release_time(&self.opts, &self.repo.root_dir).await
+    }
+
+    fn root_dir(&self) -> Option<&FilePath> {
+        Some(&self.repo.root_dir)
+    }
+
     async fn finalize_with_result(&self, result: Self::Result) -> Self::Result {
         match &result {
             MirrorResult::NewRelease { .. } | MirrorResult::IrrelevantChanges => {
+                if let Some(publisher) = &self.publisher {
+                    // published, and snapshotted, along with the other suites of the mirror
+                    // once the run is done
+                    publisher.stage(
+                        &self.repo.root_dir,
+                        StagedSuite {
+                            name: self.to_string(),
+                            opts: self.opts.clone(),
+                            tmp_dir: self.repo.tmp_dir.clone(),
+                            delete_paths: self.output.lock().await.delete_paths.clone(),
+                            snapshot: self
+                                .snapshot
+                                .clone()
+                                .filter(|_| result.summary().outcome == Outcome::Ok),
+                        },
+                    );
+
+                    return result;
+                }
+
                 if let Err(e) = self.move_metadata_into_root().await {
                     return MirrorResult::Error(MirsError::Finalize { inner: Box::new(e) });
                 }
             }
-            MirrorResult::ReleaseUnchangedButIncomplete
+            MirrorResult::ReleaseUnchangedButIncomplete { .. }
             | MirrorResult::ReleaseUnchanged
+            | MirrorResult::DryRun(..)
             | MirrorResult::Error(..) => {
                 _ = self.repo.delete_tmp();
             }
         }
 
+        // a snapshot is only taken of a suite that is complete
+        if let Some(id) = &self.snapshot
+            && !matches!(result, MirrorResult::DryRun(..))
+            && result.summary().outcome == Outcome::Ok
+            && let Err(e) = create_snapshot(&self.opts, &self.repo.root_dir, id).await
+        {
+            return MirrorResult::Error(MirsError::Snapshot { inner: Box::new(e) });
+        }
+
         result
     }
 }
 
 impl Context<MirrorState> {
-    fn create_steps(opts: &MirrorOpts) -> Vec<MirrorDynStep> {
+    fn create_steps(opts: &MirrorOpts, dry_run: bool) -> Vec<MirrorDynStep> {
+        if dry_run {
+            return vec![
+                Box::new(DownloadRelease),
+                Box::new(DownloadMetadata),
+                Box::new(EstimatePackageDownloads),
+            ];
+        }
+
         let mut steps: Vec<MirrorDynStep> = vec![
             Box::new(DownloadRelease),
             Box::new(DownloadMetadata),
@@ -197,28 +394,47 @@ impl Context<MirrorState> {
         steps
     }
 
-    pub fn create(
+    pub async fn create(
         opts: Vec<MirrorOpts>,
         cli_opts: Arc<CliOpts>,
         pgp_key_store: Arc<PgpKeyStore>,
         mtime: bool,
+        snapshot: Option<CompactString>,
+        dry_run: bool,
     ) -> Result<Vec<(MirrorContext, Vec<MirrorDynStep>)>> {
-        let downloader = Downloader::build(cli_opts.dl_threads, mtime);
+        let downloader = Downloader::build(&cli_opts, mtime).await;
+        let reserved_space = SpaceReservations::default();
+        let publisher = cli_opts
+            .atomic_publish
+            .then(|| Publisher::new(cli_opts.keep_generations));
+
+        let repos = opts
+            .into_iter()
+            .map(|o| Ok((Repository::build_with_tmp(&o, &cli_opts)?, o)))
+            .collect::<Result<Vec<_>>>()?;
 
-        opts.into_iter()
-            .map(|o| {
-                let repo = Repository::build_with_tmp(&o, &cli_opts)?;
+        let bandwidth_limiters = shared_bandwidth_limiters(&repos);
 
-                let steps = Self::create_steps(&o);
+        repos
+            .into_iter()
+            .map(|(mut repo, o)| {
+                repo.bandwidth_limiter = bandwidth_limiters.get(&repo.root_dir).cloned();
 
+                let steps = Self::create_steps(&o, dry_run);
+
+                let downloader = downloader.for_repository();
                 let progress = downloader.progress();
 
                 let state = MirrorState {
-                    repo,
+                    repo: Arc::new(repo),
                     opts: Arc::new(o),
-                    downloader: downloader.clone(),
+                    downloader,
                     pgp_key_store: pgp_key_store.clone(),
                     mtime,
+                    snapshot: snapshot.clone(),
+                    dry_run,
+                    publisher: publisher.clone(),
+                    reserved_space: reserved_space.clone(),
                     ..Default::default()
                 };
 
@@ -228,6 +444,119 @@ impl Context<MirrorState> {
     }
 }
 
+/// One bandwidth limiter for each repository that has a limit, which all of its suites share
+/// so that the limit holds for the repository as a whole. When its suites have different limits,
+/// the lowest one is used.
+fn shared_bandwidth_limiters(
+    repos: &[(Repository, MirrorOpts)],
+) -> HashMap<FilePath, BandwidthLimiter> {
+    let mut limits = HashMap::<FilePath, u64>::default();
+
+    for (repo, opts) in repos {
+        if let Some(limit) = opts.bandwidth_limit {
+            limits
+                .entry(repo.root_dir.clone())
+                .and_modify(|v| *v = (*v).min(limit))
+                .or_insert(limit);
+        }
+    }
+
+    limits
+        .into_iter()
+        .map(|(root_dir, limit)| (root_dir, BandwidthLimiter::new(limit)))
+        .collect()
+}
+
+/// The date of the release of the suite that is published in the mirror in `root_dir`.
+async fn published_release_time(opts: &MirrorOpts, root_dir: &FilePath) -> Option<u64> {
+    let dist_root = FilePath(format_compact!("{root_dir}/{}", opts.dist_part()));
+
+    let release_files = get_rooted_release_files(&dist_root);
+    let release_file = pick_release(&release_files)?;
+
+    Release::parse(release_file, opts)
+        .await
+        .ok()?
+        .release_time()
+}
+
+/// Publishes the suites that the run staged as one new generation of dists per mirror, and
+/// takes their snapshots. The suites of a mirror that could not be published fail, and the
+/// runs of the published ones get the date of the release that is now published.
+pub async fn publish_staged(publisher: &Publisher, runs: &mut [RepositoryRun<MirrorResult>]) {
+    for (root_dir, suites) in publisher.take() {
+        let tmp_dirs = suites.iter().map(|v| v.tmp_dir.clone()).collect();
+        let delete_paths = suites
+            .iter()
+            .flat_map(|v| v.delete_paths.iter().cloned())
+            .collect();
+
+        let published = publish(root_dir.clone(), tmp_dirs, delete_paths, publisher.keep).await;
+
+        match &published {
+            Ok(generation) => crate::log(format!(
+                "Published {root_dir}/dists as generation {generation}"
+            )),
+            Err(e) => crate::log(format!("Publishing {root_dir}/dists: Fail: {e}")),
+        }
+
+        for suite in suites {
+            let Some(run) = runs
+                .iter_mut()
+                .find(|run| run.name == suite.name && run.root_dir.as_ref() == Some(&root_dir))
+            else {
+                continue;
+            };
+
+            if let Err(e) = &published {
+                run.result = MirrorResult::Error(MirsError::Finalize {
+                    inner: Box::new(MirsError::Publish {
+                        path: root_dir.clone(),
+                        msg: e.to_compact_string(),
+                    }),
+                });
+                continue;
+            }
+
+            _ = tokio::fs::remove_dir_all(&suite.tmp_dir).await;
+
+            run.release_time = published_release_time(&suite.opts, &root_dir).await;
+
+            if let Some(id) = &suite.snapshot
+                && let Err(e) = create_snapshot(&suite.opts, &root_dir, id).await
+            {
+                run.result = MirrorResult::Error(MirsError::Snapshot { inner: Box::new(e) });
+            }
+        }
+    }
+}
+
+/// Cleans up temporary download files left behind by interrupted runs, in the staging dir of
+/// the output that is about to be mirrored into.
+pub async fn remove_orphaned_files(ctxs: &[(MirrorContext, Vec<MirrorDynStep>)]) {
+    let Some((ctx, _)) = ctxs.first() else {
+        return;
+    };
+
+    // every repository downloads through the same tasks, and so into the same partial dir
+    let downloader = &ctx.state.downloader;
+
+    let result = remove_orphaned_partials(downloader.partials(), &*downloader.storage()).await;
+
+    let staging_dir = ctx.cli_opts.staging_dir();
+
+    match result {
+        Ok(0) => (),
+        Ok(removed) => crate::log(format!(
+            "removed {removed} orphaned temporary files in {staging_dir}"
+        )),
+        Err(e) => println!(
+            "{} WARNING: failed removing orphaned temporary files in {staging_dir}: {e}",
+            crate::now()
+        ),
+    }
+}
+
 pub fn verify_and_prune(files: &mut Vec<MetadataFile>) {
     let mut pos = 0;
     loop {
@@ -243,29 +572,340 @@ pub fn verify_and_prune(files: &mut Vec<MetadataFile>) {
     }
 }
 
-fn rebase_dir(dir: &Path, from: &Path, to: &Path) -> Result<()> {
-    for entry in std::fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            rebase_dir(&path, from, to)?;
-        } else {
-            let rel_path = path
-                .strip_prefix(from)
-                .expect("implemention error; path should be in tmp");
+#[cfg(test)]
+mod test {
+    use std::io::Write;
 
-            let new_path = to.join(rel_path);
+    use clap::Parser;
+    use sha2::{Digest, Sha256};
 
-            let parent = new_path.parent().unwrap();
-            if !parent.exists() {
-                std::fs::create_dir_all(parent)?;
-            }
+    use crate::{
+        cmd::Cmd,
+        mirror::*,
+        publish::{current_generation, generations},
+        storage::{SharedStorage, memory::MemoryStorage},
+        testing::TestDir,
+    };
 
-            if std::fs::rename(&path, &new_path).is_err() {
-                std::fs::copy(&path, &new_path)?;
-            }
+    #[tokio::test]
+    async fn mirrors_from_local_sources() {
+        let dir = TestDir::new("local-source");
+
+        // without a signature, the release is only picked up as an InRelease
+        for upstream in ["url", "path"] {
+            dir.write_suite(upstream, "trixie", &["hello"]);
+
+            let dist = dir.join(upstream).join("dists/trixie");
+            std::fs::rename(dist.join("Release"), dist.join("InRelease")).unwrap();
+        }
+
+        let output = dir.join("output");
+
+        let opts = [
+            format!("deb [arch=amd64] file://{} trixie main", dir.join("url")),
+            format!("deb [arch=amd64] {} trixie main", dir.join("path")),
+        ]
+        .iter()
+        .map(|line| MirrorOpts::try_from(line.as_str()).unwrap())
+        .collect();
+
+        let cli_opts = CliOpts::parse_from(["aptmirs", "--output", output.as_str()]);
+
+        let exit_code = Cmd::default()
+            .execute(opts, Arc::new(cli_opts), Arc::new(PgpKeyStore::default()))
+            .await
+            .unwrap();
+
+        assert_eq!(exit_code, 0);
+
+        // file urls have no host, so they are mirrored under localhost
+        for upstream in ["url", "path"] {
+            let root = output
+                .join("localhost")
+                .join(&dir.join(upstream).as_str()[1..]);
+
+            assert_eq!(
+                std::fs::read_to_string(root.join("pool/main/hello_1.0_amd64.deb")).unwrap(),
+                "hello"
+            );
+            assert!(
+                root.join("dists/trixie/main/binary-amd64/Packages")
+                    .exists()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn mirrors_into_another_storage() {
+        let dir = TestDir::new("memory-output");
+
+        dir.write_suite("upstream", "trixie", &["hello"]);
+
+        let dist = dir.join("upstream/dists/trixie");
+        std::fs::rename(dist.join("Release"), dist.join("InRelease")).unwrap();
+
+        let output = dir.join("output");
+        let storage = MemoryStorage::default();
+
+        let line = format!("deb [arch=amd64] {} trixie main", dir.join("upstream"));
+        let opts = vec![MirrorOpts::try_from(line.as_str()).unwrap()];
+
+        let mut cli_opts = CliOpts::parse_from([
+            "aptmirs",
+            "--output",
+            output.as_str(),
+            "--staging-dir",
+            dir.join("staging").as_str(),
+        ]);
+        cli_opts.storage = SharedStorage::new(storage.clone());
+
+        let exit_code = Cmd::default()
+            .execute(opts, Arc::new(cli_opts), Arc::new(PgpKeyStore::default()))
+            .await
+            .unwrap();
+
+        assert_eq!(exit_code, 0);
+        assert!(!output.exists());
+
+        let root = output
+            .join("localhost")
+            .join(&dir.join("upstream").as_str()[1..]);
+
+        assert_eq!(
+            storage.list(&root, &[]).await.unwrap(),
+            [
+                "dists/trixie/InRelease",
+                "dists/trixie/main/binary-amd64/Packages",
+                "pool/main/hello_1.0_amd64.deb",
+            ]
+            .map(|path| root.join(path))
+        );
+    }
+
+    #[test]
+    fn suites_of_a_repository_share_a_bandwidth_limiter() {
+        let dir = TestDir::new("shared-bandwidth");
+
+        let cli_opts = CliOpts::parse_from(["aptmirs", "--output", dir.as_str()]);
+
+        let repos = [
+            "deb [bandwidth_limit=5M] http://deb.debian.org/debian trixie main",
+            "deb [bandwidth_limit=1M] http://deb.debian.org/debian trixie-updates main",
+            "deb http://deb.debian.org/debian trixie-backports main",
+            "deb http://security.debian.org/debian-security trixie-security main",
+        ]
+        .map(|line| {
+            let opts = MirrorOpts::try_from(line).unwrap();
+            (Repository::build(&opts, &cli_opts).unwrap(), opts)
+        });
+
+        let limiters = shared_bandwidth_limiters(&repos);
+
+        assert_eq!(limiters.len(), 1);
+        assert_eq!(
+            format!("{:?}", limiters[&repos[2].0.root_dir]),
+            "BandwidthLimiter(1048576 B/s)"
+        );
+    }
+
+    #[tokio::test]
+    async fn local_only_options_need_local_storage() {
+        let mut cli_opts = CliOpts::parse_from(["aptmirs", "--output", "/mirror", "--dedup"]);
+        cli_opts.storage = SharedStorage::new(MemoryStorage::default());
+
+        let result = Cmd::default()
+            .execute(
+                Vec::new(),
+                Arc::new(cli_opts),
+                Arc::new(PgpKeyStore::default()),
+            )
+            .await;
+
+        assert!(matches!(result, Err(MirsError::Config { .. })));
+    }
+
+    #[tokio::test]
+    async fn suites_of_a_mirror_are_published_in_one_generation() {
+        let dir = TestDir::new("publish-run");
+
+        for suite in ["trixie", "trixie-updates"] {
+            dir.write_suite("upstream", suite, &["hello"]);
+
+            let dist = dir.join("upstream/dists").join(suite);
+            std::fs::rename(dist.join("Release"), dist.join("InRelease")).unwrap();
         }
+
+        let output = dir.join("output");
+
+        let opts = ["trixie", "trixie-updates"]
+            .map(|suite| {
+                let line = format!("deb [arch=amd64] {} {suite} main", dir.join("upstream"));
+                MirrorOpts::try_from(line.as_str()).unwrap()
+            })
+            .into_iter()
+            .collect();
+
+        let cli_opts =
+            CliOpts::parse_from(["aptmirs", "--output", output.as_str(), "--atomic-publish"]);
+
+        let exit_code = Cmd::default()
+            .execute(opts, Arc::new(cli_opts), Arc::new(PgpKeyStore::default()))
+            .await
+            .unwrap();
+
+        assert_eq!(exit_code, 0);
+
+        let root = output
+            .join("localhost")
+            .join(&dir.join("upstream").as_str()[1..]);
+
+        assert_eq!(generations(&root).unwrap(), vec![1]);
+        assert_eq!(current_generation(&root).unwrap(), Some(1));
+        assert!(root.join("dists/trixie/InRelease").exists());
+        assert!(root.join("dists/trixie-updates/InRelease").exists());
+    }
+
+    /// Gzips `content` with a name and a time in its header, unlike the patched indices.
+    fn gzip_with_header(content: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::GzBuilder::new()
+            .filename("Packages")
+            .mtime(1)
+            .write(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Writes an InRelease for the suite in `upstream` that lists `files` with their content.
+    fn write_in_release(dir: &TestDir, files: &[(&str, &[u8])]) {
+        let mut release =
+            "Suite: trixie\nArchitectures: amd64\nComponents: main\nSHA256:\n".to_string();
+
+        for (path, content) in files {
+            release.push_str(&format!(
+                " {} {} {path}\n",
+                hex::encode(Sha256::digest(content)),
+                content.len()
+            ));
+        }
+
+        dir.write("upstream/dists/trixie/InRelease", release);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn patched_indices_leave_out_variants_that_are_not_reproduced() {
+        let dir = TestDir::new("pdiff-variants");
+
+        let index_dir = dir.join("upstream/dists/trixie/main/binary-amd64");
+
+        dir.write_suite("upstream", "trixie", &["hello"]);
+        let v1 = std::fs::read(index_dir.join("Packages")).unwrap();
+        let v1_gz = gzip_with_header(&v1);
+
+        dir.write(
+            "upstream/dists/trixie/main/binary-amd64/Packages.gz",
+            &v1_gz,
+        );
+        write_in_release(
+            &dir,
+            &[
+                ("main/binary-amd64/Packages", &v1),
+                ("main/binary-amd64/Packages.gz", &v1_gz),
+            ],
+        );
+
+        let output = dir.join("output");
+
+        let line = format!("deb [arch=amd64] {} trixie main", dir.join("upstream"));
+        let cli_opts = Arc::new(CliOpts::parse_from([
+            "aptmirs",
+            "--output",
+            output.as_str(),
+            "--apply-pdiffs",
+        ]));
+
+        let mirror = async || {
+            Cmd::default()
+                .execute(
+                    vec![MirrorOpts::try_from(line.as_str()).unwrap()],
+                    cli_opts.clone(),
+                    Arc::new(PgpKeyStore::default()),
+                )
+                .await
+                .unwrap()
+        };
+
+        assert_eq!(mirror().await, 0);
+
+        dir.write_suite("upstream", "trixie", &["hello", "world"]);
+        let v2 = std::fs::read(index_dir.join("Packages")).unwrap();
+        let v2_gz = gzip_with_header(&v2);
+
+        // appends the paragraph of world to the end of the previous index
+        let patch = [
+            format!("{}a\n", v1.iter().filter(|&&b| b == b'\n').count()).as_bytes(),
+            &v2[v1.len()..],
+            b".\n",
+        ]
+        .concat();
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&patch).unwrap();
+        let patch_gz = encoder.finish().unwrap();
+
+        dir.write(
+            "upstream/dists/trixie/main/binary-amd64/Packages.diff/T-1.gz",
+            &patch_gz,
+        );
+
+        let sha256 = |content: &[u8]| hex::encode(Sha256::digest(content));
+
+        let diff_index = format!(
+            "SHA256-Current: {} {}\nSHA256-History:\n {} {} T-1\nSHA256-Patches:\n {} {} T-1\nSHA256-Download:\n {} {} T-1.gz\n",
+            sha256(&v2),
+            v2.len(),
+            sha256(&v1),
+            v1.len(),
+            sha256(&patch),
+            patch.len(),
+            sha256(&patch_gz),
+            patch_gz.len()
+        );
+
+        dir.write(
+            "upstream/dists/trixie/main/binary-amd64/Packages.diff/Index",
+            &diff_index,
+        );
+        write_in_release(
+            &dir,
+            &[
+                ("main/binary-amd64/Packages", &v2),
+                ("main/binary-amd64/Packages.gz", &v2_gz),
+                (
+                    "main/binary-amd64/Packages.diff/Index",
+                    diff_index.as_bytes(),
+                ),
+            ],
+        );
+
+        // any index that is downloaded in full fails its checksum
+        dir.write("upstream/dists/trixie/main/binary-amd64/Packages", "");
+        dir.write("upstream/dists/trixie/main/binary-amd64/Packages.gz", "");
+
+        let root = output
+            .join("localhost")
+            .join(&dir.join("upstream").as_str()[1..]);
+        let mirrored_index_dir = root.join("dists/trixie/main/binary-amd64");
+
+        // the second run patches the index, and the third finds it current
+        for _ in 0..2 {
+            assert_eq!(mirror().await, 0);
+
+            assert_eq!(
+                std::fs::read(mirrored_index_dir.join("Packages")).unwrap(),
+                v2
+            );
+            assert!(!mirrored_index_dir.join("Packages.gz").exists());
+            assert!(root.join("pool/main/world_1.0_amd64.deb").exists());
+        }
+    }
 }
@@ -96,7 +96,8 @@ impl Step<MirrorState> for DownloadDebianInstaller {
 
         ctx.progress.wait_for_completion(&progress_bar).await;
 
-        output.total_bytes_downloaded += ctx.progress.bytes.success();
+        output.add_transfers(&ctx.progress);
+        output.add_failures(&ctx.progress);
         output.delete_paths.extend(old_files);
 
         Ok(StepResult::Continue)
@@ -72,7 +72,8 @@ impl Step<MirrorState> for DownloadFromDiffs {
 
         ctx.progress.wait_for_completion(&progress_bar).await;
 
-        output.total_bytes_downloaded += ctx.progress.bytes.success();
+        output.add_transfers(&ctx.progress);
+        output.add_failures(&ctx.progress);
 
         Ok(StepResult::Continue)
     }
@@ -0,0 +1,232 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::{Arc, atomic::Ordering},
+};
+
+use ahash::HashSet;
+use async_trait::async_trait;
+use compact_str::{CompactString, ToCompactString};
+use indicatif::HumanBytes;
+use tokio::{runtime::Handle, task::spawn_blocking};
+
+use crate::{
+    context::Context,
+    error::{MirsError, Result},
+    metadata::{metadata_file::MetadataFile, package_filter::PackageFilter},
+    progress::Progress,
+    step::{Step, StepResult},
+};
+
+use super::{MirrorResult, MirrorState};
+
+/// The files and bytes a mirror run would download.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EstimateCount {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl EstimateCount {
+    fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+    }
+}
+
+impl Display for EstimateCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} files ({})",
+            self.files,
+            HumanBytes(self.bytes)
+        ))
+    }
+}
+
+/// The files that are missing in the mirror, in total and by the component and the
+/// architecture of the indices that reference them.
+#[derive(Debug, Default)]
+pub struct Estimate {
+    pub total: EstimateCount,
+    pub components: BTreeMap<CompactString, EstimateCount>,
+    pub architectures: BTreeMap<CompactString, EstimateCount>,
+}
+
+impl Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "Ok: dry run, {} would be downloaded",
+            self.total
+        ))?;
+
+        for (component, count) in &self.components {
+            f.write_fmt(format_args!("\n    component {component}: {count}"))?;
+        }
+
+        for (arch, count) in &self.architectures {
+            f.write_fmt(format_args!("\n    architecture {arch}: {count}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct EstimatePackageDownloads;
+
+#[async_trait]
+impl Step<MirrorState> for EstimatePackageDownloads {
+    type Result = MirrorResult;
+
+    fn step_name(&self) -> &'static str {
+        "Estimating downloads"
+    }
+
+    fn error(&self, e: MirsError) -> Self::Result {
+        MirrorResult::Error(MirsError::DownloadPackages { inner: Box::new(e) })
+    }
+
+    async fn execute(&self, ctx: Arc<Context<MirrorState>>) -> Result<StepResult<Self::Result>> {
+        let packages_metadata =
+            ctx.state.output.lock().await.take_metadata(|f| {
+                matches!(f, MetadataFile::Packages(..) | MetadataFile::Sources(..))
+            });
+
+        let estimate = estimate_downloads(&ctx, packages_metadata).await?;
+
+        Ok(StepResult::End(MirrorResult::DryRun(estimate)))
+    }
+}
+
+/// Walks the packages and sources that the indices reference, and counts the ones that the
+/// configured selections accept and that are missing from the mirror.
+async fn estimate_downloads(
+    ctx: &Context<MirrorState>,
+    packages_metadata: Vec<MetadataFile>,
+) -> Result<Estimate> {
+    let file_progress = Progress::new_with_step(0, "Processing indices");
+
+    let file_progress_bar = ctx
+        .progress
+        .attach(file_progress.create_processing_progress_bar().await);
+
+    let packages_files = packages_metadata
+        .iter()
+        .cloned()
+        .map(MetadataFile::into_reader)
+        .collect::<Result<Vec<_>>>()?;
+
+    file_progress.files.inc_total(packages_files.len() as u64);
+
+    let total_size = packages_files.iter().map(|v| v.size()).sum();
+    let mut incremental_size_base = 0;
+
+    file_progress.bytes.inc_total(total_size);
+
+    let mut filter = PackageFilter::new(&ctx.state.opts)?;
+
+    let task_downloader = ctx.state.downloader.clone();
+    let task_repo = ctx.state.repo.clone();
+    let task_opts = ctx.state.opts.clone();
+
+    spawn_blocking(move || {
+        let async_handle = Handle::current();
+
+        filter.resolve_dependencies(&task_opts, &[&task_repo.tmp_dir, &task_repo.root_dir])?;
+        filter.select_versions(&task_opts, &packages_metadata)?;
+
+        let dist_dir = task_repo.tmp_dir.join(task_opts.dist_part());
+
+        let mut estimate = Estimate::default();
+
+        // packages of architecture all are listed in the index of every architecture
+        let mut seen = HashSet::default();
+
+        for packages_file in packages_files {
+            let counter = packages_file.counter();
+            let package_size = packages_file.size();
+
+            let rel_path = packages_file
+                .file()
+                .path()
+                .as_str()
+                .strip_prefix(dist_dir.as_str())
+                .unwrap_or_default()
+                .trim_start_matches('/');
+
+            let (component, arch) = index_group(rel_path);
+
+            for package in packages_file {
+                let package = package?;
+
+                if filter.accept(&package) {
+                    let dl = task_repo.create_file_download(package);
+
+                    if seen.insert(dl.primary_target_path.clone())
+                        && async_handle.block_on(task_downloader.needs_downloading(&dl))
+                    {
+                        let size = dl.size.unwrap_or(0);
+
+                        estimate.total.add(size);
+                        estimate
+                            .components
+                            .entry(component.clone())
+                            .or_default()
+                            .add(size);
+                        estimate
+                            .architectures
+                            .entry(arch.clone())
+                            .or_default()
+                            .add(size);
+                    }
+                }
+
+                file_progress
+                    .bytes
+                    .set_success(counter.load(Ordering::SeqCst) + incremental_size_base);
+
+                file_progress.update_for_bytes(&file_progress_bar);
+            }
+
+            incremental_size_base += package_size;
+            file_progress.update_for_bytes(&file_progress_bar);
+        }
+
+        Ok::<Estimate, MirsError>(estimate)
+    })
+    .await?
+}
+
+/// The component and the architecture of an index, from its path in the dist, e.g.
+/// `main/binary-amd64/Packages.xz`. Sources indices have the architecture `source`.
+fn index_group(rel_path: &str) -> (CompactString, CompactString) {
+    let parent = rel_path.rsplit_once('/').map_or("", |(parent, _)| parent);
+
+    let (component, arch) = parent.rsplit_once('/').unwrap_or(("", parent));
+
+    let arch = arch.strip_prefix("binary-").unwrap_or(arch);
+
+    (component.to_compact_string(), arch.to_compact_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mirror::estimate::*;
+
+    #[test]
+    fn indices_are_grouped_by_component_and_architecture() {
+        assert_eq!(
+            index_group("main/binary-amd64/Packages.xz"),
+            ("main".into(), "amd64".into())
+        );
+        assert_eq!(
+            index_group("main/source/Sources.gz"),
+            ("main".into(), "source".into())
+        );
+        assert_eq!(
+            index_group("updates/main/binary-arm64/Packages"),
+            ("updates/main".into(), "arm64".into())
+        );
+        assert_eq!(index_group("Packages"), ("".into(), "".into()));
+    }
+}
@@ -1,7 +1,14 @@
-use std::sync::Arc;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::{Read, Write},
+    sync::Arc,
+};
 
+use ahash::HashSet;
 use async_trait::async_trait;
 use compact_str::format_compact;
+use tokio::{runtime::Handle, task::spawn_blocking};
 
 use crate::error::Result;
 use crate::metadata::repository::RELEASE_GPG_FILE_NAME;
@@ -9,8 +16,11 @@ use crate::{
     context::Context,
     error::MirsError,
     metadata::{
-        FilePath,
+        FilePath, create_reader,
+        diff_index_file::DiffIndexFile,
         metadata_file::{MetadataFile, deduplicate_metadata},
+        pdiff::patch_to_current,
+        release::FileEntry,
         repository::{INRELEASE_FILE_NAME, RELEASE_FILE_NAME},
     },
     mirror::MirrorResult,
@@ -46,8 +56,22 @@ impl Step<MirrorState> for DownloadMetadata {
 
         let mut metadata = Vec::new();
 
-        for (mut file, file_entry) in release.into_iter() {
-            let mut add_by_hash = by_hash;
+        let entries = release.into_iter().collect::<Vec<_>>();
+
+        let patchable = if ctx.cli_opts.apply_pdiffs {
+            patchable_indices(&entries)
+        } else {
+            HashSet::default()
+        };
+
+        let changed = entries
+            .iter()
+            .map(|(file, _)| file.path().clone())
+            .collect::<HashSet<_>>();
+
+        let mut deferred: BTreeMap<FilePath, Vec<(MetadataFile, FileEntry)>> = BTreeMap::new();
+
+        for (file, file_entry) in entries {
             let url = ctx.state.repo.to_url_in_dist(file.as_ref());
 
             let file_path_in_tmp = ctx.state.repo.to_path_in_tmp(&url);
@@ -75,33 +99,38 @@ impl Step<MirrorState> for DownloadMetadata {
                 ));
 
                 if let MetadataFile::SumFile(..) = &file {
-                    if file_path_in_root.exists() && !ctx.cli_opts.force {
+                    if file_path_in_root.exists() && !(ctx.cli_opts.force || ctx.state.dry_run) {
                         continue;
                     }
                 } else if (checksum_path.exists())
                     && file_path_in_root.exists()
-                    && !ctx.cli_opts.force
+                    && !(ctx.cli_opts.force || ctx.state.dry_run)
                 {
                     continue;
                 }
             }
 
-            if file.is_index() {
-                if let MetadataFile::SumFile(..) = &file {
-                    add_by_hash = false;
+            // compressed variants that were not reproduced when their index was patched stay out
+            // of the mirror for as long as the index is current
+            if ctx.cli_opts.apply_pdiffs && !changed.contains(&file.canonical_path()) {
+                let mut file_in_root = file.clone();
+                *file_in_root.path_mut() = file_path_in_root;
+
+                if file_in_root.is_left_out_variant() {
+                    continue;
                 }
+            }
 
-                *file.path_mut() = file_path_in_tmp.clone();
-                metadata.push(file);
+            // indices that can be patched wait for their diff index to be downloaded
+            if patchable.contains(&file.canonical_path()) {
+                deferred
+                    .entry(file.canonical_path())
+                    .or_default()
+                    .push((file, file_entry));
+                continue;
             }
 
-            let download = ctx.state.repo.create_metadata_download(
-                url,
-                file_path_in_tmp,
-                file_entry,
-                add_by_hash,
-            )?;
-            ctx.state.downloader.queue(download).await?;
+            queue_metadata(&ctx, file, file_entry, by_hash, &mut metadata).await?;
         }
 
         ctx.progress.wait_for_completion(&progress_bar).await;
@@ -112,17 +141,68 @@ impl Step<MirrorState> for DownloadMetadata {
             });
         }
 
+        if !deferred.is_empty() {
+            for (canonical_path, variants) in deferred {
+                let written = match apply_pdiffs(&ctx, &canonical_path, &variants, by_hash).await {
+                    Ok(written) => {
+                        output.total_indices_patched += 1;
+                        Some(written)
+                    }
+                    Err(e) => {
+                        println!(
+                            "{} WARNING: downloading {canonical_path} in full, its pdiffs could not be applied: {e}",
+                            crate::now()
+                        );
+                        None
+                    }
+                };
+
+                for (mut file, file_entry) in variants {
+                    let url = ctx.state.repo.to_url_in_dist(file.as_ref());
+
+                    match &written {
+                        Some(written) if written.contains(file.path()) => {
+                            *file.path_mut() = ctx.state.repo.to_path_in_tmp(&url);
+                            metadata.push(file);
+                        }
+                        // a variant that compressing the patched index does not reproduce is
+                        // left out, rather than downloaded in full, and its old version removed
+                        Some(_) => output
+                            .delete_paths
+                            .push(ctx.state.repo.to_path_in_root(&url)),
+                        None => {
+                            queue_metadata(&ctx, file, file_entry, by_hash, &mut metadata).await?
+                        }
+                    }
+                }
+            }
+
+            // patches that fail their checksum are fallen back from, rather than failing
+            let failed_patches = ctx.progress.files.failed();
+
+            ctx.progress.wait_for_completion(&progress_bar).await;
+
+            if ctx.progress.files.failed() > failed_patches {
+                return Err(MirsError::InconsistentRepository {
+                    progress: ctx.progress.files.clone(),
+                });
+            }
+        }
+
         verify_and_prune(&mut metadata);
 
         output.indices = deduplicate_metadata(metadata);
 
-        output.total_bytes_downloaded += ctx.progress.bytes.success();
+        output.add_transfers(&ctx.progress);
 
         if output.is_empty() {
             let result = if output.new_release {
                 MirrorResult::IrrelevantChanges
             } else {
-                MirrorResult::ReleaseUnchangedButIncomplete
+                // optional variants of indices that are not found do not count as errors
+                MirrorResult::ReleaseUnchangedButIncomplete {
+                    num_skipped: ctx.progress.errors(),
+                }
             };
 
             return Ok(StepResult::End(result));
@@ -131,3 +211,240 @@ impl Step<MirrorState> for DownloadMetadata {
         Ok(StepResult::Continue)
     }
 }
+
+async fn queue_metadata(
+    ctx: &Context<MirrorState>,
+    mut file: MetadataFile,
+    file_entry: FileEntry,
+    by_hash: bool,
+    metadata: &mut Vec<MetadataFile>,
+) -> Result<()> {
+    let mut add_by_hash = by_hash;
+    let url = ctx.state.repo.to_url_in_dist(file.as_ref());
+
+    let file_path_in_tmp = ctx.state.repo.to_path_in_tmp(&url);
+
+    if file.is_index() {
+        if let MetadataFile::SumFile(..) = &file {
+            add_by_hash = false;
+        }
+
+        *file.path_mut() = file_path_in_tmp.clone();
+        metadata.push(file);
+    }
+
+    let download =
+        ctx.state
+            .repo
+            .create_metadata_download(url, file_path_in_tmp, file_entry, add_by_hash)?;
+
+    ctx.state.downloader.queue(download).await
+}
+
+/// The canonical paths of the Packages and Sources indices that have a diff index, and whose
+/// uncompressed checksum the release lists, so that a patched index can be verified.
+fn patchable_indices(entries: &[(MetadataFile, FileEntry)]) -> HashSet<FilePath> {
+    let paths = entries
+        .iter()
+        .map(|(file, _)| file.path().as_str())
+        .collect::<HashSet<_>>();
+
+    entries
+        .iter()
+        .filter(|(file, _)| matches!(file, MetadataFile::Packages(..) | MetadataFile::Sources(..)))
+        .map(|(file, _)| file.canonical_path())
+        .filter(|path| {
+            paths.contains(path.as_str()) && paths.contains(format!("{path}.diff/Index").as_str())
+        })
+        .collect()
+}
+
+/// Patches the previously mirrored version of an index up to the one in the release, with the
+/// patches from its diff index, and writes it to tmp. The compressed variants are written as
+/// well, if compressing the patched index reproduces them, and are left out otherwise. Returns
+/// the variants that were written, and an error if the index has to be downloaded in full.
+async fn apply_pdiffs(
+    ctx: &Context<MirrorState>,
+    canonical_path: &FilePath,
+    variants: &[(MetadataFile, FileEntry)],
+    by_hash: bool,
+) -> Result<Vec<FilePath>> {
+    if !variants
+        .iter()
+        .any(|(file, _)| file.path() == canonical_path)
+    {
+        return Err(MirsError::Pdiff {
+            msg: "the uncompressed index is unchanged".into(),
+        });
+    }
+
+    let repo = ctx.state.repo.clone();
+
+    let diff_index_path =
+        repo.to_path_in_tmp(&repo.to_url_in_dist(&format!("{canonical_path}.diff/Index")));
+
+    let canonical_url = repo.to_url_in_dist(canonical_path.as_str());
+
+    let Some(previous) = ["", ".xz", ".gz", ".bz2"]
+        .into_iter()
+        .map(|ext| repo.to_path_in_root(&format!("{canonical_url}{ext}")))
+        .find(FilePath::exists)
+    else {
+        return Err(MirsError::Pdiff {
+            msg: "there is no previous version of the index".into(),
+        });
+    };
+
+    let diff_dir = repo.strip_tmp_base(
+        diff_index_path
+            .parent()
+            .expect("diff indices should have parents"),
+    );
+    let canonical_path = canonical_path.clone();
+    let variants = variants
+        .iter()
+        .map(|(file, file_entry)| (file.path().clone(), *file_entry))
+        .collect::<Vec<_>>();
+    let downloader = ctx.state.downloader.clone();
+    let progress = ctx.progress.clone();
+
+    spawn_blocking(move || {
+        let async_handle = Handle::current();
+
+        let diff_index = DiffIndexFile::read(MetadataFile::DiffIndex(diff_index_path))?;
+
+        let content = read_all(&previous)?;
+
+        let patched = patch_to_current(&diff_index, content, |name| {
+            let Some((file_name, patch_entry)) = diff_index.download_for(name) else {
+                return Err(MirsError::Pdiff {
+                    msg: format_compact!("patch {name} can not be downloaded"),
+                });
+            };
+
+            // patches are only needed to build the index, so they stay in tmp
+            let url = repo.to_url_in_root(diff_dir.join(file_name).as_str());
+            let patch_path = repo.to_path_in_tmp(&url);
+
+            let mut dl =
+                repo.create_raw_download(patch_path.clone(), url, patch_entry.strongest_hash());
+            dl.size = Some(patch_entry.size);
+            // the patches rotate, so an old one can be gone by the time it is requested
+            dl.optional = true;
+
+            progress.files.inc_total(1);
+            async_handle.block_on(downloader.download(dl))?;
+
+            read_all(&patch_path)
+        })?;
+
+        let mut written = Vec::with_capacity(variants.len());
+
+        for (path, file_entry) in variants {
+            let content = if path == canonical_path {
+                if !matches_entry(&patched, &file_entry) {
+                    return Err(MirsError::Pdiff {
+                        msg: format_compact!("patched {canonical_path} does not match the release"),
+                    });
+                }
+
+                Cow::Borrowed(&patched)
+            } else {
+                match compress(&path, &patched)? {
+                    Some(compressed) if matches_entry(&compressed, &file_entry) => {
+                        Cow::Owned(compressed)
+                    }
+                    _ => continue,
+                }
+            };
+
+            let target = repo.to_path_in_tmp(&repo.to_url_in_dist(path.as_str()));
+
+            write_index(&content, &target, file_entry, by_hash)?;
+
+            written.push(path);
+        }
+
+        Ok(written)
+    })
+    .await?
+}
+
+/// Whether `content` is the file that `file_entry` in the release describes.
+fn matches_entry(content: &[u8], file_entry: &FileEntry) -> bool {
+    let Some(expected) = file_entry.strongest_hash() else {
+        return false;
+    };
+
+    let mut hasher = expected.create_hasher();
+    hasher.consume(content);
+
+    content.len() as u64 == file_entry.size && hasher.compute() == expected
+}
+
+/// Compresses `content` the way the variant at `path` is usually compressed, which only
+/// reproduces the variant if the repository used the same settings.
+fn compress(path: &FilePath, content: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut output = Vec::new();
+
+    match path.extension() {
+        Some("xz") => {
+            let mut encoder = xz2::write::XzEncoder::new(&mut output, 6);
+            encoder.write_all(content)?;
+            encoder.finish()?;
+        }
+        Some("gz") => {
+            // like gzip -9n, which leaves out the name and time of the file
+            let mut encoder = flate2::GzBuilder::new()
+                .operating_system(3)
+                .write(&mut output, flate2::Compression::best());
+            encoder.write_all(content)?;
+            encoder.finish()?;
+        }
+        Some("bz2") => {
+            let mut encoder = bzip2::write::BzEncoder::new(&mut output, bzip2::Compression::best());
+            encoder.write_all(content)?;
+            encoder.finish()?;
+        }
+        _ => return Ok(None),
+    }
+
+    Ok(Some(output))
+}
+
+/// Writes a patched index to `target` in tmp, along with its by-hash links.
+fn write_index(
+    content: &[u8],
+    target: &FilePath,
+    file_entry: FileEntry,
+    by_hash: bool,
+) -> Result<()> {
+    let (_, primary_path, symlink_paths) = file_entry.into_paths(target, by_hash)?;
+
+    if let Some(parent) = primary_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    std::fs::write(&primary_path, content)?;
+
+    for symlink_path in symlink_paths {
+        let symlink_dir = symlink_path.parent().expect("base dir needs to exist");
+
+        let rel_primary_path = pathdiff::diff_paths(&primary_path, symlink_dir)
+            .expect("all files will be in some relative path");
+
+        std::fs::create_dir_all(symlink_dir)?;
+        std::os::unix::fs::symlink(rel_primary_path, &symlink_path)?;
+    }
+
+    Ok(())
+}
+
+fn read_all(path: &FilePath) -> Result<Vec<u8>> {
+    let (mut reader, _) = create_reader(std::fs::File::open(path)?, path)?;
+
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content)?;
+
+    Ok(content)
+}
@@ -1,14 +1,19 @@
 use std::sync::{Arc, atomic::Ordering};
 
+use ahash::HashSet;
 use async_trait::async_trait;
+use compact_str::ToCompactString;
 use indicatif::MultiProgress;
 use tokio::{runtime::Handle, task::spawn_blocking};
 
 use crate::{
     context::Context,
     error::{MirsError, Result},
-    metadata::metadata_file::MetadataFile,
+    metadata::{
+        IndexFileEntryIterator, metadata_file::MetadataFile, package_filter::PackageFilter,
+    },
     progress::Progress,
+    quota::{Reservation, suite_usage},
     step::{Step, StepResult},
 };
 
@@ -31,36 +36,92 @@ impl Step<MirrorState> for DownloadFromPackageIndices {
     async fn execute(&self, ctx: Arc<Context<MirrorState>>) -> Result<StepResult<Self::Result>> {
         let mut output = ctx.state.output.lock().await;
 
-        let multi_bar = MultiProgress::new();
-
         let file_progress = Progress::new_with_step(0, "Processing indices");
         let dl_progress = ctx.state.downloader.progress();
 
-        let file_progress_bar = multi_bar.add(file_progress.create_processing_progress_bar().await);
-        let dl_progress_bar = multi_bar.add(dl_progress.create_download_progress_bar().await);
+        let file_progress_bar = ctx
+            .progress
+            .attach(file_progress.create_processing_progress_bar().await);
+        let dl_progress_bar = dl_progress.create_download_progress_bar().await;
+
+        // the two bars are drawn together, in the section of the repository if it has one
+        let multi_bar = MultiProgress::new();
+
+        let (file_progress_bar, dl_progress_bar) = match ctx.progress.section() {
+            Some(_) => (file_progress_bar, dl_progress_bar),
+            None => (
+                multi_bar.add(file_progress_bar),
+                multi_bar.add(dl_progress_bar),
+            ),
+        };
 
-        let packages_files = output
-            .take_metadata(|f| matches!(f, MetadataFile::Packages(..) | MetadataFile::Sources(..)))
-            .into_iter()
-            .map(MetadataFile::into_reader)
-            .collect::<Result<Vec<_>>>()?;
+        let packages_metadata = output
+            .take_metadata(|f| matches!(f, MetadataFile::Packages(..) | MetadataFile::Sources(..)));
 
-        file_progress.files.inc_total(packages_files.len() as u64);
+        // the indices are read twice, once to sum up what is missing and once to queue it
+        let packages_files = readers(&packages_metadata)?;
 
-        let total_size = packages_files.iter().map(|v| v.size()).sum();
+        file_progress
+            .files
+            .inc_total(packages_files.len() as u64 * 2);
+
+        let total_size: u64 = packages_files.iter().map(|v| v.size()).sum();
         let mut incremental_size_base = 0;
 
-        file_progress.bytes.inc_total(total_size);
+        file_progress.bytes.inc_total(total_size * 2);
+
+        let mut filter = PackageFilter::new(&ctx.state.opts)?;
 
-        let task_downloader = ctx.state.downloader.clone();
-        let task_repo = ctx.state.repo.clone();
+        let task_ctx = ctx.clone();
         let task_dl_progress_bar = dl_progress_bar.clone();
         let task_dl_progress = dl_progress.clone();
 
-        spawn_blocking(move || {
+        let _reservation = spawn_blocking(move || {
             let async_handle = Handle::current();
 
+            let downloader = &task_ctx.state.downloader;
+            let repo = &task_ctx.state.repo;
+
+            filter.resolve_dependencies(&task_ctx.state.opts, &[&repo.tmp_dir, &repo.root_dir])?;
+            filter.select_versions(&task_ctx.state.opts, &packages_metadata)?;
+
+            let mut required = 0;
+
+            // packages of architecture all are listed in the index of every architecture
+            let mut missing = HashSet::default();
+
             for packages_file in packages_files {
+                let counter = packages_file.counter();
+                let package_size = packages_file.size();
+
+                for package in packages_file {
+                    let package = package?;
+
+                    if filter.accept(&package) {
+                        let dl = repo.create_file_download(package);
+
+                        if async_handle.block_on(downloader.needs_downloading(&dl))
+                            && missing.insert(dl.primary_target_path.clone())
+                        {
+                            required += dl.size.unwrap_or(0);
+                        }
+                    }
+
+                    file_progress
+                        .bytes
+                        .set_success(counter.load(Ordering::SeqCst) + incremental_size_base);
+
+                    file_progress.update_for_bytes(&file_progress_bar);
+                }
+
+                incremental_size_base += package_size;
+            }
+
+            drop(missing);
+
+            let reservation = async_handle.block_on(check_capacity(&task_ctx, required))?;
+
+            for packages_file in readers(&packages_metadata)? {
                 let counter = packages_file.counter();
                 file_progress.update_for_bytes(&file_progress_bar);
                 let package_size = packages_file.size();
@@ -68,8 +129,10 @@ impl Step<MirrorState> for DownloadFromPackageIndices {
                 for package in packages_file {
                     let package = package?;
 
-                    let dl = task_repo.create_file_download(package);
-                    async_handle.block_on(async { task_downloader.queue(dl).await })?;
+                    if filter.accept(&package) {
+                        let dl = repo.create_file_download(package);
+                        async_handle.block_on(async { downloader.queue(dl).await })?;
+                    }
 
                     file_progress
                         .bytes
@@ -83,15 +146,45 @@ impl Step<MirrorState> for DownloadFromPackageIndices {
                 file_progress.update_for_bytes(&file_progress_bar);
             }
 
-            Ok::<(), MirsError>(())
+            Ok::<_, MirsError>(reservation)
         })
         .await??;
 
         dl_progress.wait_for_completion(&dl_progress_bar).await;
 
-        output.total_bytes_downloaded += ctx.progress.bytes.success();
+        output.add_transfers(&ctx.progress);
+        output.add_failures(&ctx.progress);
         output.total_packages_downloaded += ctx.progress.files.success();
 
         Ok(StepResult::Continue)
     }
 }
+
+fn readers(metadata: &[MetadataFile]) -> Result<Vec<Box<dyn IndexFileEntryIterator>>> {
+    metadata
+        .iter()
+        .cloned()
+        .map(MetadataFile::into_reader)
+        .collect()
+}
+
+/// Fails if the packages that are missing, `required` bytes of them, would take the suite past
+/// its quota, or do not fit in the free space of the output that the suites running at the same
+/// time have not claimed. The space is claimed until the returned reservation is dropped.
+async fn check_capacity(ctx: &Context<MirrorState>, required: u64) -> Result<Reservation> {
+    if let Some(quota) = ctx.state.opts.quota {
+        let required = suite_usage(&ctx.state.opts, &ctx.state.repo.root_dir).await? + required;
+
+        if required > quota {
+            return Err(MirsError::QuotaExceeded {
+                suite: ctx.state.opts.to_compact_string(),
+                required,
+                quota,
+            });
+        }
+    }
+
+    ctx.state
+        .reserved_space
+        .reserve(&ctx.cli_opts.output, required)
+}
@@ -1,11 +1,12 @@
 use std::{fs::File, sync::Arc};
 
 use async_trait::async_trait;
-use compact_str::format_compact;
+use compact_str::{ToCompactString, format_compact};
+use indicatif::ProgressBar;
 
 use crate::{
     context::Context,
-    downloader::{Download, time_from_atomic},
+    downloader::{Download, create_dirs, time_from_atomic},
     error::{MirsError, Result},
     metadata::{
         FilePath,
@@ -40,40 +41,17 @@ impl Step<MirrorState> for DownloadRelease {
 
         let progress_bar = ctx.progress.create_download_progress_bar().await;
 
-        let mut files = Vec::with_capacity(3);
-
-        ctx.progress.files.inc_total(3);
-
-        for file_url in ctx.state.repo.release_urls() {
-            let destination = ctx.state.repo.to_path_in_tmp(&file_url);
-
-            let dl = Box::new(Download {
-                primary_target_path: destination.clone(),
-                url: file_url,
-                checksum: None,
-                size: None,
-                symlink_paths: Vec::new(),
-                always_download: true,
-            });
-
-            ctx.state.downloader.download(dl).await;
-
-            ctx.progress.update_for_files(&progress_bar);
-
-            files.push(destination);
-        }
+        let files = if ctx.state.repo.mirror_urls.len() > 1 {
+            select_freshest_release(&ctx, &progress_bar).await?
+        } else {
+            download_release_files(&ctx, 0, &ctx.state.repo.tmp_dir, &progress_bar).await
+        };
 
         progress_bar.finish_using_style();
 
         let new_release = ReleaseFile::try_from(files.as_ref())?;
 
-        if ctx.state.opts.pgp_verify {
-            if ctx.state.repo.has_specified_pgp_key() {
-                ctx.state.repo.verify(&new_release)?;
-            } else {
-                ctx.state.pgp_key_store.verify(&new_release)?;
-            }
-        }
+        verify_release(&ctx, &new_release)?;
 
         let local_release = ctx.state.repo.tmp_to_root(new_release.release());
 
@@ -100,16 +78,21 @@ impl Step<MirrorState> for DownloadRelease {
         let file_progress = Progress::new_with_step(0, "Verifying existing");
         file_progress.bytes.inc_total(total_meta_size);
 
-        let processing_progress_bar = file_progress.create_processing_progress_bar().await;
+        let processing_progress_bar = ctx
+            .progress
+            .attach(file_progress.create_processing_progress_bar().await);
 
         let dist_root = FilePath(format_compact!(
             "{}/{}",
             ctx.state.repo.root_dir,
             ctx.state.opts.dist_part()
         ));
-        release
-            .prune_existing(dist_root.as_str(), file_progress.clone())
-            .await?;
+        // a dry run estimates from every index, not only the ones that changed
+        if !ctx.state.dry_run {
+            release
+                .prune_existing(dist_root.as_str(), file_progress.clone())
+                .await?;
+        }
 
         file_progress
             .wait_for_completion(&processing_progress_bar)
@@ -155,13 +138,135 @@ impl Step<MirrorState> for DownloadRelease {
             }
         }
 
-        output.total_bytes_downloaded += ctx.progress.bytes.success();
+        output.add_transfers(&ctx.progress);
         output.release = Some(release);
 
         Ok(StepResult::Continue)
     }
 }
 
+async fn download_release_files(
+    ctx: &Context<MirrorState>,
+    mirror: usize,
+    base_dir: &FilePath,
+    progress_bar: &ProgressBar,
+) -> Vec<FilePath> {
+    let mut files = Vec::with_capacity(3);
+
+    ctx.progress.files.inc_total(3);
+
+    for file_url in ctx.state.repo.release_urls() {
+        let destination = ctx.state.repo.to_path_in_local_dir(base_dir, &file_url);
+
+        let dl = Box::new(Download {
+            primary_target_path: destination.clone(),
+            url: ctx.state.repo.to_mirror_url(&file_url, mirror),
+            fallback_urls: Vec::new(),
+            checksum: None,
+            size: None,
+            symlink_paths: Vec::new(),
+            always_download: true,
+            // a repository has an InRelease, a Release with its signature, or both
+            optional: true,
+            bandwidth_limiter: ctx.state.repo.bandwidth_limiter.clone(),
+        });
+
+        // a missing release file is sorted out once all of them have been tried
+        _ = ctx.state.downloader.download(dl).await;
+
+        ctx.progress.update_for_files(progress_bar);
+
+        files.push(destination);
+    }
+
+    files
+}
+
+fn verify_release(ctx: &Context<MirrorState>, release: &ReleaseFile) -> Result<()> {
+    if ctx.state.opts.pgp_verify {
+        if ctx.state.repo.has_specified_pgp_key() {
+            ctx.state.repo.verify(release)?;
+        } else {
+            ctx.state.pgp_key_store.verify(release)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads the release from every mirror of the repository and keeps the newest one that can
+/// be verified. Mirrors can lag behind, so the one with the latest Date is used, and the rest of
+/// the run prefers that mirror.
+async fn select_freshest_release(
+    ctx: &Context<MirrorState>,
+    progress_bar: &ProgressBar,
+) -> Result<Vec<FilePath>> {
+    let candidates_dir = ctx.state.repo.tmp_dir.join(".mirrors");
+
+    let mut freshest: Option<(usize, u64, Vec<FilePath>)> = None;
+
+    for mirror in 0..ctx.state.repo.mirror_urls.len() {
+        let base_dir = candidates_dir.join(mirror.to_compact_string());
+
+        let files = download_release_files(ctx, mirror, &base_dir, progress_bar).await;
+
+        let release_time = match candidate_release_time(ctx, &files).await {
+            Ok(release_time) => release_time,
+            Err(e) => {
+                println!(
+                    "{} WARNING: ignoring release from {}: {e}",
+                    crate::now(),
+                    ctx.state.repo.mirror_urls[mirror]
+                );
+                continue;
+            }
+        };
+
+        if freshest
+            .as_ref()
+            .is_none_or(|(_, freshest_time, _)| release_time > *freshest_time)
+        {
+            freshest = Some((mirror, release_time, files));
+        }
+    }
+
+    let Some((mirror, _, candidate_files)) = freshest else {
+        _ = tokio::fs::remove_dir_all(&candidates_dir).await;
+        return Err(MirsError::NoReleaseFile);
+    };
+
+    ctx.state.repo.set_active_mirror(mirror);
+
+    let mut files = Vec::with_capacity(candidate_files.len());
+
+    for (file_url, candidate) in ctx.state.repo.release_urls().iter().zip(candidate_files) {
+        let destination = ctx.state.repo.to_path_in_tmp(file_url);
+
+        if candidate.exists() {
+            create_dirs(&destination).await?;
+            tokio::fs::rename(&candidate, &destination).await?;
+        }
+
+        files.push(destination);
+    }
+
+    tokio::fs::remove_dir_all(&candidates_dir).await?;
+
+    Ok(files)
+}
+
+async fn candidate_release_time(ctx: &Context<MirrorState>, files: &[FilePath]) -> Result<u64> {
+    let release_file = ReleaseFile::try_from(files)?;
+
+    verify_release(ctx, &release_file)?;
+
+    let release = Release::parse(release_file.release(), &ctx.state.opts)
+        .await
+        .map_err(|e| MirsError::InvalidReleaseFile { inner: Box::new(e) })?;
+
+    Ok(release.release_time().unwrap_or_default())
+}
+
 pub enum ReleaseFile<'a> {
     Detached {
         release: &'a FilePath,
@@ -248,6 +248,28 @@ impl KeyStore for PgpKeyStore {
     }
 }
 
+pub fn load_public_key(value: &str) -> Result<SignedPublicKey> {
+    if value
+        .trim_start()
+        .starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----")
+    {
+        read_inline_public_key(value)
+    } else {
+        read_public_key(&FilePath::from(value))
+    }
+}
+
+pub fn read_inline_public_key(armored: &str) -> Result<SignedPublicKey> {
+    let (signed_public_key, _) =
+        SignedPublicKey::from_string(armored).map_err(|e| MirsError::PgpInlinePubKey {
+            inner: Box::new(e.into()),
+        })?;
+
+    signed_public_key.verify()?;
+
+    Ok(signed_public_key)
+}
+
 pub fn read_public_key(path: &FilePath) -> Result<SignedPublicKey> {
     let key_file = std::fs::File::open(path).map_err(|e| MirsError::PgpPubKey {
         path: path.clone(),
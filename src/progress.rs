@@ -1,15 +1,15 @@
 use std::{
     fmt::Display,
     sync::{
-        Arc,
+        Arc, OnceLock,
         atomic::{AtomicU8, AtomicU64, Ordering},
     },
     time::Duration,
 };
 
-use compact_str::ToCompactString;
+use compact_str::{CompactString, ToCompactString};
 use console::{pad_str, style};
-use indicatif::{HumanBytes, ProgressBar, ProgressFinish, ProgressStyle};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressFinish, ProgressStyle};
 use tokio::{sync::Mutex, time::sleep};
 
 #[derive(Clone, Default)]
@@ -20,6 +20,14 @@ pub struct Progress {
     pub bytes: ProgressPart,
     pub total_bytes: Arc<AtomicU64>,
     total_steps: Arc<AtomicU8>,
+    retries: Arc<AtomicU64>,
+    deduplicated_bytes: Arc<AtomicU64>,
+    deduplicated_files: Arc<AtomicU64>,
+    seeded_bytes: Arc<AtomicU64>,
+    errors: Arc<AtomicU64>,
+    failed_urls: Arc<std::sync::Mutex<Vec<CompactString>>>,
+    corrupt_urls: Arc<std::sync::Mutex<Vec<CompactString>>>,
+    section: Arc<OnceLock<ProgressSection>>,
 }
 
 impl Progress {
@@ -31,6 +39,14 @@ impl Progress {
             bytes: ProgressPart::new(),
             total_bytes: Arc::new(AtomicU64::new(0)),
             total_steps: Arc::new(AtomicU8::new(4)),
+            retries: Arc::new(AtomicU64::new(0)),
+            deduplicated_bytes: Arc::new(AtomicU64::new(0)),
+            deduplicated_files: Arc::new(AtomicU64::new(0)),
+            seeded_bytes: Arc::new(AtomicU64::new(0)),
+            errors: Arc::new(AtomicU64::new(0)),
+            failed_urls: Default::default(),
+            corrupt_urls: Default::default(),
+            section: Arc::new(OnceLock::new()),
         }
     }
 
@@ -42,6 +58,14 @@ impl Progress {
             bytes: ProgressPart::new(),
             total_bytes: Arc::new(AtomicU64::new(0)),
             total_steps: Arc::new(AtomicU8::new(4)),
+            retries: Arc::new(AtomicU64::new(0)),
+            deduplicated_bytes: Arc::new(AtomicU64::new(0)),
+            deduplicated_files: Arc::new(AtomicU64::new(0)),
+            seeded_bytes: Arc::new(AtomicU64::new(0)),
+            errors: Arc::new(AtomicU64::new(0)),
+            failed_urls: Default::default(),
+            corrupt_urls: Default::default(),
+            section: Arc::new(OnceLock::new()),
         }
     }
 
@@ -77,59 +101,84 @@ impl Progress {
     pub async fn create_processing_progress_bar(&self) -> ProgressBar {
         let prefix = self.create_prefix_stepless().await;
 
-        ProgressBar::new(self.bytes.total())
-            .with_style(
-                ProgressStyle::default_bar()
-                    .template("{prefix} [{wide_bar:.green/dim}] [{percent}%]")
-                    .expect("template string should follow the syntax")
-                    .progress_chars("###"),
-            )
-            .with_finish(ProgressFinish::AndLeave)
-            .with_prefix(prefix)
+        self.attach(
+            ProgressBar::new(self.bytes.total())
+                .with_style(
+                    ProgressStyle::default_bar()
+                        .template("{prefix} [{wide_bar:.green/dim}] [{percent}%]")
+                        .expect("template string should follow the syntax")
+                        .progress_chars("###"),
+                )
+                .with_finish(ProgressFinish::AndLeave)
+                .with_prefix(prefix),
+        )
     }
 
     pub async fn create_download_progress_bar(&self) -> ProgressBar {
         let prefix = self.create_prefix().await;
 
-        ProgressBar::new(self.files.total())
-            .with_style(
-                ProgressStyle::default_bar()
-                    .template(
-                        "{prefix} [{wide_bar:.cyan/dim}] {pos}/{len} [{elapsed_precise}] [{msg}]",
-                    )
-                    .expect("template string should follow the syntax")
-                    .progress_chars("###"),
-            )
-            .with_finish(ProgressFinish::AndLeave)
-            .with_prefix(prefix)
+        self.attach(
+            ProgressBar::new(self.files.total())
+                .with_style(
+                    ProgressStyle::default_bar()
+                        .template(
+                            "{prefix} [{wide_bar:.cyan/dim}] {pos}/{len} [{elapsed_precise}] [{msg}]",
+                        )
+                        .expect("template string should follow the syntax")
+                        .progress_chars("###"),
+                )
+                .with_finish(ProgressFinish::AndLeave)
+                .with_prefix(prefix),
+        )
     }
 
     pub async fn create_unbounded_progress_bar(&self) -> ProgressBar {
         let prefix = self.create_prefix().await;
 
-        ProgressBar::new(self.files.total())
-            .with_style(
-                ProgressStyle::default_bar()
-                    .template("{prefix} [{elapsed_precise}] {pos}/{len} [{msg}]")
-                    .expect("template string should follow the syntax")
-                    .progress_chars("###"),
-            )
-            .with_finish(ProgressFinish::AndLeave)
-            .with_prefix(prefix)
+        self.attach(
+            ProgressBar::new(self.files.total())
+                .with_style(
+                    ProgressStyle::default_bar()
+                        .template("{prefix} [{elapsed_precise}] {pos}/{len} [{msg}]")
+                        .expect("template string should follow the syntax")
+                        .progress_chars("###"),
+                )
+                .with_finish(ProgressFinish::AndLeave)
+                .with_prefix(prefix),
+        )
     }
 
     pub async fn create_count_progress_bar(&self) -> ProgressBar {
         let prefix = self.create_prefix().await;
 
-        ProgressBar::new(self.files.total())
-            .with_style(
-                ProgressStyle::default_bar()
-                    .template("{prefix} [{wide_bar:.cyan/dim}] [{elapsed_precise}] [{msg}]")
-                    .expect("template string should follow the syntax")
-                    .progress_chars("###"),
-            )
-            .with_finish(ProgressFinish::AndLeave)
-            .with_prefix(prefix)
+        self.attach(
+            ProgressBar::new(self.files.total())
+                .with_style(
+                    ProgressStyle::default_bar()
+                        .template("{prefix} [{wide_bar:.cyan/dim}] [{elapsed_precise}] [{msg}]")
+                        .expect("template string should follow the syntax")
+                        .progress_chars("###"),
+                )
+                .with_finish(ProgressFinish::AndLeave)
+                .with_prefix(prefix),
+        )
+    }
+
+    /// Draws the progress bars of this progress in `section`, for when several repositories
+    /// are processed at the same time.
+    pub fn set_section(&self, section: ProgressSection) {
+        _ = self.section.set(section);
+    }
+
+    pub fn section(&self) -> Option<&ProgressSection> {
+        self.section.get()
+    }
+
+    pub fn attach(&self, progress_bar: ProgressBar) -> ProgressBar {
+        match self.section.get() {
+            Some(section) => section.add(progress_bar),
+            None => progress_bar,
+        }
     }
 
     pub fn update_for_count(&self, progress_bar: &ProgressBar) {
@@ -150,9 +199,92 @@ impl Progress {
         progress_bar.set_message(HumanBytes(self.bytes.success()).to_compact_string());
     }
 
+    pub fn inc_retries(&self, count: u64) {
+        self.retries.fetch_add(count, Ordering::SeqCst);
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::SeqCst)
+    }
+
+    /// Counts a file of `bytes` that was linked from the dedup store instead of downloaded.
+    pub fn inc_deduplicated(&self, bytes: u64) {
+        self.deduplicated_bytes.fetch_add(bytes, Ordering::SeqCst);
+        self.deduplicated_files.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn deduplicated(&self) -> u64 {
+        self.deduplicated_bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn deduplicated_files(&self) -> u64 {
+        self.deduplicated_files.load(Ordering::SeqCst)
+    }
+
+    pub fn inc_seeded(&self, bytes: u64) {
+        self.seeded_bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn seeded(&self) -> u64 {
+        self.seeded_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Counts a file that was skipped because it could not be downloaded, as opposed to one that
+    /// was skipped because it was already there.
+    pub fn inc_errors(&self, count: u64) {
+        self.errors.fetch_add(count, Ordering::SeqCst);
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::SeqCst)
+    }
+
+    /// Records a url or path that could not be downloaded or read, for the report.
+    pub fn add_failed_url(&self, url: &str) {
+        self.failed_urls
+            .lock()
+            .expect("progress lock poisoned")
+            .push(url.into());
+    }
+
+    pub fn failed_urls(&self) -> Vec<CompactString> {
+        self.failed_urls
+            .lock()
+            .expect("progress lock poisoned")
+            .clone()
+    }
+
+    /// Records a url or path whose content did not match its checksum, for the report.
+    pub fn add_corrupt_url(&self, url: &str) {
+        self.corrupt_urls
+            .lock()
+            .expect("progress lock poisoned")
+            .push(url.into());
+    }
+
+    pub fn corrupt_urls(&self) -> Vec<CompactString> {
+        self.corrupt_urls
+            .lock()
+            .expect("progress lock poisoned")
+            .clone()
+    }
+
     pub fn reset(&self) {
         self.bytes.reset();
         self.files.reset();
+        self.retries.store(0, Ordering::SeqCst);
+        self.deduplicated_bytes.store(0, Ordering::SeqCst);
+        self.deduplicated_files.store(0, Ordering::SeqCst);
+        self.seeded_bytes.store(0, Ordering::SeqCst);
+        self.errors.store(0, Ordering::SeqCst);
+        self.failed_urls
+            .lock()
+            .expect("progress lock poisoned")
+            .clear();
+        self.corrupt_urls
+            .lock()
+            .expect("progress lock poisoned")
+            .clear();
         self.step.store(0, Ordering::SeqCst);
         self.total_steps.store(5, Ordering::SeqCst);
     }
@@ -166,6 +298,19 @@ impl Progress {
 
         self.bytes.reset();
         self.files.reset();
+        self.retries.store(0, Ordering::SeqCst);
+        self.deduplicated_bytes.store(0, Ordering::SeqCst);
+        self.deduplicated_files.store(0, Ordering::SeqCst);
+        self.seeded_bytes.store(0, Ordering::SeqCst);
+        self.errors.store(0, Ordering::SeqCst);
+        self.failed_urls
+            .lock()
+            .expect("progress lock poisoned")
+            .clear();
+        self.corrupt_urls
+            .lock()
+            .expect("progress lock poisoned")
+            .clear();
 
         self.step.fetch_add(1, Ordering::SeqCst);
     }
@@ -185,6 +330,60 @@ impl Progress {
     }
 }
 
+/// The progress bars of one repository, under a line with its name, in a `MultiProgress`
+/// shared with the other repositories that are processed at the same time.
+#[derive(Clone)]
+pub struct ProgressSection {
+    multi: MultiProgress,
+    bars: Arc<std::sync::Mutex<Vec<ProgressBar>>>,
+}
+
+impl ProgressSection {
+    pub fn new(multi: &MultiProgress, title: String) -> Self {
+        let header = multi.add(
+            ProgressBar::new(0)
+                .with_style(
+                    ProgressStyle::default_bar()
+                        .template("{msg}")
+                        .expect("template string should follow the syntax"),
+                )
+                .with_message(style(title).bold().to_string()),
+        );
+
+        header.tick();
+
+        Self {
+            multi: multi.clone(),
+            bars: Arc::new(std::sync::Mutex::new(vec![header])),
+        }
+    }
+
+    pub fn add(&self, progress_bar: ProgressBar) -> ProgressBar {
+        let mut bars = self.bars.lock().expect("progress section lock poisoned");
+
+        let progress_bar = match bars.last() {
+            Some(last) => self.multi.insert_after(last, progress_bar),
+            None => self.multi.add(progress_bar),
+        };
+
+        bars.push(progress_bar.clone());
+
+        progress_bar
+    }
+
+    /// Removes the section, once the repository is done.
+    pub fn clear(&self) {
+        for bar in self
+            .bars
+            .lock()
+            .expect("progress section lock poisoned")
+            .drain(..)
+        {
+            self.multi.remove(&bar);
+        }
+    }
+}
+
 #[derive(Clone, Default, Debug)]
 pub struct ProgressPart {
     total: Arc<AtomicU64>,
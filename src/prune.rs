@@ -11,18 +11,22 @@ use tokio::sync::Mutex;
 use crate::error::Result;
 use crate::{
     CliOpts,
-    cmd::{CmdResult, CmdState},
+    cmd::{CmdResult, CmdState, Outcome, ResultSummary},
     config::MirrorOpts,
     context::Context,
     error::MirsError,
     metadata::{FilePath, repository::Repository},
     progress::Progress,
+    snapshot::SNAPSHOTS_DIR,
     step::Step,
+    storage::SharedStorage,
 };
 
 mod delete;
 mod inventory;
 
+pub use inventory::add_referenced_files;
+
 pub type PruneDynStep = Box<dyn Step<PruneState, Result = PruneResult>>;
 pub type PruneContext = Arc<Context<PruneState>>;
 
@@ -33,6 +37,8 @@ pub enum PruneResult {
         valid_bytes: u64,
         deleted_files: u64,
         deleted_bytes: u64,
+        /// The suites that reference more than their quota, even after pruning.
+        over_quota: Vec<MirsError>,
     },
     Error(MirsError),
 }
@@ -45,17 +51,45 @@ impl Display for PruneResult {
                 valid_bytes,
                 deleted_files,
                 deleted_bytes,
-            } => f.write_fmt(format_args!(
-                "Ok: valid {valid_files} ({}), pruned {deleted_files} ({})",
-                HumanBytes(*valid_bytes),
-                HumanBytes(*deleted_bytes)
-            )),
+                over_quota,
+            } => {
+                f.write_fmt(format_args!(
+                    "Ok: valid {valid_files} ({}), pruned {deleted_files} ({})",
+                    HumanBytes(*valid_bytes),
+                    HumanBytes(*deleted_bytes)
+                ))?;
+
+                for e in over_quota {
+                    f.write_fmt(format_args!(", {e}"))?;
+                }
+
+                Ok(())
+            }
             PruneResult::Error(e) => f.write_fmt(format_args!("Fail: {e}")),
         }
     }
 }
 
-impl CmdResult for PruneResult {}
+impl CmdResult for PruneResult {
+    fn summary(&self) -> ResultSummary {
+        match self {
+            PruneResult::Pruned {
+                deleted_bytes,
+                over_quota,
+                ..
+            } => ResultSummary {
+                outcome: if over_quota.is_empty() {
+                    Outcome::Ok
+                } else {
+                    Outcome::Incomplete
+                },
+                bytes: Some(*deleted_bytes),
+                ..ResultSummary::ok()
+            },
+            PruneResult::Error(..) => ResultSummary::failed(),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct PruneState {
@@ -63,6 +97,7 @@ pub struct PruneState {
     pub output: Arc<Mutex<PruneOutput>>,
     pub exclude_paths: Vec<FilePath>,
     pub dry_run: bool,
+    pub storage: SharedStorage,
 }
 
 impl Display for PruneState {
@@ -104,6 +139,7 @@ pub struct PruneOutput {
     pub total_valid_bytes: u64,
     pub total_deleted: u64,
     pub total_deleted_bytes: u64,
+    pub over_quota: Vec<MirsError>,
 }
 
 #[async_trait]
@@ -111,13 +147,14 @@ impl CmdState for PruneState {
     type Result = PruneResult;
 
     async fn finalize(&self) -> Self::Result {
-        let output = self.output.lock().await;
+        let mut output = self.output.lock().await;
 
         PruneResult::Pruned {
             valid_files: output.total_valid,
             valid_bytes: output.total_valid_bytes,
             deleted_files: output.total_deleted,
             deleted_bytes: output.total_deleted_bytes,
+            over_quota: std::mem::take(&mut output.over_quota),
         }
     }
 
@@ -166,7 +203,7 @@ impl Context<PruneState> {
                 .root_dir
                 .as_str();
 
-            let exclude: Vec<FilePath> = mirrors
+            let mut exclude: Vec<FilePath> = mirrors
                 .iter()
                 .map(|v| {
                     v.first()
@@ -179,6 +216,9 @@ impl Context<PruneState> {
                 .map(FilePath::from)
                 .collect();
 
+            // snapshots are only removed by deleting them
+            exclude.push(FilePath::from(root_dir).join(format!("{SNAPSHOTS_DIR}/")));
+
             exclude_paths[i] = exclude;
         }
 
@@ -196,6 +236,7 @@ impl Context<PruneState> {
                             mirrors,
                             exclude_paths,
                             dry_run,
+                            storage: cli_opts.storage(),
                             ..Default::default()
                         },
                         cli_opts.clone(),
@@ -209,3 +250,45 @@ impl Context<PruneState> {
         Ok(ctxs)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use crate::{cmd::Cmd, pgp::PgpKeyStore, prune::*, testing::TestDir};
+
+    #[tokio::test]
+    async fn files_of_snapshots_are_kept() {
+        let dir = TestDir::new("prune");
+        dir.write_suite("debian", "trixie", &["hello"]);
+
+        let root_dir = dir.join("debian");
+        let opts = MirrorOpts::try_from(
+            "deb [arch=amd64 short_name=debian] http://a.example/debian trixie main",
+        )
+        .unwrap();
+
+        crate::snapshot::create_snapshot(&opts, &root_dir, "1")
+            .await
+            .unwrap();
+
+        // the suite moves on from hello, which only the snapshot references now
+        dir.write_suite("debian", "trixie", &["world"]);
+        dir.write("debian/pool/main/stray.deb", "stray");
+
+        let cli_opts = CliOpts::parse_from(["aptmirs", "--output", dir.as_str()]);
+
+        Cmd::Prune { dry_run: false }
+            .execute(
+                vec![opts],
+                Arc::new(cli_opts),
+                Arc::new(PgpKeyStore::default()),
+            )
+            .await
+            .unwrap();
+
+        assert!(root_dir.join("pool/main/hello_1.0_amd64.deb").exists());
+        assert!(root_dir.join("pool/main/world_1.0_amd64.deb").exists());
+        assert!(!root_dir.join("pool/main/stray.deb").exists());
+    }
+}
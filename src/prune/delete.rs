@@ -2,14 +2,13 @@ use std::sync::Arc;
 
 use ahash::HashMap;
 use async_trait::async_trait;
-use tokio::fs::remove_file;
-use walkdir::WalkDir;
 
 use crate::error::Result;
 use crate::{
     context::Context,
     error::MirsError,
     metadata::FilePath,
+    publish::{current_generation, to_published_path},
     step::{Step, StepResult},
 };
 
@@ -40,44 +39,42 @@ impl Step<PruneState> for Delete {
 
         let mut output = ctx.state.output.lock().await;
 
-        for entry in WalkDir::new(&repo.root_dir).into_iter().filter_entry(|v| {
-            let path = v.path().as_os_str().to_str().expect("path should be utf8");
+        let storage = &ctx.state.storage;
 
-            !ctx.state
-                .exclude_paths
-                .iter()
-                .any(|excl| path.starts_with(excl.as_str()))
-        }) {
-            let entry = entry?;
+        let generation = current_generation(&repo.root_dir)?;
 
-            if entry.file_type().is_dir() {
+        for full_path in storage
+            .list(&repo.root_dir, &ctx.state.exclude_paths)
+            .await?
+        {
+            let Some(meta) = storage.stat(&full_path).await? else {
                 continue;
-            }
+            };
 
-            let path = repo.strip_root(
-                entry
-                    .path()
-                    .as_os_str()
-                    .to_str()
-                    .expect("path should be utf8"),
-            );
+            // only the generation of dists that clients see is pruned
+            let Some(path) = to_published_path(repo.strip_root(full_path.as_str()), generation)
+            else {
+                continue;
+            };
+            let path = path.as_str();
 
-            let size = entry.metadata()?.len();
+            // symlinks whose target is gone are always deleted
+            let dangling = meta.is_symlink && !storage.exists(&full_path).await?;
 
             ctx.progress.files.inc_total(1);
 
-            if should_delete(&output.files, &entry, path, size)? {
+            if dangling || should_delete(&output.files, path, meta.size) {
                 ctx.progress.files.inc_success(1);
-                ctx.progress.bytes.inc_success(size);
+                ctx.progress.bytes.inc_success(meta.size);
 
                 if ctx.state.dry_run {
                     eprintln!("{path}");
                 } else {
-                    remove_file(repo.root_dir.join(path)).await?;
+                    storage.remove(&full_path).await?;
                 }
             } else {
                 ctx.progress.files.inc_skipped(1);
-                ctx.progress.bytes.inc_skipped(size);
+                ctx.progress.bytes.inc_skipped(meta.size);
             }
 
             ctx.progress.update_for_files(&progress_bar);
@@ -94,39 +91,18 @@ impl Step<PruneState> for Delete {
     }
 }
 
-fn should_delete(
-    valid_files: &HashMap<FilePath, Option<u64>>,
-    entry: &walkdir::DirEntry,
-    path: &str,
-    size: u64,
-) -> Result<bool> {
-    if entry.path_is_symlink() {
-        let mut real_path = std::fs::read_link(entry.path())?;
-
-        if !real_path.starts_with("/") {
-            real_path = entry
-                .path()
-                .parent()
-                .expect("all links should point from parent folders")
-                .join(&real_path);
-        }
-
-        if !real_path.exists() {
-            return Ok(true);
-        }
-    }
-
+fn should_delete(valid_files: &HashMap<FilePath, Option<u64>>, path: &str, size: u64) -> bool {
     if size == 0 {
         if let Some(expected_size) = valid_files.get(path) {
             if let Some(expected_size) = expected_size
                 && *expected_size != 0
             {
-                return Ok(true);
+                return true;
             }
         } else {
-            return Ok(true);
+            return true;
         }
     }
 
-    Ok(!valid_files.contains_key(path))
+    !valid_files.contains_key(path)
 }
@@ -2,21 +2,25 @@ use std::sync::{Arc, atomic::Ordering};
 
 use ahash::HashMap;
 use async_trait::async_trait;
-use compact_str::format_compact;
+use compact_str::{ToCompactString, format_compact};
+use indicatif::ProgressBar;
 
 use crate::error::Result;
 use crate::metadata::repository::{get_rooted_release_files, pick_release};
 use crate::{
+    config::MirrorOpts,
     context::Context,
     error::MirsError,
     metadata::{
         FilePath,
         metadata_file::{MetadataFile, deduplicate_metadata},
+        package_filter::PackageFilter,
         release::{FileEntry, Release},
-        repository::Repository,
     },
     mirror::verify_and_prune,
     progress::Progress,
+    quota::pool_usage,
+    snapshot::snapshot_roots,
     step::{Step, StepResult},
 };
 
@@ -42,97 +46,167 @@ impl Step<PruneState> for Inventory {
 
         let progress_bar = progress.create_count_progress_bar().await;
 
-        let mut incremental_size_base = 0;
-
         for (opts, repo) in &ctx.state.mirrors {
-            let dist_root = FilePath(format_compact!("{}/{}", repo.root_dir, opts.dist_part()));
+            // the files of a suite with a quota are collected on their own, to measure them
+            if let Some(quota) = opts.quota {
+                let mut files = HashMap::default();
+                add_referenced_files(opts, &repo.root_dir, &progress, &progress_bar, &mut files)
+                    .await?;
+
+                let used = pool_usage(opts, &repo.root_dir, &files);
+
+                // every file that the suite references is still needed by it, so a suite above
+                // its quota is only reported, not pruned down to it
+                if used > quota {
+                    state.over_quota.push(MirsError::QuotaExceeded {
+                        suite: opts.to_compact_string(),
+                        required: used,
+                        quota,
+                    });
+                }
 
-            let release_files = get_rooted_release_files(&dist_root);
+                state.files.extend(files);
+            } else {
+                add_referenced_files(
+                    opts,
+                    &repo.root_dir,
+                    &progress,
+                    &progress_bar,
+                    &mut state.files,
+                )
+                .await?;
+            }
 
-            let Some(release_file) = pick_release(&release_files) else {
-                return Err(MirsError::NoReleaseFile);
-            };
+            // files that a retained snapshot references are kept, even when the suite itself
+            // has moved on
+            for snapshot_root in snapshot_roots(&repo.root_dir)? {
+                if snapshot_root.join(opts.dist_part()).exists() {
+                    add_referenced_files(
+                        opts,
+                        &snapshot_root,
+                        &progress,
+                        &progress_bar,
+                        &mut state.files,
+                    )
+                    .await?;
+                }
+            }
+        }
 
-            let release = Release::parse(release_file, opts).await?;
+        progress_bar.finish_using_style();
 
-            let by_hash = release.acquire_by_hash();
+        Ok(StepResult::Continue)
+    }
+}
 
-            let mut metadata: Vec<(MetadataFile, FileEntry)> = release.into_iter().collect();
+/// Adds every file that the release of the suite in `root_dir` references, metadata and
+/// packages alike, to `files`. The paths are relative to `root_dir`.
+pub async fn add_referenced_files(
+    opts: &MirrorOpts,
+    root_dir: &FilePath,
+    progress: &Progress,
+    progress_bar: &ProgressBar,
+    files: &mut HashMap<FilePath, Option<u64>>,
+) -> Result<()> {
+    let dist_root = FilePath(format_compact!("{}/{}", root_dir, opts.dist_part()));
 
-            for f in release_files {
-                add_valid_metadata_file(&progress, &mut state.files, &f, None, repo);
-            }
+    let release_files = get_rooted_release_files(&dist_root);
 
-            for (metadata_file, file_entry) in &mut metadata {
-                metadata_file.prefix_with(dist_root.as_str());
+    let Some(release_file) = pick_release(&release_files) else {
+        return Err(MirsError::NoReleaseFile);
+    };
 
-                let size = file_entry.size;
-                let (_, primary, other) = file_entry.into_paths(metadata_file.path(), by_hash)?;
+    let release = Release::parse(release_file, opts).await?;
 
-                add_valid_metadata_file(&progress, &mut state.files, &primary, Some(size), repo);
+    let mut filter = PackageFilter::new(opts)?;
+    filter.resolve_dependencies(opts, &[root_dir])?;
 
-                for f in other {
-                    add_valid_metadata_file(&progress, &mut state.files, &f, Some(size), repo);
-                }
-            }
+    let by_hash = release.acquire_by_hash();
 
-            let mut metadata = metadata
-                .into_iter()
-                .map(|(v, _)| v)
-                .filter(MetadataFile::is_index)
-                .collect();
+    let mut metadata: Vec<(MetadataFile, FileEntry)> = release.into_iter().collect();
 
-            verify_and_prune(&mut metadata);
+    for f in release_files {
+        add_valid_metadata_file(progress, files, &f, None, root_dir);
+    }
 
-            let metadata = deduplicate_metadata(metadata);
+    for (metadata_file, file_entry) in &mut metadata {
+        metadata_file.prefix_with(dist_root.as_str());
 
-            let index_files = metadata
-                .into_iter()
-                .map(MetadataFile::into_reader)
-                .collect::<Result<Vec<_>>>()?;
+        let size = file_entry.size;
+        let (_, primary, other) = file_entry.into_paths(metadata_file.path(), by_hash)?;
 
-            let total_size = index_files.iter().map(|v| v.size()).sum();
-            progress.bytes.inc_total(total_size);
+        add_valid_metadata_file(progress, files, &primary, Some(size), root_dir);
 
-            for meta_file in index_files {
-                let counter = meta_file.counter();
-                let meta_file_size = meta_file.size();
+        for f in other {
+            add_valid_metadata_file(progress, files, &f, Some(size), root_dir);
+        }
+    }
 
-                let base_path = match meta_file.file() {
-                    MetadataFile::Packages(..) | MetadataFile::Sources(..) => FilePath::from(""),
-                    MetadataFile::SumFile(file_path) | MetadataFile::DiffIndex(file_path) => {
-                        FilePath::from(
-                            repo.strip_root(
-                                file_path
-                                    .parent()
-                                    .expect("diff indicies should have parents"),
-                            ),
-                        )
-                    }
-                    MetadataFile::Other(..) => unreachable!(),
-                };
+    let mut metadata = metadata
+        .into_iter()
+        .map(|(v, _)| v)
+        .filter(MetadataFile::is_index)
+        .collect();
 
-                for entry in meta_file {
-                    let entry = entry?;
+    verify_and_prune(&mut metadata);
 
-                    let path = base_path.join(entry.path);
+    let metadata = deduplicate_metadata(metadata);
 
-                    add_valid_file(&progress, &mut state.files, path, entry.size);
+    filter.select_versions(opts, &metadata)?;
 
-                    progress
-                        .bytes
-                        .set_success(counter.load(Ordering::SeqCst) + incremental_size_base);
+    let index_files = metadata
+        .into_iter()
+        .map(MetadataFile::into_reader)
+        .collect::<Result<Vec<_>>>()?;
 
-                    progress.update_for_count(&progress_bar);
-                }
+    let total_size = index_files.iter().map(|v| v.size()).sum();
+    progress.bytes.inc_total(total_size);
+
+    for meta_file in index_files {
+        let counter = meta_file.counter();
+        let incremental_size_base = progress.bytes.success();
+
+        let base_path = match meta_file.file() {
+            MetadataFile::Packages(..) | MetadataFile::Sources(..) => FilePath::from(""),
+            MetadataFile::SumFile(file_path) | MetadataFile::DiffIndex(file_path) => {
+                FilePath::from(strip_root(
+                    root_dir,
+                    file_path
+                        .parent()
+                        .expect("diff indicies should have parents"),
+                ))
+            }
+            MetadataFile::Other(..) => unreachable!(),
+        };
+
+        for entry in meta_file {
+            let entry = entry?;
+
+            if filter.accept(&entry) {
+                let path = base_path.join(entry.path);
 
-                incremental_size_base += meta_file_size;
+                add_valid_file(progress, files, path, entry.size);
             }
+
+            progress
+                .bytes
+                .set_success(counter.load(Ordering::SeqCst) + incremental_size_base);
+
+            progress.update_for_count(progress_bar);
         }
+    }
 
-        progress_bar.finish_using_style();
+    Ok(())
+}
 
-        Ok(StepResult::Continue)
+fn strip_root<'a>(root_dir: &FilePath, path: &'a str) -> &'a str {
+    let Some(path) = path.strip_prefix(root_dir.as_str()) else {
+        return path;
+    };
+
+    match path.strip_prefix('/') {
+        Some(p) => p,
+        None => path,
     }
 }
 
@@ -141,9 +215,9 @@ fn add_valid_metadata_file(
     files: &mut HashMap<FilePath, Option<u64>>,
     file: &FilePath,
     size: Option<u64>,
-    repo: &Repository,
+    root_dir: &FilePath,
 ) {
-    let path = repo.strip_root(file.as_str());
+    let path = strip_root(root_dir, file.as_str());
 
     add_valid_file(progress, files, path.into(), size);
 }
@@ -0,0 +1,507 @@
+use std::{collections::BTreeMap, ffi::CString, path::Path, sync::Arc};
+
+use compact_str::{CompactString, format_compact};
+use walkdir::WalkDir;
+
+use crate::{
+    CliOpts,
+    cmd::{Outcome, exit_code},
+    config::MirrorOpts,
+    error::{MirsError, Result},
+    log,
+    metadata::{FilePath, repository::Repository},
+};
+
+/// The name of the `dists` folder clients fetch from. With atomic publishing it is a symlink to
+/// the current generation, `dists.<generation>`.
+pub const DISTS_DIR: &str = "dists";
+
+/// The metadata of a mirrored suite that waits to be published in the next generation of dists.
+pub struct StagedSuite {
+    /// The name of the suite in the results of the run.
+    pub name: String,
+    pub opts: Arc<MirrorOpts>,
+    pub tmp_dir: FilePath,
+    pub delete_paths: Vec<FilePath>,
+    /// The snapshot to take of the suite once it is published.
+    pub snapshot: Option<CompactString>,
+}
+
+/// Collects the suites of a mirror run that finished, by the mirror they are in, so that every
+/// mirror gets one new generation of dists with all of its suites once the run is done. Clones
+/// share the suites.
+#[derive(Clone, Default)]
+pub struct Publisher {
+    staged: Arc<std::sync::Mutex<BTreeMap<FilePath, Vec<StagedSuite>>>>,
+    /// The number of previous generations to keep.
+    pub keep: usize,
+}
+
+impl Publisher {
+    pub fn new(keep: usize) -> Self {
+        Self {
+            keep,
+            ..Default::default()
+        }
+    }
+
+    pub fn stage(&self, root_dir: &FilePath, suite: StagedSuite) {
+        self.staged
+            .lock()
+            .expect("publisher lock poisoned")
+            .entry(root_dir.clone())
+            .or_default()
+            .push(suite);
+    }
+
+    /// Takes the staged suites, by the root dir of their mirror.
+    pub fn take(&self) -> BTreeMap<FilePath, Vec<StagedSuite>> {
+        std::mem::take(&mut self.staged.lock().expect("publisher lock poisoned"))
+    }
+}
+
+/// Publishes the metadata of the suites of a mirror in `tmp_dirs` as a new generation of
+/// `dists`. The current generation is hardlinked into the new one, the new metadata is moved
+/// over it, and the `dists` symlink is switched to it with a single rename, so a client never
+/// sees a mix of old and new metadata. All but the `keep` latest previous generations are
+/// removed.
+///
+/// A generation is built from hardlinks and switched to with the rename of a symlink, which
+/// only the local file system can do, so this works on the files directly instead of through
+/// the storage.
+pub async fn publish(
+    root_dir: FilePath,
+    tmp_dirs: Vec<FilePath>,
+    delete_paths: Vec<FilePath>,
+    keep: usize,
+) -> Result<u32> {
+    tokio::task::spawn_blocking(move || {
+        let current = current_generation(&root_dir)?;
+        let generation = generations(&root_dir)?.last().map_or(1, |v| v + 1);
+
+        let dists = root_dir.join(DISTS_DIR);
+        let new_dists = generation_dir(&root_dir, generation);
+
+        if dists.exists() {
+            link_tree(&dists, &new_dists)?;
+        } else {
+            std::fs::create_dir_all(&new_dists)?;
+        }
+
+        for path in delete_paths {
+            let Some(rel_path) = dists_relative(&root_dir, path.as_str()) else {
+                remove_if_exists(&path)?;
+                continue;
+            };
+
+            remove_if_exists(&new_dists.join(rel_path))?;
+        }
+
+        for tmp_dir in &tmp_dirs {
+            move_from_tmp(&root_dir, tmp_dir, &new_dists)?;
+        }
+
+        // a dists folder from before atomic publishing was enabled becomes the first generation
+        if current.is_none() && dists.exists() {
+            replace_dists_dir(&root_dir, generation)?;
+        } else {
+            switch_to(&root_dir, generation)?;
+        }
+        remove_old_generations(&root_dir, generation, keep)?;
+
+        Ok(generation)
+    })
+    .await?
+}
+
+/// Switches `dists` back to the generation before the current one, or to `generation`.
+pub fn rollback(root_dir: &FilePath, generation: Option<u32>) -> Result<u32> {
+    let Some(current) = current_generation(root_dir)? else {
+        return Err(MirsError::Publish {
+            path: root_dir.clone(),
+            msg: "dists is not published atomically".into(),
+        });
+    };
+
+    let generations = generations(root_dir)?;
+
+    let target = match generation {
+        Some(generation) if generations.contains(&generation) => generation,
+        Some(generation) => {
+            return Err(MirsError::Publish {
+                path: root_dir.clone(),
+                msg: format_compact!("there is no generation {generation}"),
+            });
+        }
+        None => generations
+            .into_iter()
+            .rev()
+            .find(|v| *v < current)
+            .ok_or_else(|| MirsError::Publish {
+                path: root_dir.clone(),
+                msg: "there is no previous generation".into(),
+            })?,
+    };
+
+    switch_to(root_dir, target)?;
+
+    Ok(target)
+}
+
+/// Rolls back every configured mirror, and returns the exit code for them.
+pub async fn rollback_all(
+    opts: Vec<MirrorOpts>,
+    cli_opts: &CliOpts,
+    generation: Option<u32>,
+) -> Result<i32> {
+    let mut root_dirs = opts
+        .iter()
+        .map(|opts| Ok(Repository::build(opts, cli_opts)?.root_dir))
+        .collect::<Result<Vec<_>>>()?;
+
+    root_dirs.sort();
+    root_dirs.dedup();
+
+    let mut outcomes = Vec::with_capacity(root_dirs.len());
+
+    for root_dir in root_dirs {
+        match rollback(&root_dir, generation) {
+            Ok(generation) => {
+                log(format!(
+                    "Switched {root_dir}/dists to generation {generation}"
+                ));
+                outcomes.push(Outcome::Ok);
+            }
+            Err(e) => {
+                log(format!("Rolling back {root_dir}: Fail: {e}"));
+                outcomes.push(Outcome::Failed);
+            }
+        }
+    }
+
+    Ok(exit_code(&outcomes))
+}
+
+/// The generation that `dists` points to, if it is published atomically.
+pub fn current_generation(root_dir: &FilePath) -> Result<Option<u32>> {
+    let dists = root_dir.join(DISTS_DIR);
+
+    match std::fs::read_link(&dists) {
+        Ok(target) => Ok(target
+            .to_str()
+            .and_then(parse_generation)
+            .filter(|_| target.parent() == Some(Path::new("")))),
+        Err(e)
+            if e.kind() == std::io::ErrorKind::NotFound
+                || e.kind() == std::io::ErrorKind::InvalidInput =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// All generations of `dists` in the mirror, oldest first.
+pub fn generations(root_dir: &FilePath) -> Result<Vec<u32>> {
+    if !root_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut generations = Vec::new();
+
+    for entry in std::fs::read_dir(root_dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir()
+            && let Some(generation) = entry.file_name().to_str().and_then(parse_generation)
+        {
+            generations.push(generation);
+        }
+    }
+
+    generations.sort();
+
+    Ok(generations)
+}
+
+/// Maps a path relative to the mirror root to the path that clients see it as, for the files
+/// in the current generation. Other generations, and the `dists` symlink itself, are not seen
+/// by clients and are left alone.
+pub fn to_published_path(path: &str, current: Option<u32>) -> Option<String> {
+    let Some(current) = current else {
+        return Some(path.to_string());
+    };
+
+    if path == DISTS_DIR {
+        return None;
+    }
+
+    let Some((first, rest)) = path.split_once('/') else {
+        return Some(path.to_string());
+    };
+
+    match parse_generation(first) {
+        Some(generation) if generation == current => Some(format!("{DISTS_DIR}/{rest}")),
+        Some(_) => None,
+        None => Some(path.to_string()),
+    }
+}
+
+fn parse_generation(name: &str) -> Option<u32> {
+    name.strip_prefix(DISTS_DIR)?
+        .strip_prefix('.')?
+        .parse()
+        .ok()
+}
+
+fn generation_dir(root_dir: &FilePath, generation: u32) -> FilePath {
+    root_dir.join(format!("{DISTS_DIR}.{generation}"))
+}
+
+fn dists_relative<'a>(root_dir: &FilePath, path: &'a str) -> Option<&'a str> {
+    path.strip_prefix(root_dir.as_str())?
+        .trim_start_matches('/')
+        .strip_prefix(DISTS_DIR)?
+        .strip_prefix('/')
+}
+
+/// Points `dists` at `generation`, by renaming a new symlink over it.
+fn switch_to(root_dir: &FilePath, generation: u32) -> Result<()> {
+    let link = create_link(root_dir, generation)?;
+
+    std::fs::rename(&link, root_dir.join(DISTS_DIR))?;
+
+    Ok(())
+}
+
+/// Replaces a `dists` folder from before atomic publishing with a symlink to `generation`, and
+/// keeps the folder as generation 0. A symlink can not be renamed over a folder, so the two are
+/// exchanged in one step instead, where the file system supports it, so that there always is a
+/// `dists` for clients.
+fn replace_dists_dir(root_dir: &FilePath, generation: u32) -> Result<()> {
+    let link = create_link(root_dir, generation)?;
+    let dists = root_dir.join(DISTS_DIR);
+
+    if exchange(&link, &dists).is_ok() {
+        // the folder now has the name of the link
+        std::fs::rename(&link, generation_dir(root_dir, 0))?;
+        return Ok(());
+    }
+
+    remove_if_exists(&link)?;
+
+    std::fs::rename(&dists, generation_dir(root_dir, 0))?;
+    switch_to(root_dir, generation)
+}
+
+fn create_link(root_dir: &FilePath, generation: u32) -> Result<FilePath> {
+    let link = root_dir.join(format!(".{DISTS_DIR}.link"));
+
+    remove_if_exists(&link)?;
+
+    std::os::unix::fs::symlink(format!("{DISTS_DIR}.{generation}"), &link)?;
+
+    Ok(link)
+}
+
+/// Swaps the names of two paths in a single step.
+#[cfg(target_os = "linux")]
+fn exchange(a: &FilePath, b: &FilePath) -> std::io::Result<()> {
+    let a = CString::new(a.as_str())?;
+    let b = CString::new(b.as_str())?;
+
+    // SAFETY: both paths are nul terminated and outlive the call, which only reads them
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_: &FilePath, _: &FilePath) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+fn remove_old_generations(root_dir: &FilePath, current: u32, keep: usize) -> Result<()> {
+    let previous = generations(root_dir)?
+        .into_iter()
+        .filter(|v| *v != current)
+        .collect::<Vec<_>>();
+
+    for generation in &previous[..previous.len().saturating_sub(keep)] {
+        std::fs::remove_dir_all(generation_dir(root_dir, *generation))?;
+    }
+
+    Ok(())
+}
+
+/// Recreates the tree in `from` at `to`, hardlinking files and copying symlinks.
+fn link_tree(from: &FilePath, to: &FilePath) -> Result<()> {
+    // the walk starts at the generation itself, rather than at the dists symlink to it
+    let from = FilePath::from(std::fs::canonicalize(from)?);
+
+    for entry in WalkDir::new(&from) {
+        let entry = entry?;
+
+        let path = FilePath::from(entry.path());
+        let target = to.join(
+            path.as_str()
+                .strip_prefix(from.as_str())
+                .expect("walked paths should be in the walked dir"),
+        );
+
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(&path)?, &target)?;
+        } else {
+            std::fs::hard_link(&path, &target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the downloaded metadata from `tmp_dir` into the new generation of dists.
+fn move_from_tmp(root_dir: &FilePath, tmp_dir: &FilePath, new_dists: &FilePath) -> Result<()> {
+    if !tmp_dir.exists() {
+        return Ok(());
+    }
+
+    for entry in WalkDir::new(tmp_dir) {
+        let entry = entry?;
+
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        let path = FilePath::from(entry.path());
+        let rel_path = path
+            .as_str()
+            .strip_prefix(tmp_dir.as_str())
+            .expect("implemention error; path should be in tmp");
+
+        // flat repositories have no dists, and are published in place
+        let target = match dists_relative(root_dir, root_dir.join(rel_path).as_str()) {
+            Some(rel_path) => new_dists.join(rel_path),
+            None => root_dir.join(rel_path),
+        };
+
+        move_file(&path, &target)?;
+    }
+
+    Ok(())
+}
+
+fn move_file(from: &FilePath, to: &FilePath) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // renaming across file systems fails, in which case the file is copied instead
+    if std::fs::rename(from, to).is_err() {
+        remove_if_exists(to)?;
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+fn remove_if_exists(path: &FilePath) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{publish::*, testing::TestDir};
+
+    #[test]
+    fn published_paths_of_generations() {
+        assert_eq!(
+            to_published_path("dists.3/trixie/InRelease", Some(3)).as_deref(),
+            Some("dists/trixie/InRelease")
+        );
+        assert_eq!(to_published_path("dists.2/trixie/InRelease", Some(3)), None);
+        assert_eq!(to_published_path("dists", Some(3)), None);
+        assert_eq!(
+            to_published_path("pool/main/h/hello.deb", Some(3)).as_deref(),
+            Some("pool/main/h/hello.deb")
+        );
+        assert_eq!(
+            to_published_path("dists/trixie/InRelease", None).as_deref(),
+            Some("dists/trixie/InRelease")
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_switches_generations() {
+        let root = TestDir::new("publish");
+        let root_dir = root.join("mirror");
+        let tmp_dir = root.join("tmp");
+
+        // a dists folder from before atomic publishing
+        root.write("mirror/dists/trixie/Release", "old");
+        root.write("mirror/dists/trixie/Packages", "old");
+
+        let dists = root_dir.join(DISTS_DIR);
+
+        root.write("tmp/dists/trixie/Release", "new");
+        let first = publish(
+            root_dir.clone(),
+            vec![tmp_dir.clone()],
+            vec![root_dir.join("dists/trixie/Packages")],
+            1,
+        )
+        .await
+        .unwrap();
+
+        let read = |path: &str| std::fs::read_to_string(root_dir.join(path)).ok();
+
+        assert_eq!(first, 1);
+        assert!(dists.exists());
+        assert!(!root_dir.join(".dists.link").exists());
+        assert_eq!(current_generation(&root_dir).unwrap(), Some(1));
+        assert_eq!(read("dists/trixie/Release").as_deref(), Some("new"));
+        assert_eq!(read("dists/trixie/Packages"), None);
+        assert_eq!(read("dists.0/trixie/Packages").as_deref(), Some("old"));
+
+        // the suites of a mirror are published together
+        root.write("tmp/dists/trixie/Release", "newer");
+        root.write("tmp-updates/dists/trixie-updates/Release", "new");
+        publish(
+            root_dir.clone(),
+            vec![tmp_dir.clone(), root.join("tmp-updates")],
+            Vec::new(),
+            1,
+        )
+        .await
+        .unwrap();
+
+        assert!(dists.exists());
+        assert_eq!(generations(&root_dir).unwrap(), vec![1, 2]);
+        assert_eq!(read("dists/trixie/Release").as_deref(), Some("newer"));
+        assert_eq!(read("dists/trixie-updates/Release").as_deref(), Some("new"));
+
+        assert_eq!(rollback(&root_dir, None).unwrap(), 1);
+        assert!(dists.exists());
+        assert_eq!(read("dists/trixie/Release").as_deref(), Some("new"));
+        assert!(rollback(&root_dir, None).is_err());
+        assert!(dists.exists());
+    }
+}
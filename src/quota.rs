@@ -0,0 +1,180 @@
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ahash::HashMap;
+use indicatif::ProgressBar;
+
+use crate::{
+    config::{MirrorOpts, parse_size},
+    error::{MirsError, Result},
+    metadata::FilePath,
+    progress::Progress,
+    prune::add_referenced_files,
+};
+
+/// Parses a quota in bytes, with an optional K, M, G or T suffix (powers of 1024).
+pub fn parse_quota(value: &str) -> std::result::Result<u64, String> {
+    parse_size(value, "quota")
+}
+
+/// The space available to unprivileged users on the file system that `path` is on. The path
+/// does not have to exist yet, the closest folder above it that does is used instead.
+pub fn available_space(path: &FilePath) -> Result<u64> {
+    let mut path = Path::new(path.as_str());
+
+    while !path.exists() {
+        let Some(parent) = path.parent() else {
+            break;
+        };
+
+        path = parent;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    // SAFETY: statvfs is a plain struct of integers, for which all zeroes is a valid value
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: the path is nul terminated and outlives the call, and statvfs only writes into
+    // the struct it is given, which is valid for writes
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// The free space that suites mirroring at the same time have claimed for their packages, so
+/// that each of them only counts on what the others have not claimed. Clones share the claims.
+#[derive(Clone, Default)]
+pub struct SpaceReservations {
+    reserved: Arc<Mutex<u64>>,
+}
+
+/// A claim on free space, which is given back when it is dropped.
+pub struct Reservation {
+    reservations: SpaceReservations,
+    bytes: u64,
+}
+
+impl SpaceReservations {
+    /// Claims `bytes` of the free space of the file system that `path` is on. A suite holds the
+    /// claim until its downloads are done, so the space it has filled by then is counted twice,
+    /// which errs on the side of caution.
+    pub fn reserve(&self, path: &FilePath, bytes: u64) -> Result<Reservation> {
+        let mut reserved = self
+            .reserved
+            .lock()
+            .expect("space reservations lock poisoned");
+
+        let available = available_space(path)?.saturating_sub(*reserved);
+
+        if bytes > available {
+            return Err(MirsError::InsufficientSpace {
+                path: path.clone(),
+                required: bytes,
+                available,
+            });
+        }
+
+        *reserved += bytes;
+
+        Ok(Reservation {
+            reservations: self.clone(),
+            bytes,
+        })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self
+            .reservations
+            .reserved
+            .lock()
+            .expect("space reservations lock poisoned") -= self.bytes;
+    }
+}
+
+/// The bytes of the packages and source files that the suite in `root_dir` references. A
+/// quota applies to these, since the metadata of a suite is small and replaced on every run.
+pub async fn suite_usage(opts: &MirrorOpts, root_dir: &FilePath) -> Result<u64> {
+    let mut files = HashMap::default();
+
+    match add_referenced_files(
+        opts,
+        root_dir,
+        &Progress::new(),
+        &ProgressBar::hidden(),
+        &mut files,
+    )
+    .await
+    {
+        Ok(()) => (),
+        // a suite that has not been mirrored yet uses nothing
+        Err(MirsError::NoReleaseFile) => return Ok(0),
+        Err(e) => return Err(e),
+    }
+
+    Ok(pool_usage(opts, root_dir, &files))
+}
+
+/// Sums the sizes of the files outside of the metadata of the suite that exist in `root_dir`.
+pub fn pool_usage(
+    opts: &MirrorOpts,
+    root_dir: &FilePath,
+    files: &HashMap<FilePath, Option<u64>>,
+) -> u64 {
+    let dist_part = opts.dist_part();
+
+    files
+        .iter()
+        .filter(|(path, _)| dist_part.is_empty() || !path.as_str().starts_with(dist_part.as_str()))
+        .filter(|(path, _)| root_dir.join(path).exists())
+        .filter_map(|(_, size)| *size)
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::quota::*;
+
+    #[test]
+    fn parse_quota_with_suffixes() {
+        assert_eq!(parse_quota("4096"), Ok(4096));
+        assert_eq!(parse_quota("500M"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_quota("2t"), Ok(2 * 1024 * 1024 * 1024 * 1024));
+        assert!(parse_quota("0G").is_err());
+        assert!(parse_quota("lots").is_err());
+    }
+
+    #[test]
+    fn available_space_of_missing_folders() {
+        let path = FilePath::from(std::env::temp_dir().join("aptmirs-missing/a/b").as_path());
+
+        assert!(available_space(&path).unwrap() > 0);
+    }
+
+    #[test]
+    fn reservations_count_against_free_space() {
+        let path = FilePath::from(std::env::temp_dir().as_path());
+        let reservations = SpaceReservations::default();
+
+        let available = available_space(&path).unwrap();
+
+        let half = reservations.reserve(&path, available / 2).unwrap();
+
+        assert!(reservations.reserve(&path, available / 4 * 3).is_err());
+
+        drop(half);
+
+        assert!(reservations.reserve(&path, available / 4 * 3).is_ok());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{io::Write, time::Duration};
+
+use chrono::{DateTime, Local};
+use compact_str::CompactString;
+use serde::Serialize;
+
+use crate::{
+    cmd::{Cmd, CmdResult, Outcome, RepositoryRun},
+    error::Result,
+    metadata::FilePath,
+    progress::{Progress, ProgressPart},
+};
+
+/// A machine readable account of a command run, written with `--report-file`.
+#[derive(Serialize)]
+pub struct Report {
+    pub command: &'static str,
+    pub started: String,
+    pub finished: String,
+    pub exit_code: i32,
+    pub repositories: Vec<RepositoryReport>,
+}
+
+#[derive(Serialize)]
+pub struct RepositoryReport {
+    pub repository: String,
+    /// The folder of the repository in the output.
+    pub root_dir: Option<CompactString>,
+    pub outcome: Outcome,
+    pub status: String,
+    pub bytes: Option<u64>,
+    pub packages: Option<u64>,
+    pub failed: u64,
+    pub skipped: u64,
+    pub release_date: Option<String>,
+    #[serde(skip)]
+    pub release_time: Option<u64>,
+    pub steps: Vec<StepReport>,
+}
+
+#[derive(Serialize)]
+pub struct StepReport {
+    pub name: &'static str,
+    pub duration_secs: f64,
+    pub files: Counters,
+    pub bytes: Counters,
+    pub failed_urls: Vec<CompactString>,
+    pub corrupt_urls: Vec<CompactString>,
+}
+
+#[derive(Serialize)]
+pub struct Counters {
+    pub total: u64,
+    pub success: u64,
+    pub skipped: u64,
+    pub failed: u64,
+}
+
+impl From<&ProgressPart> for Counters {
+    fn from(value: &ProgressPart) -> Self {
+        Self {
+            total: value.total(),
+            success: value.success(),
+            skipped: value.skipped(),
+            failed: value.failed(),
+        }
+    }
+}
+
+impl StepReport {
+    /// Takes the counters of a step, which have to be read before the next step resets them.
+    pub fn new(name: &'static str, duration: Duration, progress: &Progress) -> Self {
+        Self {
+            name,
+            duration_secs: duration.as_secs_f64(),
+            files: Counters::from(&progress.files),
+            bytes: Counters::from(&progress.bytes),
+            failed_urls: progress.failed_urls(),
+            corrupt_urls: progress.corrupt_urls(),
+        }
+    }
+}
+
+impl<R: CmdResult> From<RepositoryRun<R>> for RepositoryReport {
+    fn from(run: RepositoryRun<R>) -> Self {
+        let summary = run.result.summary();
+
+        Self {
+            repository: run.name,
+            root_dir: run.root_dir.map(|v| v.0),
+            outcome: summary.outcome,
+            status: run.result.to_string(),
+            bytes: summary.bytes,
+            packages: summary.packages,
+            failed: summary.failed,
+            skipped: summary.skipped,
+            release_date: run
+                .release_time
+                .and_then(|v| DateTime::from_timestamp(v as i64, 0))
+                .map(|v| v.to_rfc3339()),
+            release_time: run.release_time,
+            steps: run.steps,
+        }
+    }
+}
+
+impl Report {
+    pub fn new(
+        cmd: &Cmd,
+        started: DateTime<Local>,
+        exit_code: i32,
+        repositories: Vec<RepositoryReport>,
+    ) -> Self {
+        Self {
+            command: match cmd {
+                Cmd::Mirror { .. } => "mirror",
+                Cmd::Verify => "verify",
+                Cmd::Prune { .. } => "prune",
+                Cmd::Snapshot { .. } => "snapshot",
+                Cmd::Rollback { .. } => "rollback",
+            },
+            started: started.to_rfc3339(),
+            finished: Local::now().to_rfc3339(),
+            exit_code,
+            repositories,
+        }
+    }
+
+    /// Writes the report next to `path` and renames it into place, so that a reader never sees
+    /// a partially written report.
+    pub fn write(&self, path: &FilePath) -> Result<()> {
+        let tmp_path = format!("{path}.tmp");
+
+        let mut output = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+
+        serde_json::to_writer_pretty(&mut output, self).map_err(std::io::Error::from)?;
+        writeln!(output)?;
+
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+
+        std::fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{error::MirsError, mirror::MirrorResult, report::*};
+
+    #[test]
+    fn repository_report_from_run() {
+        let progress = Progress::new();
+        progress.files.inc_total(2);
+        progress.files.inc_failed(1);
+        progress.add_corrupt_url("http://a.example/pool/p.deb");
+
+        let run = RepositoryRun {
+            name: String::from("deb http://a.example/debian trixie main"),
+            result: MirrorResult::Error(MirsError::NoReleaseFile),
+            steps: vec![StepReport::new(
+                "Downloading packages",
+                Duration::from_millis(1500),
+                &progress,
+            )],
+            release_time: Some(1_759_363_200),
+            root_dir: None,
+        };
+
+        let report = serde_json::to_value(RepositoryReport::from(run)).unwrap();
+
+        assert_eq!(report["outcome"], "failed");
+        assert_eq!(report["release_date"], "2025-10-02T00:00:00+00:00");
+        assert_eq!(report["steps"][0]["duration_secs"], 1.5);
+        assert_eq!(report["steps"][0]["files"]["failed"], 1);
+        assert_eq!(
+            report["steps"][0]["corrupt_urls"][0],
+            "http://a.example/pool/p.deb"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use ahash::{HashMap, HashMapExt};
+use tokio::{sync::Mutex, task::spawn_blocking};
+use walkdir::WalkDir;
+
+use crate::{
+    downloader::{Download, PartialDir, create_dirs},
+    error::Result,
+    metadata::{FilePath, checksum::Checksum},
+    storage::Storage,
+};
+
+/// An index of local files, like an old mirror or an apt cache, that downloads can be seeded
+/// from. Files are indexed by size up front, and only hashed once a download of the same size
+/// asks for them.
+pub struct SeedIndex {
+    by_size: HashMap<u64, Vec<FilePath>>,
+    checksums: Mutex<HashMap<FilePath, Vec<Checksum>>>,
+    hardlink: bool,
+}
+
+impl SeedIndex {
+    /// Indexes the files of `dirs` on a blocking task, since seed dirs can be large.
+    pub async fn build(dirs: Vec<FilePath>, hardlink: bool) -> Option<Self> {
+        if dirs.is_empty() {
+            return None;
+        }
+
+        match spawn_blocking(move || Self::build_blocking(&dirs, hardlink)).await {
+            Ok(index) => index,
+            Err(e) => {
+                println!("{} WARNING: failed indexing seed dirs: {e}", crate::now());
+                None
+            }
+        }
+    }
+
+    fn build_blocking(dirs: &[FilePath], hardlink: bool) -> Option<Self> {
+        if dirs.is_empty() {
+            return None;
+        }
+
+        let mut by_size: HashMap<u64, Vec<FilePath>> = HashMap::new();
+        let mut num_files = 0;
+
+        for dir in dirs {
+            for entry in WalkDir::new(dir).follow_links(true) {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        println!("{} WARNING: failed indexing {dir}: {e}", crate::now());
+                        continue;
+                    }
+                };
+
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+
+                if metadata.len() == 0 {
+                    continue;
+                }
+
+                by_size
+                    .entry(metadata.len())
+                    .or_default()
+                    .push(FilePath::from(entry.path()));
+
+                num_files += 1;
+            }
+        }
+
+        crate::log(format!("indexed {num_files} files to seed from"));
+
+        Some(Self {
+            by_size,
+            checksums: Mutex::new(HashMap::new()),
+            hardlink,
+        })
+    }
+
+    /// Puts a local file with the same size and checksum as the download in its target, as a
+    /// copy, or as a hardlink if that was asked for. Returns the number of bytes seeded.
+    pub async fn seed_into(
+        &self,
+        download: &Download,
+        partials: &PartialDir,
+        storage: &dyn Storage,
+    ) -> Result<Option<u64>> {
+        let (Some(size), Some(checksum)) = (download.size, &download.checksum) else {
+            return Ok(None);
+        };
+
+        let Some(candidates) = self.by_size.get(&size) else {
+            return Ok(None);
+        };
+
+        for candidate in candidates {
+            if !self.has_checksum(candidate, checksum).await? {
+                continue;
+            }
+
+            let target = &download.primary_target_path;
+
+            // copies go through the partial file, so that the target never has partial content
+            let partial = partials.for_target(target);
+            create_dirs(&partial.path).await?;
+
+            _ = tokio::fs::remove_file(&partial.path).await;
+
+            // a hardlink would share the inode with a file that is not ours, so that a change to
+            // the seed dir changes the mirror. Copies are reflinked where the file system can.
+            if !self.hardlink
+                || tokio::fs::hard_link(candidate, &partial.path)
+                    .await
+                    .is_err()
+            {
+                tokio::fs::copy(candidate, &partial.path).await?;
+            }
+
+            storage.put(&partial.path, target).await?;
+
+            return Ok(Some(size));
+        }
+
+        Ok(None)
+    }
+
+    async fn has_checksum(&self, path: &FilePath, checksum: &Checksum) -> Result<bool> {
+        if let Some(known) = self.checksums.lock().await.get(path)
+            && let Some(known) = known
+                .iter()
+                .find(|v| v.checksum_type() == checksum.checksum_type())
+        {
+            return Ok(known == checksum);
+        }
+
+        let computed = Checksum::checksum_file_with_hasher(path, checksum.create_hasher()).await?;
+
+        let matches = computed == *checksum;
+
+        self.checksums
+            .lock()
+            .await
+            .entry(path.clone())
+            .or_default()
+            .push(computed);
+
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{seed::*, storage::LocalStorage, testing::TestDir};
+
+    #[tokio::test]
+    async fn seeds_only_matching_checksums() {
+        let dir = TestDir::new("seed");
+        let seed_dir = dir.join("cache");
+        let output = dir.join("mirror");
+
+        dir.write("cache/a.deb", b"package-a");
+        dir.write("cache/b.deb", b"package-b");
+
+        let seeds = SeedIndex::build(vec![seed_dir.clone()], false)
+            .await
+            .unwrap();
+
+        let partials = PartialDir::new(&output.join(".tmp"), &output);
+
+        let download = Download {
+            url: "http://a.example/pool/b.deb".into(),
+            fallback_urls: Vec::new(),
+            size: Some(9),
+            checksum: Some(Checksum::Md5(md5::compute(b"package-b").0)),
+            primary_target_path: output.join("pool/b.deb"),
+            symlink_paths: Vec::new(),
+            always_download: false,
+            optional: false,
+            bandwidth_limiter: None,
+        };
+
+        assert_eq!(
+            seeds
+                .seed_into(&download, &partials, &LocalStorage)
+                .await
+                .unwrap(),
+            Some(9)
+        );
+        assert_eq!(
+            std::fs::read(&download.primary_target_path).unwrap(),
+            b"package-b"
+        );
+
+        // a copy does not share the inode with the seed dir
+        std::fs::write(seed_dir.join("b.deb"), b"changed-b").unwrap();
+
+        assert_eq!(
+            std::fs::read(&download.primary_target_path).unwrap(),
+            b"package-b"
+        );
+
+        let missing = Download {
+            checksum: Some(Checksum::Md5(md5::compute(b"package-c").0)),
+            ..download
+        };
+
+        assert_eq!(
+            seeds
+                .seed_into(&missing, &partials, &LocalStorage)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
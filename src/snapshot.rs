@@ -0,0 +1,399 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use ahash::HashMap;
+use clap::Subcommand;
+use compact_str::{CompactString, ToCompactString};
+use indicatif::{HumanBytes, ProgressBar};
+
+use crate::{
+    CliOpts,
+    cmd::{Outcome, exit_code},
+    config::MirrorOpts,
+    downloader::create_dirs,
+    error::{MirsError, Result},
+    log,
+    metadata::{FilePath, repository::Repository},
+    progress::Progress,
+    prune::add_referenced_files,
+};
+
+/// The folder in the root of a mirror that snapshots are kept in, one folder per snapshot.
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+#[derive(Subcommand, Clone)]
+pub enum SnapshotCmd {
+    /// Takes a snapshot of the configured suites as they are currently mirrored
+    Create,
+    /// Lists the snapshots of the configured mirrors
+    List,
+    /// Prints the files that were added or removed between two snapshots
+    Diff {
+        #[clap(help = "The id of the older snapshot")]
+        from: String,
+        #[clap(help = "The id of the newer snapshot")]
+        to: String,
+    },
+    /// Deletes a snapshot. Files that only the snapshot referenced are removed by the next prune
+    Delete {
+        #[clap(help = "The id of the snapshot to delete")]
+        id: String,
+    },
+}
+
+#[derive(Default)]
+pub struct SnapshotStats {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Snapshots are named after the time they were taken, so that they sort in order.
+pub fn new_snapshot_id() -> CompactString {
+    chrono::Utc::now()
+        .format("%Y%m%dT%H%M%SZ")
+        .to_compact_string()
+}
+
+/// Creates the folder of the snapshot `id` in each of `root_dirs`. Ids only have a resolution of
+/// a second, so this fails when a snapshot with the same id already exists, instead of merging
+/// two snapshots into one.
+pub fn reserve_snapshot(root_dirs: &BTreeSet<FilePath>, id: &str) -> Result<()> {
+    let mut reserved = BTreeSet::new();
+
+    for root_dir in root_dirs {
+        let snapshots_dir = root_dir.join(SNAPSHOTS_DIR);
+
+        let result = std::fs::create_dir_all(&snapshots_dir)
+            .and_then(|_| std::fs::create_dir(snapshots_dir.join(id)));
+
+        match result {
+            Ok(()) => {
+                reserved.insert(root_dir.clone());
+            }
+            Err(e) => {
+                remove_empty_snapshot(&reserved, id);
+
+                return Err(match e.kind() {
+                    std::io::ErrorKind::AlreadyExists => {
+                        MirsError::SnapshotExists { id: id.into() }
+                    }
+                    _ => e.into(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the folder of the snapshot `id` from the roots where no suite made it into it.
+pub fn remove_empty_snapshot(root_dirs: &BTreeSet<FilePath>, id: &str) {
+    for root_dir in root_dirs {
+        // only succeeds for an empty folder
+        _ = std::fs::remove_dir(root_dir.join(SNAPSHOTS_DIR).join(id));
+    }
+}
+
+/// The roots of all snapshots taken of the mirror in `root_dir`, oldest first.
+pub fn snapshot_roots(root_dir: &FilePath) -> Result<Vec<FilePath>> {
+    let snapshots_dir = root_dir.join(SNAPSHOTS_DIR);
+
+    if !snapshots_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut roots = Vec::new();
+
+    for entry in std::fs::read_dir(&snapshots_dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            roots.push(FilePath::from(entry.path().as_path()));
+        }
+    }
+
+    roots.sort();
+
+    Ok(roots)
+}
+
+/// Freezes the suite in `root_dir` into the snapshot `id`. The metadata of the suite, and every
+/// file it references, is hardlinked into `<root_dir>/snapshots/<id>`, which makes the snapshot
+/// a complete repository of its own without copying anything.
+pub async fn create_snapshot(
+    opts: &MirrorOpts,
+    root_dir: &FilePath,
+    id: &str,
+) -> Result<SnapshotStats> {
+    let snapshot_root = root_dir.join(SNAPSHOTS_DIR).join(id);
+
+    let mut files = HashMap::default();
+    add_referenced_files(
+        opts,
+        root_dir,
+        &Progress::new(),
+        &ProgressBar::hidden(),
+        &mut files,
+    )
+    .await?;
+
+    let mut stats = SnapshotStats::default();
+
+    for path in files.keys() {
+        let source = root_dir.join(path);
+        let target = snapshot_root.join(path);
+
+        // the release lists variants of indices that the mirror does not necessarily have
+        let Ok(metadata) = tokio::fs::symlink_metadata(&source).await else {
+            continue;
+        };
+
+        create_dirs(&target).await?;
+
+        let result = if metadata.is_symlink() {
+            let link = tokio::fs::read_link(&source).await?;
+            tokio::fs::symlink(link, &target).await
+        } else {
+            tokio::fs::hard_link(&source, &target).await
+        };
+
+        // suites in the same mirror share their pool, and are snapshotted into the same folder
+        match result {
+            Ok(()) => {
+                stats.files += 1;
+                stats.bytes += metadata.len();
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(stats)
+}
+
+impl SnapshotCmd {
+    pub async fn execute(&self, opts: Vec<MirrorOpts>, cli_opts: &CliOpts) -> Result<i32> {
+        let mirrors = opts
+            .into_iter()
+            .map(|opts| Ok((Repository::build(&opts, cli_opts)?.root_dir, opts)))
+            .collect::<Result<Vec<_>>>()?;
+
+        match self {
+            SnapshotCmd::Create => create(&mirrors).await,
+            SnapshotCmd::List => list(&mirrors),
+            SnapshotCmd::Diff { from, to } => diff(&mirrors, from, to).await,
+            SnapshotCmd::Delete { id } => delete(&mirrors, id).await,
+        }
+    }
+}
+
+async fn create(mirrors: &[(FilePath, MirrorOpts)]) -> Result<i32> {
+    let id = new_snapshot_id();
+
+    let root_dirs = mirrors
+        .iter()
+        .map(|(root_dir, _)| root_dir.clone())
+        .collect::<BTreeSet<_>>();
+
+    reserve_snapshot(&root_dirs, &id)?;
+
+    let mut outcomes = Vec::with_capacity(mirrors.len());
+
+    for (root_dir, opts) in mirrors {
+        match create_snapshot(opts, root_dir, &id).await {
+            Ok(stats) => {
+                log(format!(
+                    "Snapshot {id} of {opts}: {} files linked ({})",
+                    stats.files,
+                    HumanBytes(stats.bytes)
+                ));
+                outcomes.push(Outcome::Ok);
+            }
+            Err(e) => {
+                log(format!("Snapshot {id} of {opts}: Fail: {e}"));
+                outcomes.push(Outcome::Failed);
+            }
+        }
+    }
+
+    remove_empty_snapshot(&root_dirs, &id);
+
+    Ok(exit_code(&outcomes))
+}
+
+fn list(mirrors: &[(FilePath, MirrorOpts)]) -> Result<i32> {
+    let mut roots: BTreeMap<&FilePath, Vec<&MirrorOpts>> = BTreeMap::new();
+
+    for (root_dir, opts) in mirrors {
+        roots.entry(root_dir).or_default().push(opts);
+    }
+
+    for (root_dir, suites) in roots {
+        log(format!("Snapshots in {root_dir}:"));
+
+        for snapshot_root in snapshot_roots(root_dir)? {
+            let suites = suites
+                .iter()
+                .filter(|opts| snapshot_root.join(opts.dist_part()).exists())
+                .map(|opts| opts.suite.as_str())
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            println!("{}  {suites}", snapshot_root.file_name());
+        }
+    }
+
+    Ok(0)
+}
+
+async fn diff(mirrors: &[(FilePath, MirrorOpts)], from: &str, to: &str) -> Result<i32> {
+    let mut found = false;
+
+    for (root_dir, opts) in mirrors {
+        let from_root = snapshot_root(root_dir, from)?;
+        let to_root = snapshot_root(root_dir, to)?;
+
+        if !from_root.join(opts.dist_part()).exists() || !to_root.join(opts.dist_part()).exists() {
+            continue;
+        }
+
+        found = true;
+
+        let from_files = referenced_files(opts, &from_root).await?;
+        let to_files = referenced_files(opts, &to_root).await?;
+
+        log(format!("{opts}: {from} -> {to}"));
+
+        for path in from_files.difference(&to_files) {
+            println!("- {path}");
+        }
+
+        for path in to_files.difference(&from_files) {
+            println!("+ {path}");
+        }
+
+        println!(
+            "{} added, {} removed",
+            to_files.difference(&from_files).count(),
+            from_files.difference(&to_files).count()
+        );
+    }
+
+    if !found {
+        return Err(MirsError::SnapshotNotFound {
+            id: format!("{from} or {to}").into(),
+        });
+    }
+
+    Ok(0)
+}
+
+async fn delete(mirrors: &[(FilePath, MirrorOpts)], id: &str) -> Result<i32> {
+    let root_dirs = mirrors
+        .iter()
+        .map(|(root_dir, _)| root_dir)
+        .collect::<BTreeSet<_>>();
+
+    let mut found = false;
+
+    for root_dir in root_dirs {
+        let snapshot_root = snapshot_root(root_dir, id)?;
+
+        if snapshot_root.exists() {
+            tokio::fs::remove_dir_all(&snapshot_root).await?;
+            log(format!("Deleted snapshot {snapshot_root}"));
+            found = true;
+        }
+    }
+
+    if !found {
+        return Err(MirsError::SnapshotNotFound { id: id.into() });
+    }
+
+    Ok(0)
+}
+
+/// The root of the snapshot `id`, which has to be a plain folder name so that it can not point
+/// outside of the snapshots folder.
+fn snapshot_root(root_dir: &FilePath, id: &str) -> Result<FilePath> {
+    if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+        return Err(MirsError::SnapshotNotFound { id: id.into() });
+    }
+
+    Ok(root_dir.join(SNAPSHOTS_DIR).join(id))
+}
+
+/// The files outside of the metadata of the suite that a snapshot references, which are the
+/// packages and installer files.
+async fn referenced_files(opts: &MirrorOpts, root_dir: &FilePath) -> Result<BTreeSet<FilePath>> {
+    let mut files = HashMap::default();
+    add_referenced_files(
+        opts,
+        root_dir,
+        &Progress::new(),
+        &ProgressBar::hidden(),
+        &mut files,
+    )
+    .await?;
+
+    let dist_part = opts.dist_part();
+
+    Ok(files
+        .into_keys()
+        .filter(|path| dist_part.is_empty() || !path.as_str().starts_with(dist_part.as_str()))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::MetadataExt;
+
+    use crate::{snapshot::*, testing::TestDir};
+
+    #[test]
+    fn snapshot_ids_can_not_leave_the_snapshots_folder() {
+        let root_dir = FilePath::from("/mirror/debian");
+
+        assert_eq!(
+            snapshot_root(&root_dir, "20251002T000000Z").unwrap(),
+            FilePath::from("/mirror/debian/snapshots/20251002T000000Z")
+        );
+        assert!(snapshot_root(&root_dir, "..").is_err());
+        assert!(snapshot_root(&root_dir, "../../etc").is_err());
+        assert!(snapshot_root(&root_dir, "").is_err());
+    }
+
+    #[tokio::test]
+    async fn snapshots_link_metadata_and_pool_files() {
+        let dir = TestDir::new("snapshot");
+        dir.write_suite("mirror", "trixie", &["hello"]);
+
+        let root_dir = dir.join("mirror");
+        let opts =
+            MirrorOpts::try_from("deb [arch=amd64] http://a.example/debian trixie main").unwrap();
+
+        let root_dirs = BTreeSet::from([root_dir.clone()]);
+        reserve_snapshot(&root_dirs, "1").unwrap();
+
+        let stats = create_snapshot(&opts, &root_dir, "1").await.unwrap();
+
+        assert_eq!(stats.files, 3);
+
+        let inode = |path: &FilePath| std::fs::metadata(path).unwrap().ino();
+
+        for path in [
+            "dists/trixie/Release",
+            "dists/trixie/main/binary-amd64/Packages",
+            "pool/main/hello_1.0_amd64.deb",
+        ] {
+            assert_eq!(
+                inode(&root_dir.join(path)),
+                inode(&root_dir.join("snapshots/1").join(path))
+            );
+        }
+
+        assert!(matches!(
+            reserve_snapshot(&root_dirs, "1"),
+            Err(MirsError::SnapshotExists { .. })
+        ));
+    }
+}
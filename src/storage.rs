@@ -0,0 +1,232 @@
+use std::{
+    ops::Deref,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use walkdir::WalkDir;
+
+use crate::{downloader::create_dirs, error::Result, metadata::FilePath};
+
+#[cfg(test)]
+pub mod memory;
+
+pub type StorageReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type StorageWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageMeta {
+    pub size: u64,
+    pub is_symlink: bool,
+}
+
+/// Where the mirror output is published. Downloads are staged on the local disk, in the staging
+/// dir, and put into the storage when they are complete, so a backend only has to support whole
+/// file operations.
+///
+/// The dedup store, snapshots and atomic publishing link files within the output, so they need
+/// a storage on the local file system.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn create(&self, path: &FilePath) -> Result<StorageWriter>;
+
+    async fn open(&self, path: &FilePath) -> Result<StorageReader>;
+
+    /// Publishes a complete file from the local disk at `to`, creating the parent directories of
+    /// the destination as needed. The staged file is consumed. The file is in place for good once
+    /// this returns, also if the system crashes right after.
+    async fn put(&self, staged: &FilePath, to: &FilePath) -> Result<()>;
+
+    /// Creates a symlink at `link` pointing to `target`, which is relative to the link.
+    async fn symlink(&self, target: &Path, link: &FilePath) -> Result<()>;
+
+    /// Returns the metadata of the path itself, without following symlinks.
+    async fn stat(&self, path: &FilePath) -> Result<Option<StorageMeta>>;
+
+    /// Whether the path exists, following symlinks.
+    async fn exists(&self, path: &FilePath) -> Result<bool>;
+
+    async fn remove(&self, path: &FilePath) -> Result<()>;
+
+    /// Lists all files and symlinks below `dir`, recursively, without descending into the
+    /// folders in `exclude`.
+    async fn list(&self, dir: &FilePath, exclude: &[FilePath]) -> Result<Vec<FilePath>>;
+
+    /// Whether the storage is the local file system, where files can be hardlinked.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// A handle to the storage in use, which defaults to the local file system.
+#[derive(Clone)]
+pub struct SharedStorage(Arc<dyn Storage>);
+
+impl SharedStorage {
+    pub fn new<S: Storage + 'static>(storage: S) -> Self {
+        Self(Arc::new(storage))
+    }
+}
+
+impl Default for SharedStorage {
+    fn default() -> Self {
+        Self::new(LocalStorage)
+    }
+}
+
+impl Deref for SharedStorage {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+pub struct LocalStorage;
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn create(&self, path: &FilePath) -> Result<StorageWriter> {
+        create_dirs(path).await?;
+
+        Ok(Box::new(tokio::fs::File::create(path).await?))
+    }
+
+    async fn open(&self, path: &FilePath) -> Result<StorageReader> {
+        Ok(Box::new(tokio::fs::File::open(path).await?))
+    }
+
+    async fn put(&self, staged: &FilePath, to: &FilePath) -> Result<()> {
+        create_dirs(to).await?;
+
+        // renaming across file systems fails, in which case the file is copied instead
+        if tokio::fs::rename(staged, to).await.is_err() {
+            copy_into_place(staged, to).await?;
+            tokio::fs::remove_file(staged).await?;
+        }
+
+        // a new name is only on disk once the folder that holds it is
+        if let Some(parent) = Path::new(to.as_str()).parent() {
+            tokio::fs::File::open(parent).await?.sync_all().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn symlink(&self, target: &Path, link: &FilePath) -> Result<()> {
+        create_dirs(link).await?;
+
+        Ok(tokio::fs::symlink(target, link).await?)
+    }
+
+    async fn stat(&self, path: &FilePath) -> Result<Option<StorageMeta>> {
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) => Ok(Some(StorageMeta {
+                size: metadata.len(),
+                is_symlink: metadata.is_symlink(),
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, path: &FilePath) -> Result<bool> {
+        Ok(tokio::fs::try_exists(path).await?)
+    }
+
+    async fn remove(&self, path: &FilePath) -> Result<()> {
+        Ok(tokio::fs::remove_file(path).await?)
+    }
+
+    async fn list(&self, dir: &FilePath, exclude: &[FilePath]) -> Result<Vec<FilePath>> {
+        let dir = dir.clone();
+        let exclude = exclude.to_vec();
+
+        tokio::task::spawn_blocking(move || {
+            let mut files = Vec::new();
+
+            let entries = WalkDir::new(&dir)
+                .into_iter()
+                .filter_entry(|entry| !exclude.iter().any(|v| entry.path().starts_with(v)));
+
+            for entry in entries {
+                let entry = entry?;
+
+                if !entry.file_type().is_dir() {
+                    files.push(FilePath::from(entry.path()));
+                }
+            }
+
+            Ok(files)
+        })
+        .await?
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// Copies `from` next to `to` and renames the copy over it once it is on disk. Copying onto `to`
+/// itself would publish a truncated file if the system crashed midway, and would write through
+/// to the other hardlinks of `to`, like in the dedup store or a snapshot.
+async fn copy_into_place(from: &FilePath, to: &FilePath) -> Result<()> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let tmp_path = format!(
+        "{}.{}-{}.put",
+        to,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let result = async {
+        tokio::fs::copy(from, &tmp_path).await?;
+        tokio::fs::File::open(&tmp_path).await?.sync_all().await?;
+        tokio::fs::rename(&tmp_path, to).await
+    }
+    .await;
+
+    if result.is_err() {
+        _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{storage::*, testing::TestDir};
+
+    #[tokio::test]
+    async fn put_replaces_hardlinked_targets() {
+        let dir = TestDir::new("put");
+
+        let target = dir.write("mirror/pool/p.deb", "old");
+        let stored = dir.join("store/p.deb");
+        create_dirs(&stored).await.unwrap();
+        std::fs::hard_link(&target, &stored).unwrap();
+
+        let staged = dir.write("staging/p.deb", "new");
+        LocalStorage.put(&staged, &target).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(std::fs::read_to_string(&stored).unwrap(), "old");
+
+        // the copy of a staged file on another file system
+        let staged = dir.write("staging/p.deb", "newer");
+        copy_into_place(&staged, &target).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "newer");
+        assert_eq!(std::fs::read_to_string(&stored).unwrap(), "old");
+        assert_eq!(
+            std::fs::read_dir(dir.join("mirror/pool")).unwrap().count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use tokio::io::AsyncWrite;
+
+use crate::{
+    error::{MirsError, Result},
+    metadata::FilePath,
+};
+
+use super::{Storage, StorageMeta, StorageReader, StorageWriter};
+
+enum MemoryEntry {
+    File(Vec<u8>),
+    Symlink(PathBuf),
+}
+
+/// Keeps all files in memory, for tests.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    entries: Arc<Mutex<BTreeMap<FilePath, MemoryEntry>>>,
+}
+
+impl MemoryStorage {
+    fn not_found(path: &FilePath) -> MirsError {
+        std::io::Error::new(std::io::ErrorKind::NotFound, path.to_string()).into()
+    }
+
+    fn resolve(&self, path: &FilePath) -> Option<FilePath> {
+        let entries = self.entries.lock().expect("memory storage lock poisoned");
+
+        let mut path = path.clone();
+
+        // bounded, in case of symlink loops
+        for _ in 0..16 {
+            match entries.get(&path)? {
+                MemoryEntry::File(_) => return Some(path),
+                MemoryEntry::Symlink(target) => {
+                    let parent = Path::new(path.as_str()).parent()?;
+                    path = FilePath::from(normalize(&parent.join(target)).as_path());
+                }
+            }
+        }
+
+        None
+    }
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            std::path::Component::CurDir => (),
+            c => normalized.push(c),
+        }
+    }
+
+    normalized
+}
+
+struct MemoryWriter {
+    storage: MemoryStorage,
+    path: FilePath,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut entries = self
+            .storage
+            .entries
+            .lock()
+            .expect("memory storage lock poisoned");
+
+        match entries.get_mut(&self.path) {
+            Some(MemoryEntry::File(data)) => {
+                data.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+            _ => Poll::Ready(Err(std::io::ErrorKind::NotFound.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn create(&self, path: &FilePath) -> Result<StorageWriter> {
+        self.entries
+            .lock()
+            .expect("memory storage lock poisoned")
+            .insert(path.clone(), MemoryEntry::File(Vec::new()));
+
+        Ok(Box::new(MemoryWriter {
+            storage: self.clone(),
+            path: path.clone(),
+        }))
+    }
+
+    async fn open(&self, path: &FilePath) -> Result<StorageReader> {
+        let resolved = self.resolve(path).ok_or_else(|| Self::not_found(path))?;
+
+        let entries = self.entries.lock().expect("memory storage lock poisoned");
+
+        match entries.get(&resolved) {
+            Some(MemoryEntry::File(data)) => Ok(Box::new(Cursor::new(data.clone()))),
+            _ => Err(Self::not_found(path)),
+        }
+    }
+
+    async fn put(&self, staged: &FilePath, to: &FilePath) -> Result<()> {
+        let data = tokio::fs::read(staged).await?;
+
+        self.entries
+            .lock()
+            .expect("memory storage lock poisoned")
+            .insert(to.clone(), MemoryEntry::File(data));
+
+        Ok(tokio::fs::remove_file(staged).await?)
+    }
+
+    async fn symlink(&self, target: &Path, link: &FilePath) -> Result<()> {
+        self.entries
+            .lock()
+            .expect("memory storage lock poisoned")
+            .insert(link.clone(), MemoryEntry::Symlink(target.to_path_buf()));
+
+        Ok(())
+    }
+
+    async fn stat(&self, path: &FilePath) -> Result<Option<StorageMeta>> {
+        let entries = self.entries.lock().expect("memory storage lock poisoned");
+
+        Ok(entries.get(path).map(|entry| match entry {
+            MemoryEntry::File(data) => StorageMeta {
+                size: data.len() as u64,
+                is_symlink: false,
+            },
+            MemoryEntry::Symlink(target) => StorageMeta {
+                size: target.as_os_str().len() as u64,
+                is_symlink: true,
+            },
+        }))
+    }
+
+    async fn exists(&self, path: &FilePath) -> Result<bool> {
+        Ok(self.resolve(path).is_some())
+    }
+
+    async fn remove(&self, path: &FilePath) -> Result<()> {
+        self.entries
+            .lock()
+            .expect("memory storage lock poisoned")
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| Self::not_found(path))
+    }
+
+    async fn list(&self, dir: &FilePath, exclude: &[FilePath]) -> Result<Vec<FilePath>> {
+        let prefix = format!("{}/", dir.as_str().trim_end_matches('/'));
+
+        Ok(self
+            .entries
+            .lock()
+            .expect("memory storage lock poisoned")
+            .keys()
+            .filter(|path| path.as_str().starts_with(&prefix))
+            .filter(|path| {
+                !exclude
+                    .iter()
+                    .any(|v| Path::new(path.as_str()).starts_with(v))
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{storage::memory::*, testing::TestDir};
+
+    #[tokio::test]
+    async fn memory_storage_operations() {
+        let storage = MemoryStorage::default();
+
+        let staging = TestDir::new("memory-storage");
+        let staged = staging.write("p.deb", "package");
+
+        let pool_file = FilePath::from("/mirror/pool/p.deb");
+
+        storage.put(&staged, &pool_file).await.unwrap();
+        assert!(!staged.exists());
+
+        let mut writer = storage
+            .create(&FilePath::from("/mirror/snapshots/1/p.deb"))
+            .await
+            .unwrap();
+        writer.write_all(b"snapshot").await.unwrap();
+
+        let link = FilePath::from("/mirror/by-hash/p");
+        storage
+            .symlink(Path::new("../pool/p.deb"), &link)
+            .await
+            .unwrap();
+
+        assert!(storage.exists(&link).await.unwrap());
+        assert_eq!(
+            storage.stat(&pool_file).await.unwrap(),
+            Some(StorageMeta {
+                size: 7,
+                is_symlink: false
+            })
+        );
+
+        let mut content = Vec::new();
+        storage
+            .open(&link)
+            .await
+            .unwrap()
+            .read_to_end(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, b"package");
+
+        assert_eq!(
+            storage
+                .list(
+                    &FilePath::from("/mirror"),
+                    &[FilePath::from("/mirror/snapshots")]
+                )
+                .await
+                .unwrap(),
+            vec![link.clone(), pool_file.clone()]
+        );
+
+        storage.remove(&pool_file).await.unwrap();
+
+        assert!(!storage.exists(&link).await.unwrap());
+        assert!(storage.stat(&link).await.unwrap().is_some());
+        assert!(
+            storage
+                .list(&FilePath::from("/mirror/pool"), &[])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    ops::Deref,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::metadata::FilePath;
+
+/// A folder in the temp dir of the system that is unique to a test. It is removed when it is
+/// dropped, which includes when an assertion of the test fails.
+pub struct TestDir(FilePath);
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "aptmirs-{name}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        Self(FilePath::from(path.as_path()))
+    }
+
+    /// Writes `content` to `path` in the folder, along with the folders above it.
+    pub fn write(&self, path: &str, content: impl AsRef<[u8]>) -> FilePath {
+        let path = self.0.join(path);
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        // replaces the file like a mirror does, instead of writing through hardlinks to it
+        _ = std::fs::remove_file(&path);
+        std::fs::write(&path, content).unwrap();
+
+        path
+    }
+
+    /// Writes the suite `suite` of the mirror in `root`, with one uncompressed index for the
+    /// `main` component and the `amd64` architecture, and the packages it lists in the pool.
+    /// The content of each package is its name.
+    pub fn write_suite(&self, root: &str, suite: &str, packages: &[&str]) {
+        let mut index = String::new();
+
+        for package in packages {
+            let filename = format!("pool/main/{package}_1.0_amd64.deb");
+
+            self.write(&format!("{root}/{filename}"), package);
+
+            index.push_str(&format!(
+                "Package: {package}\nVersion: 1.0\nArchitecture: amd64\nFilename: {filename}\nSize: {}\nSHA256: {}\n\n",
+                package.len(),
+                hex::encode(Sha256::digest(package))
+            ));
+        }
+
+        self.write(
+            &format!("{root}/dists/{suite}/main/binary-amd64/Packages"),
+            &index,
+        );
+
+        self.write(
+            &format!("{root}/dists/{suite}/Release"),
+            format!(
+                "Suite: {suite}\nArchitectures: amd64\nComponents: main\nSHA256:\n {} {} main/binary-amd64/Packages\n",
+                hex::encode(Sha256::digest(&index)),
+                index.len()
+            ),
+        );
+    }
+}
+
+impl Deref for TestDir {
+    type Target = FilePath;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
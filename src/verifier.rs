@@ -7,6 +7,7 @@ use tokio::{io::AsyncReadExt, sync::Mutex, task::JoinHandle};
 use crate::{
     error::{MirsError, Result},
     metadata::{FilePath, IndexFileEntry, checksum::Checksum},
+    storage::SharedStorage,
 };
 
 use super::progress::Progress;
@@ -32,7 +33,7 @@ impl Default for Verifier {
 }
 
 impl Verifier {
-    pub fn build(num_threads: u8) -> Self {
+    pub fn build(num_threads: u8, storage: SharedStorage) -> Self {
         let (sender, receiver) = bounded(1024);
 
         let mut tasks = Vec::with_capacity(num_threads as usize);
@@ -43,6 +44,7 @@ impl Verifier {
         for _ in 0..num_threads {
             let task_receiver: Receiver<Arc<VerifyTask>> = receiver.clone();
             let task_progress = progress.clone();
+            let task_storage = storage.clone();
 
             let handle = tokio::spawn(async move {
                 let mut buf = vec![0u8; 1024 * 1024];
@@ -50,17 +52,22 @@ impl Verifier {
                 while let Ok(task) = task_receiver.recv().await {
                     let file_size = task.size;
 
-                    match verify_file(&mut buf, task.clone(), |downloaded| {
+                    match verify_file(&task_storage, &mut buf, task.clone(), |downloaded| {
                         task_progress.bytes.inc_success(downloaded)
                     })
                     .await
                     {
                         Ok(true) => task_progress.files.inc_success(1),
                         Ok(false) => {
+                            let path = task.paths.first().unwrap();
+
                             task_progress.files.inc_failed(1);
-                            eprintln!("checksum failed: {}", task.paths.first().unwrap());
+                            task_progress.add_corrupt_url(path.as_str());
+                            eprintln!("checksum failed: {path}");
                         }
                         Err(e) => {
+                            task_progress.add_failed_url(task.paths.first().unwrap().as_str());
+
                             if let MirsError::Download { .. } = e
                                 && let Some(size) = file_size
                             {
@@ -114,6 +121,7 @@ impl Verifier {
 }
 
 async fn verify_file<F>(
+    storage: &SharedStorage,
     buf: &mut [u8],
     verify_task: Arc<VerifyTask>,
     mut progress_cb: F,
@@ -122,7 +130,7 @@ where
     F: FnMut(u64),
 {
     for path in &verify_task.paths {
-        let mut file = tokio::fs::File::open(path).await?;
+        let mut file = storage.open(path).await?;
 
         if verify_task.size.is_some_and(|v| v > 0) || verify_task.size.is_none() {
             let mut hasher = verify_task.checksum.create_hasher();
@@ -169,3 +177,46 @@ impl TryFrom<IndexFileEntry> for VerifyTask {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{storage::memory::MemoryStorage, verifier::*};
+
+    #[tokio::test]
+    async fn verifies_files_in_storage() {
+        let storage = SharedStorage::new(MemoryStorage::default());
+
+        let path = FilePath::from("/mirror/pool/p.deb");
+
+        storage
+            .create(&path)
+            .await
+            .unwrap()
+            .write_all(b"package")
+            .await
+            .unwrap();
+
+        let task = |content: &[u8]| {
+            Arc::new(VerifyTask {
+                size: Some(7),
+                checksum: Checksum::Md5(md5::compute(content).0),
+                paths: vec![path.clone()],
+            })
+        };
+
+        let mut buf = vec![0u8; 16];
+
+        assert!(
+            verify_file(&storage, &mut buf, task(b"package"), |_| ())
+                .await
+                .unwrap()
+        );
+        assert!(
+            !verify_file(&storage, &mut buf, task(b"other"), |_| ())
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -7,7 +7,7 @@ use verification::Verify;
 use crate::error::Result;
 use crate::{
     CliOpts,
-    cmd::{CmdResult, CmdState},
+    cmd::{CmdResult, CmdState, Outcome, ResultSummary},
     config::MirrorOpts,
     context::Context,
     error::MirsError,
@@ -46,7 +46,28 @@ impl Display for VerifyResult {
     }
 }
 
-impl CmdResult for VerifyResult {}
+impl CmdResult for VerifyResult {
+    fn summary(&self) -> ResultSummary {
+        match self {
+            VerifyResult::Done {
+                corrupt_files,
+                missing_files,
+                ..
+            } => ResultSummary {
+                outcome: if corrupt_files + missing_files > 0 {
+                    Outcome::Incomplete
+                } else {
+                    Outcome::Ok
+                },
+                bytes: None,
+                packages: None,
+                failed: *corrupt_files,
+                skipped: *missing_files,
+            },
+            VerifyResult::Error(..) => ResultSummary::failed(),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct VerifyState {
@@ -96,7 +117,7 @@ impl Context<VerifyState> {
         opts: Vec<MirrorOpts>,
         cli_opts: Arc<CliOpts>,
     ) -> Result<Vec<(VerifyContext, Vec<VerifyDynStep>)>> {
-        let verifier = Verifier::build(cli_opts.dl_threads);
+        let verifier = Verifier::build(cli_opts.dl_threads, cli_opts.storage());
 
         opts.into_iter()
             .map(|o| {
@@ -12,6 +12,7 @@ use crate::{
     metadata::{
         FilePath,
         metadata_file::{MetadataFile, deduplicate_metadata},
+        package_filter::PackageFilter,
         release::{FileEntry, Release},
     },
     mirror::verify_and_prune,
@@ -62,6 +63,11 @@ impl Step<VerifyState> for Verify {
         for (metadata_file, file_entry) in &mut metadata {
             metadata_file.prefix_with(dist_root.as_str());
 
+            // compressed variants that --apply-pdiffs could not reproduce are not in the mirror
+            if metadata_file.is_left_out_variant() {
+                continue;
+            }
+
             let size = file_entry.size;
             let (checksum, primary, ..) = file_entry.into_paths(metadata_file.path(), by_hash)?;
 
@@ -88,21 +94,28 @@ impl Step<VerifyState> for Verify {
         let metadata = deduplicate_metadata(metadata);
 
         let index_files = metadata
-            .into_iter()
+            .iter()
+            .cloned()
             .map(MetadataFile::into_reader)
             .collect::<Result<Vec<_>>>()?;
 
         let total_size = index_files.iter().map(|v| v.size()).sum();
         progress.bytes.inc_total(total_size);
 
+        let mut filter = PackageFilter::new(&ctx.state.opts)?;
+
         let task_verifier = ctx.state.verifier.clone();
         let task_progress = progress.clone();
         let task_repo = ctx.state.repo.clone();
+        let task_opts = ctx.state.opts.clone();
         let task_progress_bar = progress_bar.clone();
 
         spawn_blocking(move || {
             let async_handle = Handle::current();
 
+            filter.resolve_dependencies(&task_opts, &[&task_repo.root_dir])?;
+            filter.select_versions(&task_opts, &metadata)?;
+
             for meta_file in index_files {
                 let base_path = match meta_file.file() {
                     MetadataFile::Packages(..) | MetadataFile::Sources(..) => {
@@ -121,6 +134,10 @@ impl Step<VerifyState> for Verify {
                 for entry in meta_file {
                     let mut entry = entry?;
 
+                    if !filter.accept(&entry) {
+                        continue;
+                    }
+
                     entry.path = base_path.join(&entry.path).0;
 
                     let verify_task = Arc::new(VerifyTask::try_from(entry)?);